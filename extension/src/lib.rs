@@ -0,0 +1,264 @@
+//! Builds tantivy-sqlite-storage into a sqlite loadable extension, so that tools outside of Rust,
+//! such as the sqlite3 shell or Python's sqlite3 module, can search, list and verify the indexes in
+//! a database.
+//!
+//! The host already has a copy of sqlite, and a second one in the same process would break the
+//! POSIX advisory locks which keep the database safe. So rusqlite is built with its
+//! `loadable_extension` feature, which sends every call through the table of routines the host
+//! passes to the entry point instead of linking sqlite, and the index is read through the host's
+//! own connection.
+
+use std::{error::Error, os::raw::c_char, os::raw::c_int, path::PathBuf};
+
+use rusqlite::{
+    ffi,
+    functions::{Context, FunctionFlags},
+    types::ValueRef,
+    Connection,
+};
+use serde_json::{json, Value};
+use tantivy::{collector::TopDocs, query::QueryParser, schema::FieldType, Index, ReloadPolicy};
+use tantivy_sqlite_storage::{SingleConnection, TantivySqliteStorage, TantivySqliteStorageError};
+
+/// The number of results returned by `tantivy_search` when the limit argument is left out.
+const DEFAULT_SEARCH_LIMIT: usize = 10;
+
+type ExtensionFunction = fn(Connection, &[Arg]) -> Result<Value, Box<dyn Error>>;
+
+/// The entry point sqlite looks for when loading the extension, which registers these functions on
+/// the connection loading it:
+///
+/// * `tantivy_search(query [, limit [, namespace]])` searches every indexed text field for a
+///   query in tantivy's query language, and returns up to `limit` matches as a JSON array of objects
+///   with the keys `score`, `segment_ord`, `doc_id` and `doc`. `limit` defaults to 10.
+/// * `tantivy_files([namespace])` returns the stored files as a JSON array of objects with the
+///   keys `path`, `size` and `checksum`.
+/// * `tantivy_verify([namespace])` runs [`TantivySqliteStorage::verify`] and returns the
+///   [`VerifyReport`](tantivy_sqlite_storage::VerifyReport) as a JSON object, along with whether it `is_ok`.
+///
+/// Each function takes the namespace of the index as its last argument, and reads the default index
+/// if it is left out or `NULL`, using the layout recorded in the database. Encrypted indexes can't
+/// be read, since there is no way to pass the key.
+///
+/// The functions read the index through the connection they are called on, so they see changes
+/// made in a transaction which is still open on it. They can only be called directly from SQL, not
+/// from triggers or views, so that a database with an untrusted schema can't run them behind the
+/// caller's back.
+///
+/// Build the extension and load it by the name of the library:
+///
+/// ```text
+/// $ cargo build --release --manifest-path extension/Cargo.toml
+/// $ sqlite3 index.sqlite
+/// sqlite> .load extension/target/release/libtantivy_sqlite_extension
+/// sqlite> SELECT value ->> '$.doc.title[0]' FROM json_each(tantivy_search('mice'));
+/// ```
+///
+/// # Safety
+///
+/// This is only meant to be called by sqlite, with the arguments it passes to a loadable extension.
+#[no_mangle]
+pub unsafe extern "C" fn sqlite3_tantivysqliteextension_init(
+    db: *mut ffi::sqlite3,
+    error_message: *mut *mut c_char,
+    api: *mut ffi::sqlite3_api_routines,
+) -> c_int {
+    Connection::extension_init2(db, error_message, api, init)
+}
+
+fn init(conn: Connection) -> rusqlite::Result<bool> {
+    let functions: [(&str, ExtensionFunction); 3] = [
+        ("tantivy_search", search),
+        ("tantivy_files", files),
+        ("tantivy_verify", verify),
+    ];
+
+    for (name, function) in functions {
+        conn.create_scalar_function(
+            name,
+            -1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DIRECTONLY,
+            move |ctx| call(ctx, function),
+        )?;
+    }
+
+    Ok(false)
+}
+
+/// Runs `function` on the connection it was called on, and hands back its result as JSON text.
+fn call(ctx: &Context, function: ExtensionFunction) -> rusqlite::Result<String> {
+    let args: Vec<Arg> = (0..ctx.len()).map(|i| Arg::from(ctx.get_raw(i))).collect();
+
+    // Neither connection closes the host's when it is dropped, and the host's stays open for as
+    // long as the function is running
+    let conn = unsafe { Connection::from_handle(ctx.get_connection()?.handle())? };
+
+    function(conn, &args)
+        .map(|value| value.to_string())
+        .map_err(|e| rusqlite::Error::UserFunctionError(error_chain(&*e).into()))
+}
+
+/// Opens the index in `namespace` with the layout recorded in the database.
+fn storage(
+    conn: Connection,
+    namespace: Option<&str>,
+) -> Result<TantivySqliteStorage, TantivySqliteStorageError> {
+    let mut builder = TantivySqliteStorage::builder(SingleConnection::new(conn))
+        .read_only(true)
+        // The storage only lasts as long as the function call
+        .watch_interval(None);
+    if let Some(namespace) = namespace {
+        builder = builder.namespace(namespace);
+    }
+    builder.build()
+}
+
+fn search(conn: Connection, args: &[Arg]) -> Result<Value, Box<dyn Error>> {
+    let (query, limit, namespace) = match args {
+        [query] => (query, None, None),
+        [query, limit] => (query, Some(limit), None),
+        [query, limit, namespace] => (query, Some(limit), Some(namespace)),
+        _ => {
+            return Err(
+                "tantivy_search takes a query, and optionally a limit and a namespace".into(),
+            )
+        }
+    };
+
+    let query = query.text().ok_or("tantivy_search needs a query")?;
+    let limit = match limit.and_then(Arg::integer) {
+        Some(limit) => usize::try_from(limit).map_err(|_| format!("invalid limit {limit}"))?,
+        None => DEFAULT_SEARCH_LIMIT,
+    };
+    let namespace = namespace.and_then(Arg::text);
+
+    // Tantivy's top docs collector can't collect nothing
+    if limit == 0 {
+        return Ok(json!([]));
+    }
+
+    let index = Index::open(storage(conn, namespace)?)?;
+    let schema = index.schema();
+    let default_fields = schema
+        .fields()
+        .filter(|(_, entry)| matches!(entry.field_type(), FieldType::Str(options) if options.get_indexing_options().is_some()))
+        .map(|(field, _)| field)
+        .collect();
+
+    let query = QueryParser::for_index(&index, default_fields).parse_query(query)?;
+    let searcher = index
+        .reader_builder()
+        .reload_policy(ReloadPolicy::Manual)
+        .try_into()?
+        .searcher();
+
+    let results = searcher
+        .search(&query, &TopDocs::with_limit(limit))?
+        .into_iter()
+        .map(|(score, address)| {
+            let doc = schema.to_json(&searcher.doc(address)?);
+            Ok(json!({
+                "score": score,
+                "segment_ord": address.segment_ord,
+                "doc_id": address.doc_id,
+                "doc": serde_json::from_str::<Value>(&doc)?,
+            }))
+        })
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+    Ok(Value::Array(results))
+}
+
+fn files(conn: Connection, args: &[Arg]) -> Result<Value, Box<dyn Error>> {
+    let storage = storage(conn, namespace_arg("tantivy_files", args)?)?;
+
+    let files = storage
+        .list_files()?
+        .into_iter()
+        .map(|file| {
+            json!({
+                "path": file.path.to_string_lossy(),
+                "size": file.size,
+                "checksum": file.checksum,
+            })
+        })
+        .collect();
+
+    Ok(Value::Array(files))
+}
+
+fn verify(conn: Connection, args: &[Arg]) -> Result<Value, Box<dyn Error>> {
+    let report = storage(conn, namespace_arg("tantivy_verify", args)?)?.verify()?;
+    let paths = |paths: &[PathBuf]| -> Vec<String> {
+        paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect()
+    };
+
+    Ok(json!({
+        "ok": report.is_ok(),
+        "corrupt": paths(&report.corrupt),
+        "missing": paths(&report.missing),
+        "orphaned": paths(&report.orphaned),
+        "unchecked": paths(&report.unchecked),
+    }))
+}
+
+fn namespace_arg<'a>(function: &str, args: &'a [Arg]) -> Result<Option<&'a str>, String> {
+    match args {
+        [] => Ok(None),
+        [namespace] => Ok(namespace.text()),
+        _ => Err(format!(
+            "{function} takes at most one argument, the namespace"
+        )),
+    }
+}
+
+/// An argument passed to one of the functions, as far as they care about its type.
+enum Arg {
+    Null,
+    Integer(i64),
+    Text(String),
+}
+
+impl Arg {
+    fn text(&self) -> Option<&str> {
+        match self {
+            Arg::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn integer(&self) -> Option<i64> {
+        match self {
+            Arg::Integer(integer) => Some(*integer),
+            _ => None,
+        }
+    }
+}
+
+impl From<ValueRef<'_>> for Arg {
+    fn from(value: ValueRef<'_>) -> Self {
+        match value {
+            ValueRef::Null => Arg::Null,
+            ValueRef::Integer(integer) => Arg::Integer(integer),
+            ValueRef::Real(real) => Arg::Text(real.to_string()),
+            ValueRef::Text(text) | ValueRef::Blob(text) => {
+                Arg::Text(String::from_utf8_lossy(text).into_owned())
+            }
+        }
+    }
+}
+
+/// The error along with everything that caused it, since sqlite only shows a single message.
+fn error_chain(e: &dyn Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+    message
+}
@@ -0,0 +1,264 @@
+//! Lets tokio applications use a storage without blocking their executor, by running the sqlite
+//! work on tokio's blocking thread pool.
+
+use std::{fmt, path::PathBuf, sync::Arc};
+
+use tantivy::{
+    directory::{WatchCallback, WatchHandle},
+    Directory, IndexReader,
+};
+use tokio::{sync::Notify, task};
+
+use crate::{
+    backup, cancel::Cancellation, export, import, verify, BackupProgress, ExportProgress,
+    ExportTarget, FileInfo, GcOptions, GcReport, ImportOptions, ImportSource, TantivySqliteStorage,
+    TantivySqliteStorageError, VerifyReport,
+};
+
+/// An async version of the storage-level operations of [`TantivySqliteStorage`], for use from
+/// tokio. Each operation runs on tokio's blocking thread pool, so must be called from within a
+/// tokio runtime.
+///
+/// Dropping the future of an operation cancels it. The operation stops at the next point where it
+/// can safely do so, such as between the blocks or pages it copies, and leaves things as they were
+/// where it can: a cancelled import or restore changes nothing, and a cancelled export never writes
+/// `meta.json`, so it can't be opened. [`AsyncTantivySqliteStorage::gc`], listing files and
+/// reloading a reader always run to the end.
+///
+/// ```
+/// # use r2d2::Pool;
+/// # use r2d2_sqlite::SqliteConnectionManager;
+/// use tantivy_sqlite_storage::{AsyncTantivySqliteStorage, TantivySqliteStorage};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let connection_manager = SqliteConnectionManager::file("file:tantivy-async-example?mode=memory&cache=shared");
+/// # let pool = Pool::builder().max_size(4).build(connection_manager)?;
+/// let storage = AsyncTantivySqliteStorage::new(TantivySqliteStorage::new(pool)?);
+///
+/// let report = storage.verify().await?;
+/// assert!(report.is_ok());
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The [`tantivy::Directory`] methods still block, so an index opened on [`AsyncTantivySqliteStorage::storage`]
+/// should itself only be searched or written from a blocking thread.
+#[derive(Debug, Clone)]
+pub struct AsyncTantivySqliteStorage {
+    storage: TantivySqliteStorage,
+}
+
+impl AsyncTantivySqliteStorage {
+    /// Wraps `storage`. Clones of the storage share everything with it, including watchers.
+    pub fn new(storage: TantivySqliteStorage) -> Self {
+        Self { storage }
+    }
+
+    /// The wrapped storage, for opening an [`tantivy::Index`] on.
+    pub fn storage(&self) -> &TantivySqliteStorage {
+        &self.storage
+    }
+
+    /// See [`TantivySqliteStorage::list_files`].
+    pub async fn list_files(&self) -> Result<Vec<FileInfo>, TantivySqliteStorageError> {
+        self.run(|storage, _| storage.list_files()).await
+    }
+
+    /// See [`TantivySqliteStorage::verify`]. Cancelled between files.
+    pub async fn verify(&self) -> Result<VerifyReport, TantivySqliteStorageError> {
+        self.run(verify::verify).await
+    }
+
+    /// See [`TantivySqliteStorage::gc`].
+    pub async fn gc(&self, options: GcOptions) -> Result<GcReport, TantivySqliteStorageError> {
+        self.run(move |storage, _| storage.gc(&options)).await
+    }
+
+    /// See [`TantivySqliteStorage::export_to`]. Cancelled between blocks.
+    pub async fn export_to(
+        &self,
+        target: impl Directory,
+        mut progress: impl FnMut(ExportProgress) + Send + 'static,
+    ) -> Result<(), TantivySqliteStorageError> {
+        self.run(move |storage, cancellation| {
+            export::export(
+                storage,
+                ExportTarget::Directory(&target),
+                &mut progress,
+                cancellation,
+            )
+        })
+        .await
+    }
+
+    /// See [`TantivySqliteStorage::export_to_path`]. Cancelled between blocks.
+    pub async fn export_to_path(
+        &self,
+        path: impl Into<PathBuf>,
+        mut progress: impl FnMut(ExportProgress) + Send + 'static,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let path = path.into();
+
+        self.run(move |storage, cancellation| {
+            std::fs::create_dir_all(&path)?;
+            export::export(
+                storage,
+                ExportTarget::Path(&path),
+                &mut progress,
+                cancellation,
+            )
+        })
+        .await
+    }
+
+    /// See [`TantivySqliteStorage::import_from`]. Cancelled between files.
+    pub async fn import_from(
+        &self,
+        source: impl Directory,
+        options: ImportOptions,
+    ) -> Result<(), TantivySqliteStorageError> {
+        self.run(move |storage, cancellation| {
+            import::import(
+                storage,
+                ImportSource::Directory(&source),
+                &options,
+                cancellation,
+            )
+        })
+        .await
+    }
+
+    /// See [`TantivySqliteStorage::import_from_path`]. Cancelled between files.
+    pub async fn import_from_path(
+        &self,
+        path: impl Into<PathBuf>,
+        options: ImportOptions,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let path = path.into();
+
+        self.run(move |storage, cancellation| {
+            import::import(storage, ImportSource::Path(&path), &options, cancellation)
+        })
+        .await
+    }
+
+    /// See [`TantivySqliteStorage::backup_to`]. Cancelled between batches of pages, leaving an
+    /// incomplete backup behind.
+    pub async fn backup_to(
+        &self,
+        path: impl Into<PathBuf>,
+        mut progress: impl FnMut(BackupProgress) + Send + 'static,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let path = path.into();
+
+        self.run(move |storage, cancellation| {
+            backup::backup(storage, &path, &mut progress, cancellation)
+        })
+        .await
+    }
+
+    /// See [`TantivySqliteStorage::restore_from`]. Cancelled between batches of pages.
+    pub async fn restore_from(
+        &self,
+        path: impl Into<PathBuf>,
+        mut progress: impl FnMut(BackupProgress) + Send + 'static,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let path = path.into();
+
+        self.run(move |storage, cancellation| {
+            backup::restore(storage, &path, &mut progress, cancellation)
+        })
+        .await
+    }
+
+    /// Reloads `reader`, which reads the new `meta.json` and opens any new segments. Readers with
+    /// [`tantivy::ReloadPolicy::OnCommit`] do this on a thread of their own after each commit, so this is
+    /// for readers with [`tantivy::ReloadPolicy::Manual`].
+    pub async fn reload_reader(
+        &self,
+        reader: &IndexReader,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let reader = reader.clone();
+
+        self.run(move |_, _| {
+            reader
+                .reload()
+                .map_err(|e| TantivySqliteStorageError::InvalidIndex(e.to_string()))
+        })
+        .await
+    }
+
+    /// Starts watching for commits, both those made through this storage and, every
+    /// [`TantivySqliteStorageBuilder::watch_interval`](crate::TantivySqliteStorageBuilder::watch_interval),
+    /// those made elsewhere. Wait for them with [`CommitWatcher::changed`].
+    pub async fn watch(&self) -> Result<CommitWatcher, TantivySqliteStorageError> {
+        let notify = Arc::new(Notify::new());
+
+        let callback = {
+            let notify = notify.clone();
+            WatchCallback::new(move || notify.notify_one())
+        };
+        let handle = self
+            .run(move |storage, _| {
+                storage
+                    .watch(callback)
+                    .map_err(|e| TantivySqliteStorageError::Io(std::io::Error::other(e)))
+            })
+            .await?;
+
+        Ok(CommitWatcher {
+            notify,
+            _handle: handle,
+        })
+    }
+
+    async fn run<T: Send + 'static>(
+        &self,
+        operation: impl FnOnce(&TantivySqliteStorage, &Cancellation) -> Result<T, TantivySqliteStorageError>
+            + Send
+            + 'static,
+    ) -> Result<T, TantivySqliteStorageError> {
+        let storage = self.storage.clone();
+        let cancellation = Cancellation::default();
+
+        // Cancels the operation if this future is dropped before it finishes
+        let _cancel_on_drop = CancelOnDrop(cancellation.clone());
+
+        match task::spawn_blocking(move || operation(&storage, &cancellation)).await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            // The runtime is shutting down
+            Err(_) => Err(TantivySqliteStorageError::Cancelled),
+        }
+    }
+}
+
+struct CancelOnDrop(Cancellation);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Waits for commits to a storage, returned by [`AsyncTantivySqliteStorage::watch`]. It stops
+/// watching when dropped.
+pub struct CommitWatcher {
+    notify: Arc<Notify>,
+    _handle: WatchHandle,
+}
+
+impl fmt::Debug for CommitWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommitWatcher").finish_non_exhaustive()
+    }
+}
+
+impl CommitWatcher {
+    /// Waits until there has been a commit since the last call, or since the watcher was created.
+    /// Several commits made in between are reported as one.
+    pub async fn changed(&self) {
+        self.notify.notified().await;
+    }
+}
@@ -0,0 +1,166 @@
+//! Copies the whole database to or from another sqlite file with sqlite's online backup API.
+
+use std::{
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rusqlite::{
+    backup::{Backup, StepResult},
+    Connection, OpenFlags,
+};
+
+use crate::{
+    cancel::Cancellation, lock, migrations, ProvidedConnection, StorageLayout,
+    TantivySqliteStorage, TantivySqliteStorageError,
+};
+
+/// How many pages are copied by each step of the backup.
+const PAGES_PER_STEP: i32 = 256;
+
+/// How long to wait before retrying a step which found the database locked.
+const BUSY_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How far a backup or restore has got, passed to the progress callback of
+/// [`TantivySqliteStorage::backup_to`] and [`TantivySqliteStorage::restore_from`] after each step.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BackupProgress {
+    /// The number of pages copied so far.
+    pub pages_copied: u64,
+    /// The number of pages in the database being copied.
+    pub total_pages: u64,
+}
+
+pub(crate) fn backup(
+    storage: &TantivySqliteStorage,
+    path: &Path,
+    progress: &mut dyn FnMut(BackupProgress),
+    cancellation: &Cancellation,
+) -> Result<(), TantivySqliteStorageError> {
+    let source = source(storage)?;
+    let mut destination = Connection::open(path)?;
+
+    // Holding a read transaction for the whole backup pins it to one snapshot. Otherwise sqlite restarts
+    // the backup whenever another connection writes, which could go on forever on a busy index.
+    source.execute_batch("BEGIN")?;
+    let result = source
+        .query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))
+        .map_err(TantivySqliteStorageError::from)
+        .and_then(|()| copy(&source, &mut destination, progress, cancellation));
+    source.execute_batch("ROLLBACK")?;
+
+    result
+}
+
+/// A connection to take a backup from. Not the connection of a transaction in progress, since its
+/// changes aren't committed yet, nor the provider's reader when the database is a file, since that
+/// would keep everything else waiting for a turn with it until the backup has finished. An in-memory
+/// database can only be reached through the provider, so its reader is used for those.
+fn source(storage: &TantivySqliteStorage) -> Result<ProvidedConnection, TantivySqliteStorageError> {
+    let reader = storage.inner.read().reader()?;
+
+    match reader.path() {
+        Some(path) if !path.is_empty() => Ok(ProvidedConnection::new(Box::new(
+            Connection::open_with_flags(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?,
+        ))),
+        _ => Ok(reader),
+    }
+}
+
+pub(crate) fn restore(
+    storage: &TantivySqliteStorage,
+    path: &Path,
+    progress: &mut dyn FnMut(BackupProgress),
+    cancellation: &Cancellation,
+) -> Result<(), TantivySqliteStorageError> {
+    storage.inner.read().check_writable()?;
+    let source = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let _writer_lock = lock::acquire_writer_lock(storage)?;
+    // Nothing else can use the storage until the restored database has been loaded
+    let mut inner = storage.inner.write();
+    if inner.transaction.is_some() {
+        return Err(TantivySqliteStorageError::TransactionInProgress);
+    }
+
+    // Checked before the copy, since the storage can't read a database with another kind of layout,
+    // or one whose data key it can't unwrap, such as a backup taken before the key was rewrapped
+    StorageLayout::resolve(
+        Some(inner.storage_layout),
+        migrations::stored_layout(&source, &inner.tables)?,
+    )?;
+    #[cfg(feature = "encryption")]
+    if let Some(encryption) = inner.layout.encryption() {
+        encryption.check(&source)?;
+    }
+
+    let mut destination = inner.writer()?;
+    let locks = lock::held(&destination, &inner.tables.locks)?;
+
+    // The copy keeps the database locked for writing, so nobody else can take over the storage's
+    // locks while their heartbeats wait for it. Their leases are kept going after each step instead,
+    // and written out once the copy has finished.
+    let leases = inner.leases.clone();
+    let result = copy(
+        &source,
+        &mut destination,
+        &mut |step| {
+            let now = Instant::now();
+            for lease in &leases {
+                lease.renewed(now);
+            }
+            progress(step);
+        },
+        cancellation,
+    );
+    drop(destination);
+
+    // The destination is left as it was if the copy doesn't finish
+    if let Err(e) = result {
+        let conn = inner.writer()?;
+        inner.write_leases(&conn)?;
+        return Err(e);
+    }
+
+    // The backup may have been taken by an older version of this crate
+    inner.init()?;
+
+    // Including the writer lock held by this restore, which would otherwise be lost
+    let conn = inner.writer()?;
+    lock::replace(&conn, &inner.tables.locks, &locks)?;
+    inner.write_leases(&conn)?;
+    drop(conn);
+
+    let meta = inner.committed_meta()?;
+    inner.reload(meta)
+}
+
+fn copy(
+    source: &Connection,
+    destination: &mut Connection,
+    progress: &mut dyn FnMut(BackupProgress),
+    cancellation: &Cancellation,
+) -> Result<(), TantivySqliteStorageError> {
+    let backup = Backup::new(source, destination)?;
+
+    loop {
+        cancellation.check()?;
+        let result = backup.step(PAGES_PER_STEP)?;
+
+        let status = backup.progress();
+        progress(BackupProgress {
+            pages_copied: (status.pagecount - status.remaining) as u64,
+            total_pages: status.pagecount as u64,
+        });
+
+        match result {
+            StepResult::Done => return Ok(()),
+            StepResult::More => {}
+            _ => thread::sleep(BUSY_RETRY_INTERVAL),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Inspects and manages tantivy indexes stored in sqlite with `tantivy-sqlite-storage`.
+//!
+//! Needs the `cli` feature, for example `cargo install tantivy-sqlite-storage --features cli`.
+
+use std::{
+    error::Error,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use clap::{Parser, Subcommand};
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::OpenFlags;
+use tantivy::{collector::TopDocs, query::QueryParser, Directory, Index};
+
+use tantivy_sqlite_storage::{
+    CompressionConfig, EncryptionKey, GcOptions, ImportOptions, StorageLayout,
+    TantivySqliteStorage, DEFAULT_CHUNK_SIZE,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Inspect and manage tantivy indexes stored in sqlite")]
+struct Cli {
+    /// The sqlite database holding the index
+    database: PathBuf,
+
+    /// The namespace of the index, if it isn't the default one
+    #[arg(long, global = true)]
+    namespace: Option<String>,
+
+    /// The start of every table name
+    #[arg(long, global = true, default_value = "tantivy")]
+    table_prefix: String,
+
+    /// Create a new database with the chunked layout rather than a single blob per file. Existing
+    /// databases always use the layout they were created with
+    #[arg(long, global = true)]
+    chunked: bool,
+
+    /// The chunk size for new files, implies --chunked
+    #[arg(long, global = true)]
+    chunk_size: Option<usize>,
+
+    /// Compress new files with LZ4, which needs the chunked layout
+    #[arg(long, global = true)]
+    compress: bool,
+
+    /// A file holding the key of an encrypted index, either as 32 raw bytes or as 64 hex digits
+    #[arg(long, global = true)]
+    key_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the stored files and their sizes
+    Ls,
+    /// Pretty print meta.json
+    CatMeta,
+    /// Copy the last commit into a directory which tantivy's MmapDirectory can open
+    Export {
+        /// The directory to write the index to
+        directory: PathBuf,
+    },
+    /// Copy the index in a directory into the database
+    Import {
+        /// The directory to read the index from
+        directory: PathBuf,
+        /// Replace the index already in the database
+        #[arg(long)]
+        replace: bool,
+    },
+    /// Check every file against its checksum and look for missing files
+    Verify,
+    /// Delete files which aren't part of the last commit
+    Gc {
+        /// Return the freed pages to the file system afterwards, if the database uses incremental auto vacuum
+        #[arg(long)]
+        vacuum: bool,
+    },
+    /// Show the segments and how many documents they hold
+    Stats,
+    /// Search a field and print the matching documents as json
+    Search {
+        /// The field to search in
+        field: String,
+        /// The query, in tantivy's query language
+        query: String,
+        /// The maximum number of documents to print
+        #[arg(long, default_value_t = 10, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        limit: usize,
+    },
+}
+
+impl Cli {
+    fn open(&self, read_only: bool) -> Result<TantivySqliteStorage, Box<dyn Error>> {
+        let mut manager = SqliteConnectionManager::file(&self.database);
+        if read_only {
+            if !self.database.exists() {
+                return Err(format!("{} does not exist", self.database.display()).into());
+            }
+
+            // Stops a mistyped path from creating an empty database
+            manager = manager.with_flags(
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            );
+        }
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let mut builder = TantivySqliteStorage::builder(pool)
+            .table_prefix(&self.table_prefix)
+            .read_only(read_only);
+        if let Some(namespace) = &self.namespace {
+            builder = builder.namespace(namespace);
+        }
+
+        let layout = match (self.chunked, self.chunk_size) {
+            (_, Some(chunk_size)) => Some(StorageLayout::Chunked { chunk_size }),
+            (true, None) => Some(StorageLayout::Chunked {
+                chunk_size: DEFAULT_CHUNK_SIZE,
+            }),
+            (false, None) => None,
+        };
+        // Without one, the layout recorded in the database is used
+        if let Some(layout) = layout {
+            builder = builder.layout(layout);
+        }
+
+        if self.compress {
+            builder = builder.compression(CompressionConfig::default());
+        }
+        if let Some(key_file) = &self.key_file {
+            builder = builder.encryption(read_key(key_file)?);
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+fn read_key(path: &Path) -> Result<EncryptionKey, Box<dyn Error>> {
+    let contents = fs::read(path)?;
+
+    let bytes = match <[u8; 32]>::try_from(contents.as_slice()) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            let hex = std::str::from_utf8(&contents).unwrap_or_default().trim();
+            let mut bytes = [0; 32];
+            if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(
+                    format!("{} must hold 32 bytes or 64 hex digits", path.display()).into(),
+                );
+            }
+            for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+                *byte = u8::from_str_radix(std::str::from_utf8(digits)?, 16)?;
+            }
+            bytes
+        }
+    };
+
+    Ok(EncryptionKey::from_bytes(bytes))
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(&cli) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e}");
+            let mut source = e.source();
+            while let Some(e) = source {
+                eprintln!("  caused by: {e}");
+                source = e.source();
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: &Cli) -> Result<ExitCode, Box<dyn Error>> {
+    match &cli.command {
+        Command::Ls => {
+            for file in cli.open(true)?.list_files()? {
+                println!("{:>12}  {}", file.size, file.path.display());
+            }
+        }
+        Command::CatMeta => {
+            let meta = cli.open(true)?.atomic_read(Path::new("meta.json"))?;
+            let meta: serde_json::Value = serde_json::from_slice(&meta)?;
+            println!("{}", serde_json::to_string_pretty(&meta)?);
+        }
+        Command::Export { directory } => {
+            cli.open(true)?.export_to_path(directory, |progress| {
+                eprint!(
+                    "\r{}/{} files, {}/{} bytes",
+                    progress.files_copied,
+                    progress.total_files,
+                    progress.bytes_copied,
+                    progress.total_bytes
+                );
+                let _ = std::io::stderr().flush();
+            })?;
+            eprintln!();
+        }
+        Command::Import { directory, replace } => {
+            cli.open(false)?
+                .import_from_path(directory, &ImportOptions { replace: *replace })?;
+        }
+        Command::Verify => {
+            let report = cli.open(true)?.verify()?;
+
+            for (problem, files) in [
+                ("corrupt", &report.corrupt),
+                ("missing", &report.missing),
+                ("orphaned", &report.orphaned),
+                ("unchecked", &report.unchecked),
+            ] {
+                for file in files {
+                    println!("{problem:<10} {}", file.display());
+                }
+            }
+
+            if !report.is_ok() {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Command::Gc { vacuum } => {
+            let report = cli.open(false)?.gc(&GcOptions {
+                incremental_vacuum: *vacuum,
+            })?;
+
+            for file in &report.deleted {
+                println!("deleted    {}", file.display());
+            }
+            for file in &report.skipped_open {
+                println!("still open {}", file.display());
+            }
+            println!(
+                "reclaimed {} bytes, vacuumed {} pages",
+                report.reclaimed_bytes, report.vacuumed_pages
+            );
+        }
+        Command::Stats => {
+            let index = Index::open(cli.open(true)?)?;
+            let segments = index.searchable_segment_metas()?;
+
+            println!("{:<32}  {:>10}  {:>10}", "segment", "docs", "deleted");
+            for segment in &segments {
+                println!(
+                    "{:<32}  {:>10}  {:>10}",
+                    segment.id().uuid_string(),
+                    segment.num_docs(),
+                    segment.num_deleted_docs()
+                );
+            }
+            println!(
+                "{:<32}  {:>10}  {:>10}",
+                format!("total ({} segments)", segments.len()),
+                segments.iter().map(|s| s.num_docs()).sum::<u32>(),
+                segments.iter().map(|s| s.num_deleted_docs()).sum::<u32>()
+            );
+        }
+        Command::Search {
+            field,
+            query,
+            limit,
+        } => {
+            let index = Index::open(cli.open(true)?)?;
+            let schema = index.schema();
+            let field = schema
+                .get_field(field)
+                .ok_or_else(|| format!("the schema has no field called {field:?}"))?;
+
+            let query = QueryParser::for_index(&index, vec![field]).parse_query(query)?;
+            let searcher = index.reader()?.searcher();
+
+            for (score, address) in searcher.search(&query, &TopDocs::with_limit(*limit))? {
+                let doc = searcher.doc(address)?;
+                println!("{score:.3}\t{}", schema.to_json(&doc));
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,351 @@
+//! Configuration for [`TantivySqliteStorage`].
+
+use std::{fmt, sync::Arc, time::Duration};
+
+use rusqlite::Connection;
+
+use crate::{
+    encryption::EncryptionKey,
+    namespace::{self, DEFAULT_TABLE_PREFIX},
+    BlockCacheConfig, CompressionConfig, ConnectionProvider, StorageLayout, TantivySqliteStorage,
+    TantivySqliteStorageError, DEFAULT_LOCK_LEASE, DEFAULT_WATCH_INTERVAL,
+};
+
+/// The sqlite journal mode, see <https://www.sqlite.org/pragma.html#pragma_journal_mode>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalMode {
+    /// `DELETE`, sqlite's default.
+    Delete,
+    /// `TRUNCATE`
+    Truncate,
+    /// `PERSIST`
+    Persist,
+    /// `MEMORY`
+    Memory,
+    /// `WAL`, which allows readers to continue while tantivy is committing.
+    Wal,
+    /// `OFF`
+    Off,
+}
+
+impl JournalMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF",
+        }
+    }
+}
+
+/// The sqlite synchronous setting, see <https://www.sqlite.org/pragma.html#pragma_synchronous>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Synchronous {
+    /// `OFF`
+    Off,
+    /// `NORMAL`, which is safe to use along with [`JournalMode::Wal`].
+    Normal,
+    /// `FULL`, sqlite's default.
+    Full,
+    /// `EXTRA`
+    Extra,
+}
+
+impl Synchronous {
+    fn as_str(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+/// Builds a [`TantivySqliteStorage`] with non-default options. Create one with [`TantivySqliteStorage::builder`].
+///
+/// ```
+/// # use r2d2::Pool;
+/// # use r2d2_sqlite::SqliteConnectionManager;
+/// use tantivy_sqlite_storage::{Synchronous, TantivySqliteStorage};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let connection_manager = SqliteConnectionManager::file("file:tantivy-builder-example?mode=memory&cache=shared");
+/// # let pool = Pool::builder().max_size(4).build(connection_manager)?;
+/// let storage = TantivySqliteStorage::builder(pool)
+///     .chunk_size(128 * 1024)
+///     .synchronous(Synchronous::Normal)
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TantivySqliteStorageBuilder {
+    connections: Arc<dyn ConnectionProvider>,
+    table_prefix: String,
+    namespace: Option<String>,
+    layout: Option<StorageLayout>,
+    block_cache: BlockCacheConfig,
+    eager_load_threshold: Option<usize>,
+    read_only: bool,
+    create_schema: Option<bool>,
+    journal_mode: Option<JournalMode>,
+    synchronous: Option<Synchronous>,
+    lock_lease: Duration,
+    watch_interval: Option<Duration>,
+    compression: Option<CompressionConfig>,
+    encryption: Option<EncryptionKey>,
+}
+
+impl fmt::Debug for TantivySqliteStorageBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TantivySqliteStorageBuilder")
+            .field("table_prefix", &self.table_prefix)
+            .field("namespace", &self.namespace)
+            .field("layout", &self.layout)
+            .field("block_cache", &self.block_cache)
+            .field("eager_load_threshold", &self.eager_load_threshold)
+            .field("read_only", &self.read_only)
+            .field("create_schema", &self.create_schema)
+            .field("journal_mode", &self.journal_mode)
+            .field("synchronous", &self.synchronous)
+            .field("lock_lease", &self.lock_lease)
+            .field("watch_interval", &self.watch_interval)
+            .field("compression", &self.compression)
+            .field("encryption", &self.encryption)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TantivySqliteStorageBuilder {
+    pub(crate) fn new(connections: impl ConnectionProvider) -> Self {
+        Self {
+            connections: Arc::new(connections),
+            table_prefix: DEFAULT_TABLE_PREFIX.to_string(),
+            namespace: None,
+            layout: None,
+            block_cache: BlockCacheConfig::default(),
+            eager_load_threshold: None,
+            read_only: false,
+            create_schema: None,
+            journal_mode: None,
+            synchronous: None,
+            lock_lease: DEFAULT_LOCK_LEASE,
+            watch_interval: Some(DEFAULT_WATCH_INTERVAL),
+            compression: None,
+            encryption: None,
+        }
+    }
+
+    /// Sets the start of every table name, `tantivy` by default. For example, a prefix of `search`
+    /// stores files in `search_blobs` rather than `tantivy_blobs`. Like namespaces, prefixes may
+    /// only contain ascii letters and digits, and can't start with a digit.
+    pub fn table_prefix(mut self, table_prefix: impl Into<String>) -> Self {
+        self.table_prefix = table_prefix.into();
+        self
+    }
+
+    /// Stores the index in its own namespace, see [`TantivySqliteStorage::with_namespace`].
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Sets how files are laid out in the database. By default, the layout recorded in the database
+    /// when its schema was created is used, or [`StorageLayout::SingleBlob`] for a new database.
+    /// Building fails with [`TantivySqliteStorageError::LayoutMismatch`] if the database was created
+    /// with the other kind of layout.
+    pub fn layout(mut self, layout: StorageLayout) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    /// Uses [`StorageLayout::Chunked`] with the given chunk size.
+    pub fn chunk_size(self, chunk_size: usize) -> Self {
+        self.layout(StorageLayout::Chunked { chunk_size })
+    }
+
+    /// Compresses new files as described by `config`. Needs [`StorageLayout::Chunked`], since
+    /// each chunk is compressed separately to keep random access reads cheap.
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, config: CompressionConfig) -> Self {
+        self.compression = Some(config);
+        self
+    }
+
+    /// Encrypts every chunk with a data key which is itself encrypted with `key`. Opening an existing
+    /// index with a different key fails with [`TantivySqliteStorageError::DecryptionFailed`].
+    /// Needs [`StorageLayout::Chunked`]. Encryption has to be enabled when the index is created, since
+    /// an encrypted storage treats any unencrypted file as [`TantivySqliteStorageError::CorruptFile`].
+    #[cfg(feature = "encryption")]
+    pub fn encryption(mut self, key: EncryptionKey) -> Self {
+        self.encryption = Some(key);
+        self
+    }
+
+    /// Configures the block cache, see [`TantivySqliteStorage::set_block_cache`].
+    pub fn block_cache(mut self, config: BlockCacheConfig) -> Self {
+        self.block_cache = config;
+        self
+    }
+
+    /// Enables the block cache with the given capacity in bytes and the default block size.
+    pub fn cache_size(self, capacity: usize) -> Self {
+        self.block_cache(BlockCacheConfig {
+            capacity,
+            ..BlockCacheConfig::default()
+        })
+    }
+
+    /// Loads the file catalog while building the storage, see [`TantivySqliteStorage::load_catalog`].
+    pub fn load_catalog(mut self, eager_load_threshold: usize) -> Self {
+        self.eager_load_threshold = Some(eager_load_threshold);
+        self
+    }
+
+    /// Opens the storage in read-only mode. Any attempt to write or delete files fails with
+    /// [`TantivySqliteStorageError::ReadOnly`], and the schema isn't created.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Whether to create the tables if they don't exist yet. Defaults to `true` unless the storage is read-only.
+    /// If `false`, building fails with [`TantivySqliteStorageError::SchemaDoesNotExist`] if the tables are missing.
+    pub fn create_schema(mut self, create_schema: bool) -> Self {
+        self.create_schema = Some(create_schema);
+        self
+    }
+
+    /// Sets the journal mode on every connection used by the storage, the first time it uses each one.
+    pub fn journal_mode(mut self, journal_mode: JournalMode) -> Self {
+        self.journal_mode = Some(journal_mode);
+        self
+    }
+
+    /// Sets the synchronous pragma on every connection used by the storage, the first time it uses
+    /// each one.
+    pub fn synchronous(mut self, synchronous: Synchronous) -> Self {
+        self.synchronous = Some(synchronous);
+        self
+    }
+
+    /// How long tantivy's locks, such as the index writer lock, stay valid if the process holding them
+    /// stops renewing them, for example because it crashed. Held locks are renewed every third of the lease.
+    /// Defaults to [`DEFAULT_LOCK_LEASE`].
+    pub fn lock_lease(mut self, lock_lease: Duration) -> Self {
+        self.lock_lease = lock_lease;
+        self
+    }
+
+    /// How often to check for commits made through other connections to the database, such as by
+    /// another process, so that readers using [`tantivy::ReloadPolicy::OnCommit`] pick them up.
+    /// Polling starts once something watches the index. `None` disables it, so only commits made
+    /// through this storage are noticed. Defaults to [`DEFAULT_WATCH_INTERVAL`].
+    pub fn watch_interval(mut self, watch_interval: Option<Duration>) -> Self {
+        self.watch_interval = watch_interval;
+        self
+    }
+
+    /// Validates the options and creates the storage.
+    pub fn build(self) -> Result<TantivySqliteStorage, TantivySqliteStorageError> {
+        namespace::validate_table_prefix(&self.table_prefix)?;
+
+        if let Some(namespace) = &self.namespace {
+            namespace::validate(namespace)?;
+        }
+
+        if self.read_only && self.create_schema == Some(true) {
+            return Err(TantivySqliteStorageError::InvalidConfiguration(
+                "a read-only storage can't create its schema".into(),
+            ));
+        }
+
+        if self.read_only && self.journal_mode.is_some() {
+            return Err(TantivySqliteStorageError::InvalidConfiguration(
+                "a read-only storage can't change the journal mode".into(),
+            ));
+        }
+
+        if self.lock_lease.is_zero() {
+            return Err(TantivySqliteStorageError::InvalidConfiguration(
+                "lock lease must not be 0".into(),
+            ));
+        }
+
+        if self
+            .watch_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            return Err(TantivySqliteStorageError::InvalidConfiguration(
+                "watch interval must not be 0".into(),
+            ));
+        }
+
+        let settings = StorageSettings {
+            table_prefix: self.table_prefix,
+            namespace: self.namespace,
+            layout: self.layout,
+            read_only: self.read_only,
+            create_schema: self.create_schema.unwrap_or(!self.read_only),
+            lock_lease: self.lock_lease,
+            watch_interval: self.watch_interval,
+            compression: self.compression,
+            encryption: self.encryption,
+            connection: ConnectionSettings {
+                journal_mode: self.journal_mode,
+                synchronous: self.synchronous,
+            },
+        };
+
+        let storage = TantivySqliteStorage::from_settings(self.connections, settings)?;
+
+        storage.set_block_cache(self.block_cache)?;
+        if let Some(eager_load_threshold) = self.eager_load_threshold {
+            storage.load_catalog(eager_load_threshold)?;
+        }
+
+        Ok(storage)
+    }
+}
+
+/// The validated options from [`TantivySqliteStorageBuilder`].
+pub(crate) struct StorageSettings {
+    pub(crate) table_prefix: String,
+    pub(crate) namespace: Option<String>,
+    /// The layout asked for, if any, which is checked against the one recorded in the database.
+    pub(crate) layout: Option<StorageLayout>,
+    pub(crate) read_only: bool,
+    pub(crate) create_schema: bool,
+    pub(crate) lock_lease: Duration,
+    pub(crate) watch_interval: Option<Duration>,
+    pub(crate) compression: Option<CompressionConfig>,
+    pub(crate) encryption: Option<EncryptionKey>,
+    pub(crate) connection: ConnectionSettings,
+}
+
+/// Pragmas which are applied to the connections used by the storage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ConnectionSettings {
+    journal_mode: Option<JournalMode>,
+    synchronous: Option<Synchronous>,
+}
+
+impl ConnectionSettings {
+    /// Applies the pragmas to a connection. Most journal modes only last for the lifetime of a
+    /// connection, so this is done the first time the storage is given each connection, see
+    /// `ProvidedConnection::configure`.
+    pub(crate) fn apply(&self, conn: &Connection) -> Result<(), TantivySqliteStorageError> {
+        if let Some(journal_mode) = self.journal_mode {
+            conn.pragma_update(None, "journal_mode", journal_mode.as_str())?;
+        }
+
+        if let Some(synchronous) = self.synchronous {
+            conn.pragma_update(None, "synchronous", synchronous.as_str())?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+//! An in-memory cache of recently read blocks, shared by every file handle of a storage.
+
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use lru::LruCache;
+use parking_lot::Mutex;
+use tantivy::directory::OwnedBytes;
+
+use crate::{layout::ReadHandleData, TantivySqliteStorageError};
+
+/// The default size of the blocks stored in the block cache.
+pub const DEFAULT_CACHE_BLOCK_SIZE: usize = 16 * 1024;
+
+/// Configures the block cache used when reading from file handles.
+///
+/// Reads are rounded out to whole blocks of `block_size` bytes, and up to `capacity` bytes worth of
+/// blocks are kept in memory. A `capacity` smaller than `block_size` disables the cache.
+///
+/// Only enable the cache if this storage is the only thing writing to the index, since files deleted
+/// and recreated by another process won't be evicted from the cache until the storage's watcher
+/// notices the commit (see [`TantivySqliteStorageBuilder::watch_interval`](crate::TantivySqliteStorageBuilder::watch_interval)).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockCacheConfig {
+    /// The maximum number of bytes to keep in the cache.
+    pub capacity: usize,
+    /// The size of each block in bytes.
+    pub block_size: usize,
+}
+
+impl Default for BlockCacheConfig {
+    /// The cache is disabled by default.
+    fn default() -> Self {
+        Self {
+            capacity: 0,
+            block_size: DEFAULT_CACHE_BLOCK_SIZE,
+        }
+    }
+}
+
+/// Statistics about how well the block cache is doing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of blocks which were found in the cache.
+    pub hits: u64,
+    /// The number of blocks which had to be read from sqlite.
+    pub misses: u64,
+    /// The number of bytes currently held in the cache.
+    pub cached_bytes: usize,
+}
+
+pub(crate) struct BlockCache {
+    block_size: usize,
+    blocks: Mutex<Blocks>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    /// Returns `None` if the config disables the cache.
+    pub(crate) fn new(config: BlockCacheConfig) -> Result<Option<Self>, TantivySqliteStorageError> {
+        if config.block_size == 0 {
+            return Err(TantivySqliteStorageError::InvalidConfiguration(
+                "block cache block size must not be 0".into(),
+            ));
+        }
+
+        let num_blocks = config.capacity / config.block_size;
+        if num_blocks == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            block_size: config.block_size,
+            blocks: Mutex::new(Blocks {
+                lru: LruCache::new(num_blocks),
+                by_file: HashMap::new(),
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }))
+    }
+
+    /// Reads `range` from the file, using `load` to fetch any blocks which aren't already cached.
+    pub(crate) fn read_bytes(
+        &self,
+        handle: &ReadHandleData,
+        range: Range<usize>,
+        mut load: impl FnMut(Range<usize>) -> Result<OwnedBytes, TantivySqliteStorageError>,
+    ) -> Result<OwnedBytes, TantivySqliteStorageError> {
+        if range.is_empty() {
+            return Ok(OwnedBytes::empty());
+        }
+
+        if range.end > handle.length {
+            return Err(rusqlite::Error::BlobSizeError.into());
+        }
+
+        let first_block = range.start / self.block_size;
+        let last_block = (range.end - 1) / self.block_size;
+
+        let mut block_for = |block: usize| {
+            let key = (handle.file_id, block);
+            if let Some(data) = self.blocks.lock().lru.get(&key) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(data.clone());
+            }
+
+            self.misses.fetch_add(1, Ordering::Relaxed);
+
+            let block_start = block * self.block_size;
+            let block_end = (block_start + self.block_size).min(handle.length);
+            let data = load(block_start..block_end)?;
+
+            self.blocks.lock().put(key, data.clone());
+            Ok::<_, TantivySqliteStorageError>(data)
+        };
+
+        // The common case of a read within a single block doesn't need to copy anything
+        if first_block == last_block {
+            let block_start = first_block * self.block_size;
+            return Ok(
+                block_for(first_block)?.slice(range.start - block_start..range.end - block_start)
+            );
+        }
+
+        let mut buf = Vec::with_capacity(range.len());
+        for block in first_block..=last_block {
+            let block_start = block * self.block_size;
+            let data = block_for(block)?;
+
+            let start = range.start.saturating_sub(block_start);
+            let end = (range.end - block_start).min(data.len());
+            buf.extend_from_slice(&data.as_slice()[start..end]);
+        }
+
+        Ok(OwnedBytes::new(buf))
+    }
+
+    /// Removes every cached block belonging to `file_id`.
+    pub(crate) fn invalidate(&self, file_id: i64) {
+        let mut blocks = self.blocks.lock();
+
+        for block in blocks.by_file.remove(&file_id).into_iter().flatten() {
+            blocks.lru.pop(&(file_id, block));
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut blocks = self.blocks.lock();
+        blocks.lru.clear();
+        blocks.by_file.clear();
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            cached_bytes: self
+                .blocks
+                .lock()
+                .lru
+                .iter()
+                .map(|(_, data)| data.len())
+                .sum(),
+        }
+    }
+}
+
+/// The cached blocks, keyed by file id and block index, along with the blocks cached for each file
+/// so that invalidating a file doesn't need to go through the whole cache.
+struct Blocks {
+    lru: LruCache<(i64, usize), OwnedBytes>,
+    by_file: HashMap<i64, HashSet<usize>>,
+}
+
+impl Blocks {
+    fn put(&mut self, (file_id, block): (i64, usize), data: OwnedBytes) {
+        if let Some((evicted, _)) = self.lru.push((file_id, block), data) {
+            if evicted != (file_id, block) {
+                self.forget(evicted);
+            }
+        }
+
+        self.by_file.entry(file_id).or_default().insert(block);
+    }
+
+    fn forget(&mut self, (file_id, block): (i64, usize)) {
+        if let Entry::Occupied(mut blocks) = self.by_file.entry(file_id) {
+            blocks.get_mut().remove(&block);
+            if blocks.get().is_empty() {
+                blocks.remove();
+            }
+        }
+    }
+}
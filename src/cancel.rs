@@ -0,0 +1,29 @@
+//! Lets a long running operation be stopped part way through, such as when the future of an
+//! `AsyncTantivySqliteStorage` operation is dropped.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::TantivySqliteStorageError;
+
+/// Checked by operations between each step, which then fail with
+/// [`TantivySqliteStorageError::Cancelled`]. Operations started without one are never cancelled.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    #[cfg(feature = "tokio")]
+    pub(crate) fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn check(&self) -> Result<(), TantivySqliteStorageError> {
+        if self.0.load(Ordering::Relaxed) {
+            Err(TantivySqliteStorageError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! An in-memory list of every file in the index, used to avoid a round trip to sqlite per file when opening an index.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use tantivy::directory::OwnedBytes;
+
+use crate::layout::{FileEntry, ReadHandleData};
+
+/// The default size below which files are loaded into memory by [`TantivySqliteStorage::load_catalog`](crate::TantivySqliteStorage::load_catalog).
+pub const DEFAULT_EAGER_LOAD_THRESHOLD: usize = 256 * 1024;
+
+pub(crate) struct Catalog {
+    eager_load_threshold: usize,
+    files: HashMap<PathBuf, CatalogEntry>,
+}
+
+pub(crate) struct CatalogEntry {
+    pub(crate) handle: ReadHandleData,
+    pub(crate) content: Option<OwnedBytes>,
+}
+
+impl Catalog {
+    pub(crate) fn new(eager_load_threshold: usize, files: Vec<FileEntry>) -> Self {
+        // Corrupt files are left to be looked up in sqlite, which fails to read them
+        let files = files
+            .into_iter()
+            .filter(|file| !file.corrupt)
+            .map(|file| {
+                (
+                    file.path,
+                    CatalogEntry {
+                        handle: file.handle,
+                        content: file.content.map(OwnedBytes::new),
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            eager_load_threshold,
+            files,
+        }
+    }
+
+    pub(crate) fn eager_load_threshold(&self) -> usize {
+        self.eager_load_threshold
+    }
+
+    pub(crate) fn get(&self, path: &Path) -> Option<&CatalogEntry> {
+        self.files.get(path)
+    }
+
+    pub(crate) fn insert(&mut self, path: &Path, handle: ReadHandleData, content: Option<Vec<u8>>) {
+        self.files.insert(
+            path.to_path_buf(),
+            CatalogEntry {
+                handle,
+                content: content.map(OwnedBytes::new),
+            },
+        );
+    }
+
+    pub(crate) fn remove(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+}
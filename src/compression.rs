@@ -0,0 +1,127 @@
+//! Optional compression of the chunks stored by [`StorageLayout::Chunked`](crate::StorageLayout::Chunked).
+
+use std::path::Path;
+
+use crate::TantivySqliteStorageError;
+
+/// The codecs which files can be compressed with. Reading or writing compressed files needs the
+/// `compression` feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CompressionCodec {
+    /// [LZ4](https://lz4.org), which is fast enough to decompress on every read.
+    Lz4,
+}
+
+impl CompressionCodec {
+    /// The name stored alongside each compressed file.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            CompressionCodec::Lz4 => "lz4",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Result<Self, TantivySqliteStorageError> {
+        match name {
+            "lz4" => Ok(CompressionCodec::Lz4),
+            _ => Err(TantivySqliteStorageError::UnsupportedCompression(
+                name.to_string(),
+            )),
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    pub(crate) fn compress(self, data: &[u8]) -> Result<Vec<u8>, TantivySqliteStorageError> {
+        match self {
+            CompressionCodec::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+
+    /// Decompresses a chunk which was at most `max_size` bytes long before it was compressed.
+    #[cfg(feature = "compression")]
+    pub(crate) fn decompress(
+        self,
+        data: &[u8],
+        max_size: usize,
+    ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+        let invalid = |e: lz4_flex::block::DecompressError| {
+            TantivySqliteStorageError::InvalidCompressedData(e.to_string())
+        };
+
+        match self {
+            CompressionCodec::Lz4 => {
+                // A corrupt size prefix could otherwise ask for up to 4 GiB
+                let (size, compressed) =
+                    lz4_flex::block::uncompressed_size(data).map_err(invalid)?;
+                if size > max_size {
+                    return Err(TantivySqliteStorageError::InvalidCompressedData(format!(
+                        "chunk claims to be {size} bytes, but chunks are at most {max_size} bytes"
+                    )));
+                }
+
+                let mut decompressed = vec![0; size];
+                let len =
+                    lz4_flex::decompress_into(compressed, &mut decompressed).map_err(invalid)?;
+                if len != size {
+                    return Err(TantivySqliteStorageError::InvalidCompressedData(format!(
+                        "chunk claims to be {size} bytes, but decompressed to {len} bytes"
+                    )));
+                }
+
+                Ok(decompressed)
+            }
+        }
+    }
+
+    #[cfg(not(feature = "compression"))]
+    pub(crate) fn compress(self, _data: &[u8]) -> Result<Vec<u8>, TantivySqliteStorageError> {
+        Err(TantivySqliteStorageError::UnsupportedCompression(
+            self.name().to_string(),
+        ))
+    }
+
+    #[cfg(not(feature = "compression"))]
+    pub(crate) fn decompress(
+        self,
+        _data: &[u8],
+        _max_size: usize,
+    ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+        Err(TantivySqliteStorageError::UnsupportedCompression(
+            self.name().to_string(),
+        ))
+    }
+}
+
+/// Compresses files stored with [`StorageLayout::Chunked`](crate::StorageLayout::Chunked) one chunk at a time,
+/// so reads only need to decompress the chunks they cover. Whether a file is compressed is recorded
+/// alongside it, so files written with different settings can still be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// The codec new files are compressed with.
+    pub codec: CompressionCodec,
+    /// Files with one of these extensions are stored uncompressed. By default this is `store`,
+    /// since tantivy already compresses its doc store.
+    pub skip_extensions: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            codec: CompressionCodec::Lz4,
+            skip_extensions: vec!["store".to_string()],
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// The codec to compress `path` with, if it should be compressed at all.
+    pub(crate) fn codec_for(&self, path: &Path) -> Option<CompressionCodec> {
+        let skip = path.extension().is_some_and(|extension| {
+            self.skip_extensions
+                .iter()
+                .any(|skip_extension| extension == skip_extension.as_str())
+        });
+
+        (!skip).then_some(self.codec)
+    }
+}
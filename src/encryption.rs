@@ -0,0 +1,384 @@
+//! Optional encryption of the chunks stored by [`StorageLayout::Chunked`](crate::StorageLayout::Chunked).
+//!
+//! Chunks are encrypted with a random data key, which is stored in `tantivy_keys` encrypted with the
+//! user's key. Changing the user's key then only needs the data key to be rewrapped, rather than
+//! every chunk to be rewritten.
+//!
+//! Each chunk is encrypted with XChaCha20-Poly1305 under a random nonce, which is stored in front of
+//! the ciphertext. Chunks get rewritten in place when a file is flushed part way through, so a nonce
+//! derived from the chunk's position could be reused. Instead the file id and chunk index are
+//! authenticated as associated data, so a chunk can't be moved to a different position undetected.
+//! File ids are never reused, so the chunks of a deleted file can't pass for those of a later one.
+//!
+//! Each file's length is authenticated by a tag stored alongside it, so that removing chunks from the
+//! end of a file and shortening its length to match is detected as well. Encrypted files have no
+//! checksum, since a checksum of the plaintext would let anyone reading the database confirm guesses
+//! of a file's content.
+
+#[cfg(feature = "encryption")]
+pub(crate) use imp::Encryption;
+#[cfg(feature = "encryption")]
+pub use imp::EncryptionKey;
+#[cfg(not(feature = "encryption"))]
+pub(crate) use stub::{Encryption, EncryptionKey};
+
+#[cfg(feature = "encryption")]
+mod imp {
+    use std::fmt;
+
+    use parking_lot::{MappedRwLockReadGuard, Mutex, RwLock, RwLockReadGuard};
+
+    use chacha20poly1305::{
+        aead::{Aead, OsRng, Payload},
+        AeadCore, KeyInit, XChaCha20Poly1305, XNonce,
+    };
+    use rusqlite::{Connection, OptionalExtension};
+
+    use crate::TantivySqliteStorageError;
+
+    const NONCE_SIZE: usize = 24;
+
+    /// Associated data for the wrapped data key, so it can't be confused with a chunk.
+    const DATA_KEY_AAD: &[u8] = b"tantivy-sqlite-storage data key";
+
+    /// Prefixes the associated data of a length tag, so it can't be confused with a chunk.
+    const LENGTH_AAD: &[u8; 6] = b"length";
+
+    /// A 256 bit key used to encrypt the index. Set with
+    /// [`TantivySqliteStorageBuilder::encryption`](crate::TantivySqliteStorageBuilder::encryption).
+    #[derive(Clone)]
+    pub struct EncryptionKey([u8; 32]);
+
+    impl EncryptionKey {
+        /// Uses the given bytes as the key. They should come from a secure source of randomness
+        /// or a key derivation function, not directly from a password.
+        pub fn from_bytes(bytes: [u8; 32]) -> Self {
+            Self(bytes)
+        }
+
+        /// Generates a new random key.
+        pub fn generate() -> Self {
+            Self(XChaCha20Poly1305::generate_key(&mut OsRng).into())
+        }
+
+        fn cipher(&self) -> XChaCha20Poly1305 {
+            XChaCha20Poly1305::new(&self.0.into())
+        }
+    }
+
+    impl fmt::Debug for EncryptionKey {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "EncryptionKey(..)")
+        }
+    }
+
+    /// Encrypts and decrypts chunks once the data key has been loaded with [`Encryption::open`].
+    pub(crate) struct Encryption {
+        key: Mutex<EncryptionKey>,
+        keys_table: String,
+        data_key: RwLock<Option<XChaCha20Poly1305>>,
+    }
+
+    impl fmt::Debug for Encryption {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Encryption")
+                .field("keys_table", &self.keys_table)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl Encryption {
+        pub(crate) fn new(key: EncryptionKey, keys_table: &str) -> Self {
+            Self {
+                key: Mutex::new(key),
+                keys_table: keys_table.to_string(),
+                data_key: RwLock::new(None),
+            }
+        }
+
+        pub(crate) fn keys_table(&self) -> &str {
+            &self.keys_table
+        }
+
+        pub(crate) fn init(&self, conn: &Connection) -> Result<(), TantivySqliteStorageError> {
+            conn.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY, wrapped_key BLOB NOT NULL)",
+                    self.keys_table
+                ),
+                [],
+            )?;
+            Ok(())
+        }
+
+        /// Loads the data key, creating one if this is the first time the index has been encrypted.
+        /// Fails with [`TantivySqliteStorageError::DecryptionFailed`] if the key is wrong. Replaces
+        /// any data key loaded before, since a restore can bring back an index with a different one.
+        pub(crate) fn open(
+            &self,
+            conn: &Connection,
+            read_only: bool,
+        ) -> Result<(), TantivySqliteStorageError> {
+            let key = self.key.lock();
+
+            if !read_only {
+                let data_key = XChaCha20Poly1305::generate_key(&mut OsRng);
+                conn.execute(
+                    &format!("INSERT OR IGNORE INTO {} VALUES (1, ?)", self.keys_table),
+                    [wrap(&key, &data_key)?],
+                )?;
+            }
+
+            *self.data_key.write() = Some(self.unwrap_data_key(conn, &key)?);
+            Ok(())
+        }
+
+        /// Fails unless the data key stored in `conn`'s database can be unwrapped with the storage's
+        /// key, without loading it. Used to check a backup before restoring it.
+        pub(crate) fn check(&self, conn: &Connection) -> Result<(), TantivySqliteStorageError> {
+            if !crate::migrations::table_exists(conn, &self.keys_table)? {
+                return Err(TantivySqliteStorageError::InvalidConfiguration(
+                    "the database isn't encrypted".into(),
+                ));
+            }
+
+            self.unwrap_data_key(conn, &self.key.lock())?;
+            Ok(())
+        }
+
+        fn unwrap_data_key(
+            &self,
+            conn: &Connection,
+            key: &EncryptionKey,
+        ) -> Result<XChaCha20Poly1305, TantivySqliteStorageError> {
+            let wrapped_key: Option<Vec<u8>> = conn
+                .query_row(
+                    &format!("SELECT wrapped_key FROM {} WHERE id = 1", self.keys_table),
+                    [],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let wrapped_key = wrapped_key.ok_or_else(|| {
+                TantivySqliteStorageError::InvalidConfiguration(
+                    "the database doesn't have an encryption key yet".into(),
+                )
+            })?;
+
+            let data_key = decrypt(&key.cipher(), &wrapped_key, DATA_KEY_AAD)?;
+            XChaCha20Poly1305::new_from_slice(&data_key)
+                .map_err(|_| TantivySqliteStorageError::DecryptionFailed)
+        }
+
+        /// Re-encrypts the data key with `new_key`. Other storages opened with the old key keep
+        /// working until they are reopened.
+        pub(crate) fn rewrap(
+            &self,
+            conn: &Connection,
+            new_key: &EncryptionKey,
+        ) -> Result<(), TantivySqliteStorageError> {
+            let mut key = self.key.lock();
+
+            let wrapped_key: Vec<u8> = conn.query_row(
+                &format!("SELECT wrapped_key FROM {} WHERE id = 1", self.keys_table),
+                [],
+                |row| row.get(0),
+            )?;
+
+            let data_key = decrypt(&key.cipher(), &wrapped_key, DATA_KEY_AAD)?;
+            conn.execute(
+                &format!(
+                    "UPDATE {} SET wrapped_key = ? WHERE id = 1",
+                    self.keys_table
+                ),
+                [wrap(new_key, &data_key)?],
+            )?;
+
+            *key = new_key.clone();
+            Ok(())
+        }
+
+        pub(crate) fn encrypt_chunk(
+            &self,
+            file_id: i64,
+            chunk_index: i64,
+            chunk: &[u8],
+        ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+            encrypt(&*self.data_key()?, chunk, &chunk_aad(file_id, chunk_index))
+        }
+
+        pub(crate) fn decrypt_chunk(
+            &self,
+            file_id: i64,
+            chunk_index: i64,
+            stored: &[u8],
+        ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+            decrypt(&*self.data_key()?, stored, &chunk_aad(file_id, chunk_index))
+        }
+
+        /// Authenticates the length of a file, without encrypting anything.
+        pub(crate) fn length_tag(
+            &self,
+            file_id: i64,
+            length: usize,
+        ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+            encrypt(&*self.data_key()?, &[], &length_aad(file_id, length))
+        }
+
+        /// Fails with [`TantivySqliteStorageError::DecryptionFailed`] unless `tag` was made by
+        /// [`Encryption::length_tag`] for the same file and length.
+        pub(crate) fn check_length_tag(
+            &self,
+            file_id: i64,
+            length: usize,
+            tag: &[u8],
+        ) -> Result<(), TantivySqliteStorageError> {
+            decrypt(&*self.data_key()?, tag, &length_aad(file_id, length))?;
+            Ok(())
+        }
+
+        fn data_key(
+            &self,
+        ) -> Result<MappedRwLockReadGuard<'_, XChaCha20Poly1305>, TantivySqliteStorageError>
+        {
+            RwLockReadGuard::try_map(self.data_key.read(), Option::as_ref).map_err(|_| {
+                TantivySqliteStorageError::InvalidConfiguration(
+                    "the encryption key hasn't been loaded".into(),
+                )
+            })
+        }
+    }
+
+    fn chunk_aad(file_id: i64, chunk_index: i64) -> [u8; 16] {
+        let mut aad = [0; 16];
+        aad[..8].copy_from_slice(&file_id.to_le_bytes());
+        aad[8..].copy_from_slice(&chunk_index.to_le_bytes());
+        aad
+    }
+
+    fn length_aad(file_id: i64, length: usize) -> [u8; 22] {
+        let mut aad = [0; 22];
+        aad[..6].copy_from_slice(LENGTH_AAD);
+        aad[6..14].copy_from_slice(&file_id.to_le_bytes());
+        aad[14..].copy_from_slice(&(length as u64).to_le_bytes());
+        aad
+    }
+
+    fn wrap(key: &EncryptionKey, data_key: &[u8]) -> Result<Vec<u8>, TantivySqliteStorageError> {
+        encrypt(&key.cipher(), data_key, DATA_KEY_AAD)
+    }
+
+    fn encrypt(
+        cipher: &XChaCha20Poly1305,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| TantivySqliteStorageError::EncryptionFailed)?;
+
+        let mut stored = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        stored.extend_from_slice(&nonce);
+        stored.extend_from_slice(&ciphertext);
+        Ok(stored)
+    }
+
+    fn decrypt(
+        cipher: &XChaCha20Poly1305,
+        stored: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+        if stored.len() < NONCE_SIZE {
+            return Err(TantivySqliteStorageError::DecryptionFailed);
+        }
+
+        let (nonce, ciphertext) = stored.split_at(NONCE_SIZE);
+        cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| TantivySqliteStorageError::DecryptionFailed)
+    }
+}
+
+/// Stands in for the encryption types when the `encryption` feature is disabled. Neither can be
+/// constructed, so a storage built without the feature never encrypts anything.
+#[cfg(not(feature = "encryption"))]
+mod stub {
+    use rusqlite::Connection;
+
+    use crate::TantivySqliteStorageError;
+
+    #[derive(Debug, Clone)]
+    pub(crate) enum EncryptionKey {}
+
+    #[derive(Debug)]
+    pub(crate) enum Encryption {}
+
+    impl Encryption {
+        pub(crate) fn new(key: EncryptionKey, _keys_table: &str) -> Self {
+            match key {}
+        }
+
+        pub(crate) fn keys_table(&self) -> &str {
+            match *self {}
+        }
+
+        pub(crate) fn init(&self, _conn: &Connection) -> Result<(), TantivySqliteStorageError> {
+            match *self {}
+        }
+
+        pub(crate) fn open(
+            &self,
+            _conn: &Connection,
+            _read_only: bool,
+        ) -> Result<(), TantivySqliteStorageError> {
+            match *self {}
+        }
+
+        pub(crate) fn encrypt_chunk(
+            &self,
+            _file_id: i64,
+            _chunk_index: i64,
+            _chunk: &[u8],
+        ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+            match *self {}
+        }
+
+        pub(crate) fn decrypt_chunk(
+            &self,
+            _file_id: i64,
+            _chunk_index: i64,
+            _stored: &[u8],
+        ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+            match *self {}
+        }
+
+        pub(crate) fn length_tag(
+            &self,
+            _file_id: i64,
+            _length: usize,
+        ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+            match *self {}
+        }
+
+        pub(crate) fn check_length_tag(
+            &self,
+            _file_id: i64,
+            _length: usize,
+            _tag: &[u8],
+        ) -> Result<(), TantivySqliteStorageError> {
+            match *self {}
+        }
+    }
+}
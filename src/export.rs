@@ -0,0 +1,246 @@
+//! Copies the last commit out of the database into another [`Directory`] or a directory on disk,
+//! producing an index which tantivy can open without this crate.
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use tantivy::directory::{
+    error::{OpenReadError, OpenWriteError},
+    FileHandle, TerminatingWrite, WritePtr,
+};
+use tantivy::Directory;
+
+use crate::{
+    cancel::Cancellation, verify::referenced_files, TantivySqliteStorage,
+    TantivySqliteStorageError, COPY_BLOCK_SIZE, MANAGED_FILES, META_FILE,
+};
+
+/// How far an export has got, passed to the progress callback of [`TantivySqliteStorage::export_to`]
+/// each time a block of a file has been copied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportProgress {
+    /// The number of files which have been copied in full.
+    pub files_copied: usize,
+    /// The number of files being exported, including `meta.json`.
+    pub total_files: usize,
+    /// The number of bytes copied so far.
+    pub bytes_copied: u64,
+    /// The combined length of all the files being exported.
+    pub total_bytes: u64,
+}
+
+/// Where the exported files are written.
+pub(crate) enum ExportTarget<'a> {
+    Directory(&'a dyn Directory),
+    Path(&'a Path),
+}
+
+enum ExportWriter {
+    Directory(WritePtr),
+    File(BufWriter<File>),
+}
+
+impl ExportTarget<'_> {
+    fn exists(&self, path: &Path) -> Result<bool, TantivySqliteStorageError> {
+        match self {
+            ExportTarget::Directory(directory) => directory
+                .exists(path)
+                .map_err(|e| TantivySqliteStorageError::Io(std::io::Error::other(e))),
+            ExportTarget::Path(root) => Ok(root.join(path).try_exists()?),
+        }
+    }
+
+    fn open_write(&self, path: &Path) -> Result<ExportWriter, TantivySqliteStorageError> {
+        match self {
+            ExportTarget::Directory(directory) => directory
+                .open_write(path)
+                .map(ExportWriter::Directory)
+                .map_err(|e| match e {
+                    OpenWriteError::FileAlreadyExists(path) => {
+                        TantivySqliteStorageError::FileAlreadyExists(path)
+                    }
+                    OpenWriteError::IoError { io_error, .. } => {
+                        TantivySqliteStorageError::Io(io_error)
+                    }
+                }),
+            ExportTarget::Path(root) => {
+                let file = File::options()
+                    .write(true)
+                    .create_new(true)
+                    .open(root.join(path))
+                    .map_err(|e| match e.kind() {
+                        std::io::ErrorKind::AlreadyExists => {
+                            TantivySqliteStorageError::FileAlreadyExists(path.to_path_buf())
+                        }
+                        _ => TantivySqliteStorageError::Io(e),
+                    })?;
+
+                Ok(ExportWriter::File(BufWriter::new(file)))
+            }
+        }
+    }
+
+    fn atomic_write(&self, path: &Path, data: &[u8]) -> Result<(), TantivySqliteStorageError> {
+        match self {
+            ExportTarget::Directory(directory) => Ok(directory.atomic_write(path, data)?),
+            ExportTarget::Path(root) => {
+                // Written next to the real file and renamed over it, so it is never seen half written
+                let mut temp_name = path.as_os_str().to_owned();
+                temp_name.push(".tmp");
+                let temp_path = root.join(temp_name);
+
+                let mut file = File::create(&temp_path)?;
+                file.write_all(data)?;
+                file.sync_all()?;
+                fs::rename(&temp_path, root.join(path))?;
+
+                Ok(())
+            }
+        }
+    }
+
+    fn sync(&self) -> Result<(), TantivySqliteStorageError> {
+        match self {
+            ExportTarget::Directory(directory) => Ok(directory.sync_directory()?),
+            ExportTarget::Path(root) => Ok(File::open(root)?.sync_all()?),
+        }
+    }
+}
+
+impl ExportWriter {
+    fn write_all(&mut self, data: &[u8]) -> Result<(), TantivySqliteStorageError> {
+        match self {
+            ExportWriter::Directory(writer) => writer.write_all(data)?,
+            ExportWriter::File(writer) => writer.write_all(data)?,
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), TantivySqliteStorageError> {
+        match self {
+            ExportWriter::Directory(writer) => writer.terminate()?,
+            ExportWriter::File(writer) => writer
+                .into_inner()
+                .map_err(|e| e.into_error())?
+                .sync_all()?,
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn export(
+    storage: &TantivySqliteStorage,
+    target: ExportTarget<'_>,
+    progress: &mut dyn FnMut(ExportProgress),
+    cancellation: &Cancellation,
+) -> Result<(), TantivySqliteStorageError> {
+    if target.exists(Path::new(META_FILE))? {
+        return Err(TantivySqliteStorageError::FileAlreadyExists(PathBuf::from(
+            META_FILE,
+        )));
+    }
+
+    let Snapshot { meta, files } = snapshot(storage)?;
+
+    let mut status = ExportProgress {
+        files_copied: 0,
+        total_files: files.len() + 1,
+        bytes_copied: 0,
+        total_bytes: files
+            .iter()
+            .map(|(_, handle)| handle.len() as u64)
+            .sum::<u64>()
+            + meta.len() as u64,
+    };
+
+    for (path, handle) in &files {
+        let mut writer = target.open_write(path)?;
+
+        for start in (0..handle.len()).step_by(COPY_BLOCK_SIZE) {
+            cancellation.check()?;
+
+            let end = handle.len().min(start + COPY_BLOCK_SIZE);
+            writer.write_all(handle.read_bytes(start..end)?.as_slice())?;
+
+            status.bytes_copied += (end - start) as u64;
+            progress(status);
+        }
+
+        writer.finish()?;
+        status.files_copied += 1;
+        if handle.len() == 0 {
+            progress(status);
+        }
+    }
+
+    // Lets tantivy garbage collect the exported files once they are no longer needed
+    let paths: Vec<&Path> = files.iter().map(|(path, _)| path.as_path()).collect();
+    target.atomic_write(Path::new(MANAGED_FILES), &managed_json(&paths)?)?;
+
+    // Written last, so the index can't be opened until everything it refers to is there
+    target.sync()?;
+    target.atomic_write(Path::new(META_FILE), &meta)?;
+    target.sync()?;
+
+    status.files_copied += 1;
+    status.bytes_copied += meta.len() as u64;
+    progress(status);
+
+    Ok(())
+}
+
+/// The content of a `.managed.json` listing `files` along with `meta.json`, in the same format as tantivy writes it.
+pub(crate) fn managed_json(files: &[&Path]) -> Result<Vec<u8>, TantivySqliteStorageError> {
+    let mut managed = files.to_vec();
+    managed.push(Path::new(META_FILE));
+
+    let mut json = serde_json::to_vec(&managed).map_err(std::io::Error::other)?;
+    json.push(b'\n');
+    Ok(json)
+}
+
+/// A commit's `meta.json`, along with open handles on the files it refers to.
+struct Snapshot {
+    meta: Vec<u8>,
+    files: Vec<(PathBuf, Box<dyn FileHandle>)>,
+}
+
+/// Reads `meta.json` and opens a handle on every file it refers to. Tries again if a commit
+/// lands in the meantime, so that the files always belong to the returned `meta.json`.
+fn snapshot(storage: &TantivySqliteStorage) -> Result<Snapshot, TantivySqliteStorageError> {
+    loop {
+        let meta = storage.inner.read().atomic_read(Path::new(META_FILE))?;
+
+        let mut paths: Vec<PathBuf> = referenced_files(storage)?
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        paths.sort();
+
+        // The open handles also stop `TantivySqliteStorage::gc` deleting the files while they are copied
+        let files = paths
+            .into_iter()
+            .map(|path| {
+                let handle = storage.get_file_handle(&path)?;
+                Ok((path, handle))
+            })
+            .collect::<Result<Vec<_>, OpenReadError>>();
+
+        if storage.inner.read().atomic_read(Path::new(META_FILE))? != meta {
+            continue;
+        }
+
+        return match files {
+            Ok(files) => Ok(Snapshot { meta, files }),
+            Err(OpenReadError::FileDoesNotExist(path)) => {
+                Err(TantivySqliteStorageError::FileDoesNotExist(path))
+            }
+            Err(e) => Err(TantivySqliteStorageError::Io(std::io::Error::other(e))),
+        };
+    }
+}
@@ -0,0 +1,143 @@
+//! Deletes files which tantivy created but which no segment in `meta.json` refers to any more, such
+//! as those left behind by an index writer which crashed before committing.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    lock,
+    verify::{referenced_files, INDEX_FILES},
+    TantivySqliteStorage, TantivySqliteStorageError, MANAGED_FILES,
+};
+
+/// Options for [`TantivySqliteStorage::gc`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcOptions {
+    /// Runs `PRAGMA incremental_vacuum` afterwards to return the freed pages to the file system.
+    /// This only has an effect if the database uses `PRAGMA auto_vacuum = INCREMENTAL`.
+    pub incremental_vacuum: bool,
+}
+
+/// What [`TantivySqliteStorage::gc`] deleted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// The files which were deleted.
+    pub deleted: Vec<PathBuf>,
+    /// The total length of the deleted files.
+    pub reclaimed_bytes: u64,
+    /// Files which would have been deleted, but which a file handle is still reading from,
+    /// for example because a searcher on an older commit is still alive.
+    pub skipped_open: Vec<PathBuf>,
+    /// The number of pages returned to the file system by the incremental vacuum.
+    pub vacuumed_pages: u64,
+}
+
+pub(crate) fn gc(
+    storage: &TantivySqliteStorage,
+    options: &GcOptions,
+) -> Result<GcReport, TantivySqliteStorageError> {
+    storage.inner.read().check_writable()?;
+
+    // The files of a commit in progress aren't in meta.json yet, so nothing can be collected while a writer is open
+    let _writer_lock = lock::acquire_writer_lock(storage)?;
+
+    let mut report = GcReport::default();
+
+    let Some(referenced) = referenced_files(storage)? else {
+        return Ok(report);
+    };
+    let managed = managed_files(storage)?;
+
+    let stored = storage.inner.read().stored_files()?;
+    for file in stored {
+        let collectable = managed.contains(&file.path)
+            && !referenced.contains(&file.path)
+            && !INDEX_FILES
+                .iter()
+                .any(|index_file| file.path == Path::new(index_file));
+        if !collectable {
+            continue;
+        }
+
+        match storage
+            .inner
+            .write()
+            .delete_unless_open(&file.path, file.handle.file_id)
+        {
+            Ok(true) => {
+                report.reclaimed_bytes += file.handle.length as u64;
+                report.deleted.push(file.path);
+            }
+            Ok(false) => report.skipped_open.push(file.path),
+            Err(TantivySqliteStorageError::FileDoesNotExist(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    if options.incremental_vacuum {
+        report.vacuumed_pages = storage.inner.read().incremental_vacuum()?;
+    }
+
+    report.deleted.sort();
+    report.skipped_open.sort();
+
+    Ok(report)
+}
+
+/// The files listed in `.managed.json`. Anything else in the database wasn't created by tantivy, so is left alone.
+fn managed_files(
+    storage: &TantivySqliteStorage,
+) -> Result<HashSet<PathBuf>, TantivySqliteStorageError> {
+    let content = match storage.inner.read().atomic_read(Path::new(MANAGED_FILES)) {
+        Ok(content) => content,
+        Err(TantivySqliteStorageError::FileDoesNotExist(_)) => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+
+    serde_json::from_slice(&content).map_err(|e| {
+        TantivySqliteStorageError::InvalidIndex(format!("{MANAGED_FILES} is invalid: {e}"))
+    })
+}
+
+/// Counts the file handles reading from each file, so that [`gc`] can leave those files alone.
+#[derive(Debug, Default)]
+pub(crate) struct OpenFiles(Arc<Mutex<HashMap<i64, usize>>>);
+
+impl OpenFiles {
+    pub(crate) fn open(&self, file_id: i64) -> OpenFile {
+        *self.0.lock().entry(file_id).or_default() += 1;
+
+        OpenFile {
+            files: self.0.clone(),
+            file_id,
+        }
+    }
+
+    pub(crate) fn is_open(&self, file_id: i64) -> bool {
+        self.0.lock().contains_key(&file_id)
+    }
+}
+
+/// Marks a file as open until it is dropped.
+pub(crate) struct OpenFile {
+    files: Arc<Mutex<HashMap<i64, usize>>>,
+    file_id: i64,
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        let mut files = self.files.lock();
+
+        if let Some(count) = files.get_mut(&self.file_id) {
+            *count -= 1;
+            if *count == 0 {
+                files.remove(&self.file_id);
+            }
+        }
+    }
+}
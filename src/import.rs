@@ -0,0 +1,149 @@
+//! Copies an index from another [`Directory`] or a directory on disk into the database, for example
+//! to move an index built with tantivy's `MmapDirectory` into sqlite.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use tantivy::{
+    directory::{error::OpenReadError, RamDirectory, TerminatingWrite},
+    Directory, Index,
+};
+
+use crate::{
+    cancel::Cancellation, export::managed_json, lock, verify, TantivySqliteStorage,
+    TantivySqliteStorageError, TantivySqliteStorageWritePtr, COPY_BLOCK_SIZE, MANAGED_FILES,
+    META_FILE,
+};
+
+/// Options for [`TantivySqliteStorage::import_from`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportOptions {
+    /// Deletes the index already in the storage, if there is one, and replaces it with the imported
+    /// one. Otherwise importing into a storage which already holds an index fails with
+    /// [`TantivySqliteStorageError::FileAlreadyExists`]. Searchers still open on the replaced index
+    /// fail once the import commits.
+    pub replace: bool,
+}
+
+/// Where the imported files are read from.
+pub(crate) enum ImportSource<'a> {
+    Directory(&'a dyn Directory),
+    Path(&'a Path),
+}
+
+impl ImportSource<'_> {
+    fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, TantivySqliteStorageError> {
+        match self {
+            ImportSource::Directory(directory) => {
+                directory.atomic_read(path).map_err(open_read_error)
+            }
+            ImportSource::Path(root) => {
+                std::fs::read(root.join(path)).map_err(|e| match e.kind() {
+                    std::io::ErrorKind::NotFound => {
+                        TantivySqliteStorageError::FileDoesNotExist(path.to_path_buf())
+                    }
+                    _ => TantivySqliteStorageError::Io(e),
+                })
+            }
+        }
+    }
+
+    fn copy(&self, path: &Path, writer: &mut dyn Write) -> Result<(), TantivySqliteStorageError> {
+        match self {
+            ImportSource::Directory(directory) => {
+                let handle = directory.get_file_handle(path).map_err(open_read_error)?;
+
+                for start in (0..handle.len()).step_by(COPY_BLOCK_SIZE) {
+                    let end = handle.len().min(start + COPY_BLOCK_SIZE);
+                    writer.write_all(handle.read_bytes(start..end)?.as_slice())?;
+                }
+            }
+            ImportSource::Path(root) => {
+                let mut file = File::open(root.join(path)).map_err(|e| match e.kind() {
+                    std::io::ErrorKind::NotFound => {
+                        TantivySqliteStorageError::FileDoesNotExist(path.to_path_buf())
+                    }
+                    _ => TantivySqliteStorageError::Io(e),
+                })?;
+                std::io::copy(&mut file, writer)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn open_read_error(e: OpenReadError) -> TantivySqliteStorageError {
+    match e {
+        OpenReadError::FileDoesNotExist(path) => TantivySqliteStorageError::FileDoesNotExist(path),
+        e => TantivySqliteStorageError::Io(std::io::Error::other(e)),
+    }
+}
+
+pub(crate) fn import(
+    storage: &TantivySqliteStorage,
+    source: ImportSource<'_>,
+    options: &ImportOptions,
+    cancellation: &Cancellation,
+) -> Result<(), TantivySqliteStorageError> {
+    storage.inner.read().check_writable()?;
+
+    let meta = source.atomic_read(Path::new(META_FILE))?;
+    let files = segment_files(&meta)?;
+
+    let _writer_lock = lock::acquire_writer_lock(storage)?;
+    // Dropped without committing if anything fails, which leaves the storage as it was
+    let transaction = storage.begin_transaction()?;
+
+    if options.replace {
+        let stored = storage.inner.read().stored_files()?;
+        for file in stored {
+            storage.inner.write().delete(&file.path)?;
+        }
+    } else if storage.inner.read().exists(Path::new(META_FILE))? {
+        return Err(TantivySqliteStorageError::FileAlreadyExists(PathBuf::from(
+            META_FILE,
+        )));
+    }
+
+    for path in &files {
+        cancellation.check()?;
+
+        storage.inner.write().create_empty_file(path)?;
+
+        let mut writer = BufWriter::new(TantivySqliteStorageWritePtr::new(path, storage.clone()));
+        source.copy(path, &mut writer)?;
+        writer.terminate()?;
+    }
+
+    let paths: Vec<&Path> = files.iter().map(PathBuf::as_path).collect();
+    storage
+        .inner
+        .write()
+        .atomic_write(Path::new(MANAGED_FILES), &managed_json(&paths)?)?;
+    storage
+        .inner
+        .write()
+        .atomic_write(Path::new(META_FILE), &meta)?;
+
+    transaction.commit()
+}
+
+/// The files needed by the segments in `meta`, found by opening it in an otherwise empty
+/// directory. This also checks that this version of tantivy can read the index.
+fn segment_files(meta: &[u8]) -> Result<Vec<PathBuf>, TantivySqliteStorageError> {
+    let directory = RamDirectory::create();
+    directory.atomic_write(Path::new(META_FILE), meta)?;
+
+    let segments = Index::open(directory)
+        .and_then(|index| index.searchable_segment_metas())
+        .map_err(|e| TantivySqliteStorageError::InvalidIndex(e.to_string()))?;
+
+    let mut files: Vec<PathBuf> = verify::segment_files(&segments).collect();
+    files.sort();
+
+    Ok(files)
+}
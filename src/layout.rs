@@ -0,0 +1,355 @@
+//! The different ways that tantivy's files can be laid out in the database.
+
+use std::{
+    mem,
+    ops::{Deref, Range},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::Connection;
+use tantivy::directory::OwnedBytes;
+
+use crate::{
+    encryption::{Encryption, EncryptionKey},
+    namespace::TableNames,
+    CompressionCodec, CompressionConfig, TantivySqliteStorageError,
+};
+
+mod chunked;
+mod single_blob;
+
+pub(crate) use chunked::ChunkedLayout;
+pub(crate) use single_blob::SingleBlobLayout;
+
+/// The default size of each chunk when using [`StorageLayout::Chunked`].
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How tantivy's files are stored in the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageLayout {
+    /// Every file is stored as a single blob in `tantivy_blobs`. This is the layout used by
+    /// earlier versions of this crate, so is the default in order to keep working with existing databases.
+    #[default]
+    SingleBlob,
+    /// Files are listed in `tantivy_files` and their content is split into `chunk_size` byte
+    /// chunks stored in `tantivy_chunks`. Reads only need to fetch the chunks covering the
+    /// requested range, and large files never end up as a single huge blob.
+    Chunked {
+        /// The size of each chunk in bytes. Files keep the chunk size they were written with,
+        /// so this can be changed for an existing database.
+        chunk_size: usize,
+    },
+}
+
+impl StorageLayout {
+    /// The layout to use for a database created with the `stored` layout, if its schema has been
+    /// created yet. The chunk size may differ from the stored one, but the kind of layout can't.
+    pub(crate) fn resolve(
+        requested: Option<StorageLayout>,
+        stored: Option<StorageLayout>,
+    ) -> Result<StorageLayout, TantivySqliteStorageError> {
+        match (requested, stored) {
+            (Some(requested), Some(stored))
+                if mem::discriminant(&requested) != mem::discriminant(&stored) =>
+            {
+                Err(TantivySqliteStorageError::LayoutMismatch { requested, stored })
+            }
+            (Some(layout), _) | (None, Some(layout)) => Ok(layout),
+            (None, None) => Ok(StorageLayout::default()),
+        }
+    }
+
+    pub(crate) fn build(
+        self,
+        tables: TableNames,
+        compression: Option<CompressionConfig>,
+        encryption: Option<EncryptionKey>,
+    ) -> Result<Box<dyn FileLayout>, TantivySqliteStorageError> {
+        match self {
+            StorageLayout::SingleBlob if compression.is_some() => {
+                Err(TantivySqliteStorageError::InvalidConfiguration(
+                    "compression needs the chunked layout".into(),
+                ))
+            }
+            StorageLayout::SingleBlob if encryption.is_some() => {
+                Err(TantivySqliteStorageError::InvalidConfiguration(
+                    "encryption needs the chunked layout".into(),
+                ))
+            }
+            StorageLayout::SingleBlob => Ok(Box::new(SingleBlobLayout { tables })),
+            StorageLayout::Chunked { chunk_size: 0 } => Err(
+                TantivySqliteStorageError::InvalidConfiguration("chunk size must not be 0".into()),
+            ),
+            StorageLayout::Chunked { chunk_size } => Ok(Box::new(ChunkedLayout {
+                chunk_size,
+                compression,
+                encryption: encryption.map(|key| Encryption::new(key, &tables.keys)),
+                tables,
+            })),
+        }
+    }
+}
+
+/// Everything needed to read from a file once it has been opened.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ReadHandleData {
+    pub(crate) file_id: i64,
+    pub(crate) length: usize,
+    /// The chunk size the file was written with, if it was written with [`StorageLayout::Chunked`].
+    pub(crate) chunk_size: Option<usize>,
+    /// How each chunk of the file is compressed, if it is.
+    pub(crate) compression: Option<CompressionCodec>,
+    /// Whether each chunk of the file is encrypted.
+    pub(crate) encrypted: bool,
+    /// Whether the file is still being written with [`StorageLayout::SingleBlob`], so its content is
+    /// read from the parts written so far rather than its blob.
+    pub(crate) unfinished: bool,
+    /// The CRC32C of the file's content, unless it was written by a version of this crate which didn't store one.
+    pub(crate) checksum: Option<u32>,
+}
+
+/// A file as returned by [`FileLayout::list_files`].
+#[derive(Debug)]
+pub(crate) struct FileEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) handle: ReadHandleData,
+    /// The full content of the file, if it was smaller than the threshold passed to `list_files`.
+    pub(crate) content: Option<Vec<u8>>,
+    /// When the file was created and last written, in milliseconds since the unix epoch.
+    pub(crate) created_at: Option<i64>,
+    pub(crate) updated_at: Option<i64>,
+    /// Whether the file's metadata failed to authenticate, or its content failed to check out while
+    /// it was being loaded. Its content is left out, and reading it fails with
+    /// [`TantivySqliteStorageError::CorruptFile`].
+    pub(crate) corrupt: bool,
+}
+
+impl FileEntry {
+    pub(crate) fn mark_corrupt(&mut self) {
+        self.corrupt = true;
+        self.content = None;
+    }
+
+    pub(crate) fn info(self) -> FileInfo {
+        let to_system_time =
+            |millis: Option<i64>| Some(UNIX_EPOCH + Duration::from_millis(millis? as u64));
+
+        FileInfo {
+            path: self.path,
+            size: self.handle.length as u64,
+            created_at: to_system_time(self.created_at),
+            updated_at: to_system_time(self.updated_at),
+            checksum: self.handle.checksum,
+        }
+    }
+}
+
+/// A file stored in the database, as returned by [`TantivySqliteStorage::list_files`](crate::TantivySqliteStorage::list_files).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    /// The path tantivy gave the file.
+    pub path: PathBuf,
+    /// The length of the file in bytes, before any compression or encryption.
+    pub size: u64,
+    /// When the file was created. `None` for files written by a version of this crate which didn't record it.
+    pub created_at: Option<SystemTime>,
+    /// When the file's content was last written, which is when it was flushed for files written with `open_write`.
+    pub updated_at: Option<SystemTime>,
+    /// The CRC32C of the file's content, as checked by [`TantivySqliteStorage::verify`](crate::TantivySqliteStorage::verify).
+    pub checksum: Option<u32>,
+}
+
+/// The queries needed to implement [`tantivy::Directory`] for a given layout.
+///
+/// Files being written with `open_write` are sent to the layout in parts of [`FileLayout::part_size`]
+/// bytes as they arrive. The last part written may be rewritten with more data if the file is flushed
+/// before it is finished.
+pub(crate) trait FileLayout: Send + Sync {
+    fn init(&self, conn: &Connection) -> Result<(), TantivySqliteStorageError>;
+
+    /// Called once the tables exist, whether or not they were created by [`FileLayout::init`].
+    fn open(&self, _conn: &Connection, _read_only: bool) -> Result<(), TantivySqliteStorageError> {
+        Ok(())
+    }
+
+    /// The encryption used for new files, if any.
+    #[cfg(feature = "encryption")]
+    fn encryption(&self) -> Option<&Encryption> {
+        None
+    }
+
+    /// The tables which must already exist to open an index without creating its schema.
+    fn required_tables(&self) -> Vec<&str>;
+
+    /// Every table the layout reads from along with the columns it reads, so that a read-only
+    /// storage can tell whether a database written by an earlier version can be read as it is.
+    fn read_columns(&self) -> Vec<(&str, &[&str])>;
+
+    fn exists(&self, conn: &Connection, path: &Path) -> Result<bool, TantivySqliteStorageError>;
+
+    fn delete(&self, conn: &Connection, path: &Path) -> Result<(), TantivySqliteStorageError>;
+
+    fn create_empty_file(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> Result<(), TantivySqliteStorageError>;
+
+    fn part_size(&self) -> usize;
+
+    fn write_part(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        part: i64,
+        data: &[u8],
+    ) -> Result<(), TantivySqliteStorageError>;
+
+    /// Makes all the parts written so far visible as the content of the file, which is `length` bytes long
+    /// and has the given checksum. `finished` is set once the writer has been terminated and no more parts will arrive.
+    fn finish_parts(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        length: usize,
+        checksum: u32,
+        finished: bool,
+    ) -> Result<(), TantivySqliteStorageError>;
+
+    fn atomic_write(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        data: &[u8],
+    ) -> Result<(), TantivySqliteStorageError>;
+
+    /// Reads the whole file, failing with [`TantivySqliteStorageError::CorruptFile`] if it doesn't match its checksum.
+    fn atomic_read(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> Result<Vec<u8>, TantivySqliteStorageError>;
+
+    fn read_handle(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> Result<ReadHandleData, TantivySqliteStorageError>;
+
+    fn read_bytes(
+        &self,
+        conn: &Connection,
+        handle: &ReadHandleData,
+        range: Range<usize>,
+    ) -> Result<OwnedBytes, TantivySqliteStorageError>;
+
+    /// Passes the content of the file to `f` in order, a chunk or part at a time, so that it never
+    /// needs to be held in memory all at once. Stops early if some of the content is missing.
+    fn read_pieces(
+        &self,
+        conn: &Connection,
+        handle: &ReadHandleData,
+        f: &mut dyn FnMut(&[u8]),
+    ) -> Result<(), TantivySqliteStorageError>;
+
+    /// Checks the file against its length and checksum as it is read, failing with
+    /// [`TantivySqliteStorageError::CorruptFile`] if it doesn't match.
+    fn check_file(&self, conn: &Connection, path: &Path) -> Result<(), TantivySqliteStorageError> {
+        let handle = self.read_handle(conn, path)?;
+
+        let mut length = 0;
+        let mut checksum = 0;
+        self.read_pieces(conn, &handle, &mut |piece| {
+            length += piece.len();
+            checksum = crc32c::crc32c_append(checksum, piece);
+        })
+        .map_err(mark_corrupt(path))?;
+
+        if length != handle.length || handle.checksum.is_some_and(|expected| expected != checksum) {
+            return Err(TantivySqliteStorageError::CorruptFile(path.to_path_buf()));
+        }
+
+        Ok(())
+    }
+
+    /// Lists every file without a query per file. The content of any file shorter than
+    /// `load_content_below` bytes is fetched at the same time.
+    fn list_files(
+        &self,
+        conn: &Connection,
+        load_content_below: usize,
+    ) -> Result<Vec<FileEntry>, TantivySqliteStorageError>;
+}
+
+/// Reports chunks which can't be decompressed as corruption of `path`, once the file they belong to is known.
+pub(crate) fn mark_corrupt(
+    path: &Path,
+) -> impl FnOnce(TantivySqliteStorageError) -> TantivySqliteStorageError + '_ {
+    move |e| match e {
+        TantivySqliteStorageError::InvalidCompressedData(_) => {
+            TantivySqliteStorageError::CorruptFile(path.to_path_buf())
+        }
+        e => e,
+    }
+}
+
+/// Checks that a file's content is as long as expected and matches its checksum, if it has one.
+pub(crate) fn check_content(
+    path: &Path,
+    content: &[u8],
+    length: usize,
+    checksum: Option<u32>,
+) -> Result<(), TantivySqliteStorageError> {
+    let checksum_matches = checksum.is_none_or(|checksum| crc32c::crc32c(content) == checksum);
+
+    if content.len() != length || !checksum_matches {
+        return Err(TantivySqliteStorageError::CorruptFile(path.to_path_buf()));
+    }
+
+    Ok(())
+}
+
+/// Like [`rusqlite::Transaction`], but can be nested inside a transaction which is already open on the
+/// connection, such as one started by [`TantivySqliteStorage::begin_transaction`](crate::TantivySqliteStorage::begin_transaction).
+/// Rolled back when dropped unless it is committed.
+pub(crate) struct Savepoint<'conn> {
+    conn: &'conn Connection,
+    committed: bool,
+}
+
+impl<'conn> Savepoint<'conn> {
+    pub(crate) fn new(conn: &'conn Connection) -> Result<Self, TantivySqliteStorageError> {
+        conn.execute_batch("SAVEPOINT tantivy_sqlite_storage")?;
+
+        Ok(Self {
+            conn,
+            committed: false,
+        })
+    }
+
+    pub(crate) fn commit(mut self) -> Result<(), TantivySqliteStorageError> {
+        self.conn.execute_batch("RELEASE tantivy_sqlite_storage")?;
+        self.committed = true;
+
+        Ok(())
+    }
+}
+
+impl Deref for Savepoint<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+    }
+}
+
+impl Drop for Savepoint<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = self.conn.execute_batch(
+                "ROLLBACK TO tantivy_sqlite_storage; RELEASE tantivy_sqlite_storage",
+            );
+        }
+    }
+}
@@ -0,0 +1,644 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    ffi::OsStr,
+    ops::Range,
+    os::unix::prelude::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{params, Connection, OptionalExtension};
+use tantivy::directory::OwnedBytes;
+
+use super::{check_content, mark_corrupt, FileEntry, FileLayout, ReadHandleData, Savepoint};
+use crate::{
+    encryption::Encryption, namespace::TableNames, now_millis, CompressionCodec, CompressionConfig,
+    TantivySqliteStorageError,
+};
+
+/// Lists every file in `tantivy_files` and splits their content into fixed size chunks in
+/// `tantivy_chunks`. Files being written have their chunks inserted as soon as they are full.
+///
+/// If compression is enabled, each chunk is compressed on its own and the codec is recorded in
+/// the `compression` column of `tantivy_files`. Encrypted chunks are compressed first, and marked
+/// by the `encrypted` column. Encrypted files have their length authenticated by `length_tag` rather
+/// than a checksum, and file ids are never reused, so that chunks of a deleted file can't be
+/// decrypted as part of a new one.
+#[derive(Debug)]
+pub(crate) struct ChunkedLayout {
+    pub(crate) chunk_size: usize,
+    pub(crate) tables: TableNames,
+    pub(crate) compression: Option<CompressionConfig>,
+    pub(crate) encryption: Option<Encryption>,
+}
+
+impl ChunkedLayout {
+    fn codec_for(&self, path: &Path) -> Option<CompressionCodec> {
+        self.compression.as_ref()?.codec_for(path)
+    }
+
+    /// Turns a chunk's content into what gets stored in the database.
+    fn encode_chunk<'a>(
+        &self,
+        codec: Option<CompressionCodec>,
+        file_id: i64,
+        chunk_index: i64,
+        chunk: &'a [u8],
+    ) -> Result<Cow<'a, [u8]>, TantivySqliteStorageError> {
+        let chunk = match codec {
+            Some(codec) => Cow::Owned(codec.compress(chunk)?),
+            None => Cow::Borrowed(chunk),
+        };
+
+        match &self.encryption {
+            Some(encryption) => Ok(Cow::Owned(encryption.encrypt_chunk(
+                file_id,
+                chunk_index,
+                &chunk,
+            )?)),
+            None => Ok(chunk),
+        }
+    }
+
+    /// The reverse of [`ChunkedLayout::encode_chunk`].
+    fn decode_chunk<'a>(
+        &self,
+        handle: &ReadHandleData,
+        chunk_index: i64,
+        stored: &'a [u8],
+    ) -> Result<Cow<'a, [u8]>, TantivySqliteStorageError> {
+        let stored = if handle.encrypted {
+            let encryption = self.encryption.as_ref().ok_or_else(|| {
+                TantivySqliteStorageError::InvalidConfiguration(
+                    "file is encrypted, but no encryption key was given".into(),
+                )
+            })?;
+
+            Cow::Owned(encryption.decrypt_chunk(handle.file_id, chunk_index, stored)?)
+        } else {
+            Cow::Borrowed(stored)
+        };
+
+        match handle.compression {
+            Some(codec) => Ok(Cow::Owned(
+                codec.decompress(&stored, handle.chunk_size.unwrap_or(self.chunk_size))?,
+            )),
+            None => Ok(stored),
+        }
+    }
+
+    /// Encrypted files don't store a checksum of their content, see [`crate::encryption`].
+    fn stored_checksum(&self, checksum: u32) -> Option<u32> {
+        self.encryption.is_none().then_some(checksum)
+    }
+
+    fn length_tag(
+        &self,
+        file_id: i64,
+        length: usize,
+    ) -> Result<Option<Vec<u8>>, TantivySqliteStorageError> {
+        self.encryption
+            .as_ref()
+            .map(|encryption| encryption.length_tag(file_id, length))
+            .transpose()
+    }
+
+    /// Fails with [`TantivySqliteStorageError::CorruptFile`] if the storage is encrypted and the file
+    /// isn't, or its length doesn't match its tag. Neither the `encrypted` column nor the tag can be
+    /// trusted to say whether there is anything to check, since either could have been changed.
+    fn check_length_tag(
+        &self,
+        path: &Path,
+        handle: &ReadHandleData,
+        tag: Option<&[u8]>,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let Some(encryption) = &self.encryption else {
+            return Ok(());
+        };
+
+        let corrupt = || TantivySqliteStorageError::CorruptFile(path.to_path_buf());
+        match tag {
+            Some(tag) if handle.encrypted => encryption
+                .check_length_tag(handle.file_id, handle.length, tag)
+                .map_err(|_| corrupt()),
+            _ => Err(corrupt()),
+        }
+    }
+
+    fn file_id(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> Result<Option<i64>, TantivySqliteStorageError> {
+        Ok(conn
+            .query_row(
+                &format!("SELECT id FROM {} WHERE filename = ?", self.tables.files),
+                [path.as_os_str().as_bytes()],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn remove_file(
+        &self,
+        conn: &Connection,
+        file_id: i64,
+    ) -> Result<(), TantivySqliteStorageError> {
+        conn.execute(
+            &format!("DELETE FROM {} WHERE file_id = ?", self.tables.chunks),
+            [file_id],
+        )?;
+        conn.execute(
+            &format!("DELETE FROM {} WHERE id = ?", self.tables.files),
+            [file_id],
+        )?;
+        Ok(())
+    }
+}
+
+impl FileLayout for ChunkedLayout {
+    fn init(&self, conn: &Connection) -> Result<(), TantivySqliteStorageError> {
+        conn.execute(&format!("CREATE TABLE IF NOT EXISTS {} (id INTEGER PRIMARY KEY AUTOINCREMENT, filename TEXT UNIQUE NOT NULL, length INTEGER NOT NULL, chunk_size INTEGER NOT NULL, compression TEXT, encrypted INTEGER NOT NULL DEFAULT 0, checksum INTEGER, created_at INTEGER, updated_at INTEGER, length_tag BLOB)", self.tables.files), [])?;
+        conn.execute(&format!("CREATE TABLE IF NOT EXISTS {} (file_id INTEGER NOT NULL, chunk_index INTEGER NOT NULL, content BLOB NOT NULL, PRIMARY KEY (file_id, chunk_index))", self.tables.chunks), [])?;
+
+        if let Some(encryption) = &self.encryption {
+            encryption.init(conn)?;
+        }
+
+        Ok(())
+    }
+
+    fn open(&self, conn: &Connection, read_only: bool) -> Result<(), TantivySqliteStorageError> {
+        match &self.encryption {
+            Some(encryption) => encryption.open(conn, read_only),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+
+    fn required_tables(&self) -> Vec<&str> {
+        let mut tables = vec![self.tables.files.as_str(), &self.tables.chunks];
+        if let Some(encryption) = &self.encryption {
+            tables.push(encryption.keys_table());
+        }
+
+        tables
+    }
+
+    fn read_columns(&self) -> Vec<(&str, &[&str])> {
+        vec![
+            (
+                &self.tables.files,
+                &[
+                    "id",
+                    "filename",
+                    "length",
+                    "chunk_size",
+                    "compression",
+                    "encrypted",
+                    "checksum",
+                    "created_at",
+                    "updated_at",
+                    "length_tag",
+                ],
+            ),
+            (&self.tables.chunks, &["file_id", "chunk_index", "content"]),
+        ]
+    }
+
+    fn exists(&self, conn: &Connection, path: &Path) -> Result<bool, TantivySqliteStorageError> {
+        Ok(self.file_id(conn, path)?.is_some())
+    }
+
+    fn delete(&self, conn: &Connection, path: &Path) -> Result<(), TantivySqliteStorageError> {
+        let transaction = Savepoint::new(conn)?;
+
+        let file_id = self
+            .file_id(&transaction, path)?
+            .ok_or_else(|| TantivySqliteStorageError::FileDoesNotExist(path.to_path_buf()))?;
+        self.remove_file(&transaction, file_id)?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn create_empty_file(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let transaction = Savepoint::new(conn)?;
+
+        let num_rows_modified = transaction.execute(
+            &format!(
+                "INSERT OR IGNORE INTO {} (filename, length, chunk_size, compression, encrypted, checksum, created_at, updated_at) VALUES (?1, 0, ?2, ?3, ?4, ?5, ?6, ?6)",
+                self.tables.files
+            ),
+            params![
+                path.as_os_str().as_bytes(),
+                self.chunk_size,
+                self.codec_for(path).map(CompressionCodec::name),
+                self.encryption.is_some(),
+                self.stored_checksum(crc32c::crc32c(b"")),
+                now_millis()
+            ],
+        )?;
+
+        if num_rows_modified != 1 {
+            return Err(TantivySqliteStorageError::FileAlreadyExists(
+                path.to_path_buf(),
+            ));
+        }
+
+        let file_id = transaction.last_insert_rowid();
+        transaction.execute(
+            &format!(
+                "UPDATE {} SET length_tag = ? WHERE id = ?",
+                self.tables.files
+            ),
+            params![self.length_tag(file_id, 0)?, file_id],
+        )?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn part_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    fn write_part(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        part: i64,
+        data: &[u8],
+    ) -> Result<(), TantivySqliteStorageError> {
+        let file_id = self
+            .file_id(conn, path)?
+            .ok_or_else(|| TantivySqliteStorageError::FileDoesNotExist(path.to_path_buf()))?;
+
+        conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO {} VALUES (?, ?, ?)",
+                self.tables.chunks
+            ),
+            params![
+                file_id,
+                part,
+                self.encode_chunk(self.codec_for(path), file_id, part, data)?
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn finish_parts(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        length: usize,
+        checksum: u32,
+        _finished: bool,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let Some(file_id) = self.file_id(conn, path)? else {
+            return Ok(());
+        };
+
+        conn.execute(
+            &format!(
+                "UPDATE {} SET length = ?, checksum = ?, length_tag = ?, updated_at = ? WHERE id = ?",
+                self.tables.files
+            ),
+            params![
+                length,
+                self.stored_checksum(checksum),
+                self.length_tag(file_id, length)?,
+                now_millis(),
+                file_id
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn atomic_write(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        data: &[u8],
+    ) -> Result<(), TantivySqliteStorageError> {
+        let transaction = Savepoint::new(conn)?;
+
+        let now = now_millis();
+        let mut created_at = now;
+        if let Some(file_id) = self.file_id(&transaction, path)? {
+            let existing_created_at: Option<i64> = transaction.query_row(
+                &format!("SELECT created_at FROM {} WHERE id = ?", self.tables.files),
+                [file_id],
+                |row| row.get(0),
+            )?;
+            created_at = existing_created_at.unwrap_or(now);
+
+            self.remove_file(&transaction, file_id)?;
+        }
+
+        let codec = self.codec_for(path);
+        transaction.execute(
+            &format!(
+                "INSERT INTO {} (filename, length, chunk_size, compression, encrypted, checksum, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                self.tables.files
+            ),
+            params![
+                path.as_os_str().as_bytes(),
+                data.len(),
+                self.chunk_size,
+                codec.map(CompressionCodec::name),
+                self.encryption.is_some(),
+                self.stored_checksum(crc32c::crc32c(data)),
+                created_at,
+                now
+            ],
+        )?;
+        let file_id = transaction.last_insert_rowid();
+        transaction.execute(
+            &format!(
+                "UPDATE {} SET length_tag = ? WHERE id = ?",
+                self.tables.files
+            ),
+            params![self.length_tag(file_id, data.len())?, file_id],
+        )?;
+
+        {
+            let mut statement = transaction.prepare(&format!(
+                "INSERT INTO {} VALUES (?, ?, ?)",
+                self.tables.chunks
+            ))?;
+            for (chunk_index, chunk) in (0..).zip(data.chunks(self.chunk_size)) {
+                statement.execute(params![
+                    file_id,
+                    chunk_index,
+                    self.encode_chunk(codec, file_id, chunk_index, chunk)?
+                ])?;
+            }
+        }
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn atomic_read(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+        let handle = self.read_handle(conn, path)?;
+
+        let mut content = Vec::with_capacity(handle.length);
+        self.read_pieces(conn, &handle, &mut |chunk| content.extend_from_slice(chunk))
+            .map_err(mark_corrupt(path))?;
+
+        check_content(path, &content, handle.length, handle.checksum)?;
+        Ok(content)
+    }
+
+    fn read_handle(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> Result<ReadHandleData, TantivySqliteStorageError> {
+        let handle_data = conn
+            .query_row(
+                &format!(
+                    "SELECT id, length, chunk_size, compression, encrypted, checksum, length_tag FROM {} WHERE filename = ?",
+                    self.tables.files
+                ),
+                [path.as_os_str().as_bytes()],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                        row.get::<_, Option<Vec<u8>>>(6)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        let (file_id, length, chunk_size, compression, encrypted, checksum, length_tag) =
+            handle_data
+                .ok_or_else(|| TantivySqliteStorageError::FileDoesNotExist(path.to_path_buf()))?;
+
+        let handle = ReadHandleData {
+            file_id,
+            length,
+            chunk_size: Some(chunk_size),
+            compression: parse_codec(compression)?,
+            encrypted,
+            unfinished: false,
+            checksum,
+        };
+        self.check_length_tag(path, &handle, length_tag.as_deref())?;
+
+        Ok(handle)
+    }
+
+    /// Only fetches the chunks which overlap with `range`.
+    fn read_bytes(
+        &self,
+        conn: &Connection,
+        handle: &ReadHandleData,
+        range: Range<usize>,
+    ) -> Result<OwnedBytes, TantivySqliteStorageError> {
+        if range.is_empty() {
+            return Ok(OwnedBytes::empty());
+        }
+
+        let chunk_size = handle.chunk_size.unwrap_or(self.chunk_size);
+        let first_chunk = range.start / chunk_size;
+        let last_chunk = (range.end - 1) / chunk_size;
+
+        let mut buf = Vec::with_capacity(range.len());
+
+        let mut statement = conn.prepare(
+            &format!("SELECT chunk_index, content FROM {} WHERE file_id = ? AND chunk_index BETWEEN ? AND ? ORDER BY chunk_index", self.tables.chunks),
+        )?;
+        let mut rows = statement.query(params![handle.file_id, first_chunk, last_chunk])?;
+        let mut expected_chunk = first_chunk;
+        while let Some(row) = rows.next()? {
+            let chunk_index: usize = row.get(0)?;
+            if chunk_index != expected_chunk {
+                // A chunk is missing, so the read can't be satisfied
+                break;
+            }
+            expected_chunk += 1;
+
+            let stored = row.get_ref(1)?.as_blob().map_err(rusqlite::Error::from)?;
+            let chunk = self.decode_chunk(handle, chunk_index as i64, stored)?;
+            let chunk_start = chunk_index * chunk_size;
+
+            let start = range.start.saturating_sub(chunk_start).min(chunk.len());
+            let end = (range.end - chunk_start).min(chunk.len());
+            buf.extend_from_slice(&chunk[start..end]);
+        }
+
+        if buf.len() != range.len() {
+            return Err(rusqlite::Error::BlobSizeError.into());
+        }
+
+        Ok(OwnedBytes::new(buf))
+    }
+
+    /// Ignores any chunks past the end of the file, which a writer may have written before its next flush.
+    fn read_pieces(
+        &self,
+        conn: &Connection,
+        handle: &ReadHandleData,
+        f: &mut dyn FnMut(&[u8]),
+    ) -> Result<(), TantivySqliteStorageError> {
+        let chunk_size = handle.chunk_size.unwrap_or(self.chunk_size);
+
+        let mut statement = conn.prepare(&format!(
+            "SELECT chunk_index, content FROM {} WHERE file_id = ? AND chunk_index < ? ORDER BY chunk_index",
+            self.tables.chunks
+        ))?;
+        let mut rows =
+            statement.query(params![handle.file_id, handle.length.div_ceil(chunk_size)])?;
+        let mut expected_chunk = 0;
+        while let Some(row) = rows.next()? {
+            let chunk_index: usize = row.get(0)?;
+            if chunk_index != expected_chunk {
+                break;
+            }
+            expected_chunk += 1;
+
+            let stored = row.get_ref(1)?.as_blob().map_err(rusqlite::Error::from)?;
+            f(&self.decode_chunk(handle, chunk_index as i64, stored)?);
+        }
+
+        Ok(())
+    }
+
+    fn list_files(
+        &self,
+        conn: &Connection,
+        load_content_below: usize,
+    ) -> Result<Vec<FileEntry>, TantivySqliteStorageError> {
+        let mut files = vec![];
+        {
+            let mut statement = conn.prepare(&format!(
+                "SELECT filename, id, length, chunk_size, compression, encrypted, checksum, created_at, updated_at, length_tag FROM {}",
+                self.tables.files
+            ))?;
+            let mut rows = statement.query([])?;
+            while let Some(row) = rows.next()? {
+                let handle = ReadHandleData {
+                    file_id: row.get(1)?,
+                    length: row.get(2)?,
+                    chunk_size: Some(row.get(3)?),
+                    compression: parse_codec(row.get(4)?)?,
+                    encrypted: row.get(5)?,
+                    unfinished: false,
+                    checksum: row.get(6)?,
+                };
+
+                let path = PathBuf::from(OsStr::from_bytes(
+                    row.get_ref(0)?.as_bytes().map_err(rusqlite::Error::from)?,
+                ));
+                // One tampered file mustn't stop the others from being listed
+                let corrupt = self
+                    .check_length_tag(
+                        &path,
+                        &handle,
+                        row.get_ref(9)?
+                            .as_blob_or_null()
+                            .map_err(rusqlite::Error::from)?,
+                    )
+                    .is_err();
+
+                files.push(FileEntry {
+                    path,
+                    content: (handle.length < load_content_below && !corrupt).then(Vec::new),
+                    handle,
+                    created_at: row.get(7)?,
+                    updated_at: row.get(8)?,
+                    corrupt,
+                });
+            }
+        }
+
+        // Chunks need their file's handle to be decoded, so the small files are fetched in a second
+        // query, along with the index of the chunk each file needs next
+        let mut contents: HashMap<i64, (&mut FileEntry, usize)> = files
+            .iter_mut()
+            .filter(|file| file.content.is_some())
+            .map(|file| (file.handle.file_id, (file, 0)))
+            .collect();
+
+        {
+            let mut statement = conn.prepare(&format!(
+                "SELECT chunks.file_id, chunks.chunk_index, chunks.content FROM {} AS chunks JOIN {} AS files ON files.id = chunks.file_id WHERE files.length < ? ORDER BY chunks.file_id, chunks.chunk_index",
+                self.tables.chunks, self.tables.files
+            ))?;
+            let mut rows = statement.query([load_content_below])?;
+            while let Some(row) = rows.next()? {
+                let Some((file, next_chunk)) = contents.get_mut(&row.get(0)?) else {
+                    continue;
+                };
+                if file.corrupt {
+                    continue;
+                }
+
+                // A gap leaves the file short, which is caught below. Chunks past the end may
+                // have been written by a writer which hasn't flushed them yet.
+                let chunk_index: usize = row.get(1)?;
+                let chunk_size = file.handle.chunk_size.unwrap_or(self.chunk_size);
+                if chunk_index != *next_chunk || chunk_index * chunk_size >= file.handle.length {
+                    continue;
+                }
+                *next_chunk += 1;
+
+                let stored = row.get_ref(2)?.as_blob().map_err(rusqlite::Error::from)?;
+                match (
+                    self.decode_chunk(&file.handle, chunk_index as i64, stored),
+                    &mut file.content,
+                ) {
+                    (Ok(chunk), Some(content)) => content.extend_from_slice(&chunk),
+                    _ => file.mark_corrupt(),
+                }
+            }
+        }
+
+        // The content is kept in memory from now on, so it is only checked this once
+        for (file, _) in contents.into_values() {
+            if let Some(content) = &mut file.content {
+                content.truncate(file.handle.length);
+                if check_content(
+                    &file.path,
+                    content,
+                    file.handle.length,
+                    file.handle.checksum,
+                )
+                .is_err()
+                {
+                    file.mark_corrupt();
+                }
+            }
+        }
+
+        Ok(files)
+    }
+}
+
+fn parse_codec(
+    name: Option<String>,
+) -> Result<Option<CompressionCodec>, TantivySqliteStorageError> {
+    name.as_deref().map(CompressionCodec::from_name).transpose()
+}
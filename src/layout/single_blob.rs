@@ -0,0 +1,482 @@
+use std::{
+    ffi::OsStr,
+    ops::Range,
+    os::unix::prelude::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{params, Connection, DatabaseName, OptionalExtension, Row};
+use tantivy::directory::OwnedBytes;
+
+use super::{check_content, FileEntry, FileLayout, ReadHandleData, Savepoint};
+use crate::{namespace::TableNames, now_millis, TantivySqliteStorageError};
+
+/// Stores every file as a single row in `tantivy_blobs`. Files being written are streamed
+/// into `tantivy_blob_parts` and copied into `tantivy_blobs` once, when they are finished. Until
+/// then, their content is read from the parts.
+#[derive(Debug)]
+pub(crate) struct SingleBlobLayout {
+    pub(crate) tables: TableNames,
+}
+
+/// How much data is buffered by the write pointer before it is sent to sqlite.
+const WRITE_PART_SIZE: usize = 1024 * 1024;
+
+impl SingleBlobLayout {
+    /// The columns read by [`handle_from_row`]. A file with parts is still being written, so its
+    /// length is that of its parts, and it has no checksum yet.
+    fn handle_columns(&self) -> String {
+        format!(
+            "rowid, length(content), checksum, (SELECT SUM(length(content)) FROM {} WHERE filename = {}.filename)",
+            self.tables.blob_parts, self.tables.blobs
+        )
+    }
+
+    /// Reads `range` of an unfinished file from its parts, which are all [`WRITE_PART_SIZE`] bytes
+    /// long apart from the last.
+    fn read_parts(
+        &self,
+        conn: &Connection,
+        handle: &ReadHandleData,
+        range: Range<usize>,
+    ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+        let mut buf = Vec::with_capacity(range.len());
+        if range.is_empty() {
+            return Ok(buf);
+        }
+
+        let mut statement = conn.prepare(&format!(
+            "SELECT part, content FROM {} WHERE filename = (SELECT filename FROM {} WHERE rowid = ?) AND part BETWEEN ? AND ? ORDER BY part",
+            self.tables.blob_parts, self.tables.blobs
+        ))?;
+        let mut rows = statement.query(params![
+            handle.file_id,
+            range.start / WRITE_PART_SIZE,
+            (range.end - 1) / WRITE_PART_SIZE
+        ])?;
+
+        while let Some(row) = rows.next()? {
+            let part_start = row.get::<_, usize>(0)? * WRITE_PART_SIZE;
+            let part = row.get_ref(1)?.as_blob().map_err(rusqlite::Error::from)?;
+
+            let start = range.start.max(part_start) - part_start;
+            let end = (range.end - part_start).min(part.len());
+            if part_start + start != range.start + buf.len() || start > end {
+                break;
+            }
+            buf.extend_from_slice(&part[start..end]);
+        }
+
+        if buf.len() != range.len() {
+            return Err(rusqlite::Error::BlobSizeError.into());
+        }
+
+        Ok(buf)
+    }
+}
+
+/// Builds a handle from the [`SingleBlobLayout::handle_columns`] starting at column `first`.
+fn handle_from_row(row: &Row<'_>, first: usize) -> rusqlite::Result<ReadHandleData> {
+    let parts_length: Option<usize> = row.get(first + 3)?;
+
+    Ok(ReadHandleData {
+        file_id: row.get(first)?,
+        length: parts_length.map_or_else(|| row.get(first + 1), Ok)?,
+        chunk_size: None,
+        compression: None,
+        encrypted: false,
+        unfinished: parts_length.is_some(),
+        checksum: if parts_length.is_some() {
+            None
+        } else {
+            row.get(first + 2)?
+        },
+    })
+}
+
+impl FileLayout for SingleBlobLayout {
+    fn init(&self, conn: &Connection) -> Result<(), TantivySqliteStorageError> {
+        conn.execute(&format!("CREATE TABLE IF NOT EXISTS {} (filename TEXT UNIQUE NOT NULL, content BLOB NOT NULL, checksum INTEGER, created_at INTEGER, updated_at INTEGER)", self.tables.blobs), [])?;
+        conn.execute(&format!("CREATE TABLE IF NOT EXISTS {} (filename TEXT NOT NULL, part INTEGER NOT NULL, content BLOB NOT NULL, PRIMARY KEY (filename, part))", self.tables.blob_parts), [])?;
+        Ok(())
+    }
+
+    fn required_tables(&self) -> Vec<&str> {
+        vec![&self.tables.blobs]
+    }
+
+    fn read_columns(&self) -> Vec<(&str, &[&str])> {
+        vec![
+            (
+                &self.tables.blobs,
+                &[
+                    "filename",
+                    "content",
+                    "checksum",
+                    "created_at",
+                    "updated_at",
+                ],
+            ),
+            (&self.tables.blob_parts, &["filename", "part", "content"]),
+        ]
+    }
+
+    fn exists(&self, conn: &Connection, path: &Path) -> Result<bool, TantivySqliteStorageError> {
+        let exists: Option<i32> = conn
+            .query_row(
+                &format!("SELECT 1 FROM {} WHERE filename = ?", self.tables.blobs),
+                [path.as_os_str().as_bytes()],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(exists.is_some())
+    }
+
+    fn delete(&self, conn: &Connection, path: &Path) -> Result<(), TantivySqliteStorageError> {
+        let transaction = Savepoint::new(conn)?;
+
+        let num_deleted = transaction.execute(
+            &format!("DELETE FROM {} WHERE filename = ?", self.tables.blobs),
+            [path.as_os_str().as_bytes()],
+        )?;
+
+        if num_deleted == 0 {
+            return Err(TantivySqliteStorageError::FileDoesNotExist(
+                path.to_path_buf(),
+            ));
+        }
+
+        // The file may be deleted before its writer finished
+        transaction.execute(
+            &format!("DELETE FROM {} WHERE filename = ?", self.tables.blob_parts),
+            [path.as_os_str().as_bytes()],
+        )?;
+
+        transaction.commit()
+    }
+
+    fn create_empty_file(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let num_rows_modified = conn.execute(
+            &format!(
+                "INSERT OR IGNORE INTO {} (filename, content, checksum, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4)",
+                self.tables.blobs
+            ),
+            params![
+                path.as_os_str().as_bytes(),
+                b"",
+                crc32c::crc32c(b""),
+                now_millis()
+            ],
+        )?;
+
+        if num_rows_modified != 1 {
+            return Err(TantivySqliteStorageError::FileAlreadyExists(
+                path.to_path_buf(),
+            ));
+        }
+
+        // A writer which crashed part way through may have left some parts behind
+        conn.execute(
+            &format!("DELETE FROM {} WHERE filename = ?", self.tables.blob_parts),
+            [path.as_os_str().as_bytes()],
+        )?;
+
+        Ok(())
+    }
+
+    fn part_size(&self) -> usize {
+        WRITE_PART_SIZE
+    }
+
+    fn write_part(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        part: i64,
+        data: &[u8],
+    ) -> Result<(), TantivySqliteStorageError> {
+        conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO {} VALUES (?, ?, ?)",
+                self.tables.blob_parts
+            ),
+            params![path.as_os_str().as_bytes(), part, data],
+        )?;
+
+        Ok(())
+    }
+
+    /// Once the file is finished, copies its parts into its blob, one part at a time so that the
+    /// whole file never needs to be held in memory. Until then the parts are already readable, so
+    /// flushing only needs to note when the file was written.
+    fn finish_parts(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        _length: usize,
+        checksum: u32,
+        finished: bool,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let filename = path.as_os_str().as_bytes();
+
+        if !finished {
+            conn.execute(
+                &format!(
+                    "UPDATE {} SET updated_at = ? WHERE filename = ?",
+                    self.tables.blobs
+                ),
+                params![now_millis(), filename],
+            )?;
+            return Ok(());
+        }
+
+        let transaction = Savepoint::new(conn)?;
+
+        let length: i64 = transaction.query_row(
+            &format!(
+                "SELECT COALESCE(SUM(length(content)), 0) FROM {} WHERE filename = ?",
+                self.tables.blob_parts
+            ),
+            [filename],
+            |row| row.get(0),
+        )?;
+
+        // Updating the row in place keeps its rowid, which open handles and the block cache use
+        transaction.execute(
+            &format!(
+                "INSERT INTO {} (filename, content, checksum, created_at, updated_at) VALUES (?1, zeroblob(?2), ?3, ?4, ?4)
+                ON CONFLICT (filename) DO UPDATE SET content = excluded.content, checksum = excluded.checksum, updated_at = excluded.updated_at",
+                self.tables.blobs
+            ),
+            params![filename, length, checksum, now_millis()],
+        )?;
+        let rowid: i64 = transaction.query_row(
+            &format!("SELECT rowid FROM {} WHERE filename = ?", self.tables.blobs),
+            [filename],
+            |row| row.get(0),
+        )?;
+
+        {
+            let mut blob = transaction.blob_open(
+                DatabaseName::Main,
+                &self.tables.blobs,
+                "content",
+                rowid,
+                false,
+            )?;
+
+            let mut statement = transaction.prepare(&format!(
+                "SELECT content FROM {} WHERE filename = ? ORDER BY part",
+                self.tables.blob_parts
+            ))?;
+            let mut rows = statement.query([filename])?;
+
+            let mut offset = 0;
+            while let Some(row) = rows.next()? {
+                let part = row.get_ref(0)?.as_blob().map_err(rusqlite::Error::from)?;
+                blob.write_all_at(part, offset)?;
+                offset += part.len();
+            }
+        }
+
+        transaction.execute(
+            &format!("DELETE FROM {} WHERE filename = ?", self.tables.blob_parts),
+            [filename],
+        )?;
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn atomic_write(
+        &self,
+        conn: &Connection,
+        path: &Path,
+        data: &[u8],
+    ) -> Result<(), TantivySqliteStorageError> {
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (filename, content, checksum, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4)
+                ON CONFLICT (filename) DO UPDATE SET content = excluded.content, checksum = excluded.checksum, updated_at = excluded.updated_at",
+                self.tables.blobs
+            ),
+            params![
+                path.as_os_str().as_bytes(),
+                data,
+                crc32c::crc32c(data),
+                now_millis()
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn atomic_read(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> Result<Vec<u8>, TantivySqliteStorageError> {
+        let handle = self.read_handle(conn, path)?;
+        if handle.unfinished {
+            let content = self.read_parts(conn, &handle, 0..handle.length)?;
+            check_content(path, &content, handle.length, handle.checksum)?;
+            return Ok(content);
+        }
+
+        let content: Option<(Vec<u8>, Option<u32>)> = conn
+            .query_row(
+                &format!(
+                    "SELECT content, checksum FROM {} WHERE filename = ?",
+                    self.tables.blobs
+                ),
+                [path.as_os_str().as_bytes()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let (content, checksum) = content
+            .ok_or_else(|| TantivySqliteStorageError::FileDoesNotExist(path.to_path_buf()))?;
+
+        check_content(path, &content, content.len(), checksum)?;
+        Ok(content)
+    }
+
+    fn read_handle(
+        &self,
+        conn: &Connection,
+        path: &Path,
+    ) -> Result<ReadHandleData, TantivySqliteStorageError> {
+        conn.query_row(
+            &format!(
+                "SELECT {} FROM {} WHERE filename = ?",
+                self.handle_columns(),
+                self.tables.blobs
+            ),
+            [path.as_os_str().as_bytes()],
+            |row| handle_from_row(row, 0),
+        )
+        .optional()?
+        .ok_or_else(|| TantivySqliteStorageError::FileDoesNotExist(path.to_path_buf()))
+    }
+
+    fn read_bytes(
+        &self,
+        conn: &Connection,
+        handle: &ReadHandleData,
+        range: Range<usize>,
+    ) -> Result<OwnedBytes, TantivySqliteStorageError> {
+        if handle.unfinished {
+            return Ok(OwnedBytes::new(self.read_parts(conn, handle, range)?));
+        }
+
+        let blob = conn.blob_open(
+            DatabaseName::Main,
+            &self.tables.blobs,
+            "content",
+            handle.file_id,
+            true,
+        )?;
+
+        let mut buf = vec![0; range.len()];
+
+        blob.read_at_exact(&mut buf, range.start)?;
+        Ok(OwnedBytes::new(buf))
+    }
+
+    fn read_pieces(
+        &self,
+        conn: &Connection,
+        handle: &ReadHandleData,
+        f: &mut dyn FnMut(&[u8]),
+    ) -> Result<(), TantivySqliteStorageError> {
+        if handle.unfinished {
+            let mut statement = conn.prepare(&format!(
+                "SELECT part, content FROM {} WHERE filename = (SELECT filename FROM {} WHERE rowid = ?) ORDER BY part",
+                self.tables.blob_parts, self.tables.blobs
+            ))?;
+            let mut rows = statement.query([handle.file_id])?;
+            let mut expected_part = 0;
+            while let Some(row) = rows.next()? {
+                if row.get::<_, usize>(0)? != expected_part {
+                    break;
+                }
+                expected_part += 1;
+
+                f(row.get_ref(1)?.as_blob().map_err(rusqlite::Error::from)?);
+            }
+
+            return Ok(());
+        }
+
+        let blob = conn.blob_open(
+            DatabaseName::Main,
+            &self.tables.blobs,
+            "content",
+            handle.file_id,
+            true,
+        )?;
+
+        let mut buf = vec![0; handle.length.min(WRITE_PART_SIZE)];
+        for start in (0..handle.length).step_by(WRITE_PART_SIZE) {
+            let piece = &mut buf[..(handle.length - start).min(WRITE_PART_SIZE)];
+            blob.read_at_exact(piece, start)?;
+            f(piece);
+        }
+
+        Ok(())
+    }
+
+    fn list_files(
+        &self,
+        conn: &Connection,
+        load_content_below: usize,
+    ) -> Result<Vec<FileEntry>, TantivySqliteStorageError> {
+        let mut statement = conn.prepare(&format!(
+            "SELECT filename, created_at, updated_at, CASE WHEN length(content) < ? THEN content END, {} FROM {}",
+            self.handle_columns(),
+            self.tables.blobs
+        ))?;
+
+        let mut files: Vec<FileEntry> = statement
+            .query_map([load_content_below], |row| {
+                Ok(FileEntry {
+                    path: PathBuf::from(OsStr::from_bytes(row.get_ref(0)?.as_bytes()?)),
+                    created_at: row.get(1)?,
+                    updated_at: row.get(2)?,
+                    content: row.get(3)?,
+                    handle: handle_from_row(row, 4)?,
+                    corrupt: false,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        for file in &mut files {
+            if file.handle.unfinished {
+                file.content = (file.handle.length < load_content_below)
+                    .then(|| self.read_parts(conn, &file.handle, 0..file.handle.length))
+                    .transpose()?;
+            }
+
+            // The content is kept in memory from now on, so it is only checked this once
+            if let Some(content) = &file.content {
+                if check_content(
+                    &file.path,
+                    content,
+                    file.handle.length,
+                    file.handle.checksum,
+                )
+                .is_err()
+                {
+                    file.mark_corrupt();
+                }
+            }
+        }
+
+        Ok(files)
+    }
+}
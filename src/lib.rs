@@ -8,8 +8,33 @@
 //! to io partial reads while allowing concurrent writes. So a lot of operations
 //! are ultimately serialised.
 //!
-//! All the data is stored in a table called `tantivy_blobs`. You should not interact
-//! with this table directly, and instead let tantivy manage that for you.
+//! By default, all the data is stored in a table called `tantivy_blobs`. You should not interact
+//! with this table directly, and instead let tantivy manage that for you. Files which
+//! are being written are streamed into `tantivy_blob_parts` first, and are only copied
+//! into `tantivy_blobs` once they are finished.
+//!
+//! Alternatively, [`StorageLayout::Chunked`] splits files into fixed size chunks, which
+//! avoids huge blobs and means reads only touch the chunks they need. See [`StorageLayout`]
+//! for more details.
+//!
+//! Several indexes can be kept in the same database by giving each of them a namespace
+//! with [`TantivySqliteStorage::with_namespace`]. The tables for a namespaced index are
+//! prefixed with the namespace's name, for example `articles_tantivy_blobs`.
+//!
+//! Connections usually come from an r2d2 pool, but a storage can get them from any
+//! [`ConnectionProvider`]. [`SingleConnection`] shares a single [`rusqlite::Connection`] the
+//! application already has, and [`ReadWriteConnections`] uses a dedicated pair of connections, one
+//! for reading and one for writing.
+//!
+//! Tantivy's locks, such as the one preventing two index writers from running at once, are rows
+//! in `tantivy_locks` rather than files. They are leased and kept renewed while held, so a lock
+//! left behind by a crashed process expires after [`DEFAULT_LOCK_LEASE`] instead of blocking
+//! writers forever.
+//!
+//! The version of the storage format is recorded in `tantivy_storage_meta`. Databases written by
+//! older versions of this crate are upgraded in place when the storage is created, and databases
+//! written by newer versions are refused rather than risk misreading them. The layout used to
+//! create the schema is recorded there too, so a storage built without one uses the same layout.
 //!
 //! # Example
 //!
@@ -47,29 +72,93 @@
 
 use std::{
     fmt::Debug,
-    io::{BufWriter, Cursor, Write},
+    io::{BufWriter, Write},
     ops::Range,
-    os::unix::prelude::OsStrExt,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use r2d2::Pool;
-use r2d2_sqlite::SqliteConnectionManager;
-
-use rusqlite::{DatabaseName, OptionalExtension};
-
 use tantivy::{
     directory::{
-        error, FileHandle, OwnedBytes, TerminatingWrite, WatchCallback, WatchCallbackList,
-        WatchHandle, WritePtr,
+        error, DirectoryLock, FileHandle, Lock, OwnedBytes, TerminatingWrite, WatchCallback,
+        WatchCallbackList, WatchHandle, WritePtr, INDEX_WRITER_LOCK,
     },
     Directory, HasLen,
 };
 
 use thiserror::Error;
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+
+#[cfg(feature = "tokio")]
+mod async_storage;
+mod backup;
+mod builder;
+mod cache;
+mod cancel;
+mod catalog;
+mod compression;
+mod encryption;
+mod export;
+mod gc;
+mod import;
+mod layout;
+mod lock;
+mod migrations;
+mod namespace;
+mod provider;
+mod sync;
+mod transaction;
+mod verify;
+#[cfg(feature = "vtab")]
+mod vtab;
+mod watcher;
+
+#[cfg(feature = "tokio")]
+pub use async_storage::{AsyncTantivySqliteStorage, CommitWatcher};
+pub use backup::BackupProgress;
+use builder::{ConnectionSettings, StorageSettings};
+use cache::BlockCache;
+use cancel::Cancellation;
+use catalog::{Catalog, CatalogEntry};
+use layout::{FileEntry, FileLayout, ReadHandleData};
+use lock::Lease;
+
+pub use builder::{JournalMode, Synchronous, TantivySqliteStorageBuilder};
+pub use cache::{BlockCacheConfig, CacheStats, DEFAULT_CACHE_BLOCK_SIZE};
+pub use catalog::DEFAULT_EAGER_LOAD_THRESHOLD;
+pub use compression::{CompressionCodec, CompressionConfig};
+#[cfg(feature = "encryption")]
+pub use encryption::EncryptionKey;
+pub use export::ExportProgress;
+use export::ExportTarget;
+pub use gc::{GcOptions, GcReport};
+use gc::{OpenFile, OpenFiles};
+pub use import::ImportOptions;
+use import::ImportSource;
+pub use layout::{FileInfo, StorageLayout, DEFAULT_CHUNK_SIZE};
+pub use lock::DEFAULT_LOCK_LEASE;
+use namespace::TableNames;
+pub use provider::{
+    ConnectionProvider, ProvidedConnection, ReadWriteConnections, SingleConnection,
+    DEFAULT_CONNECTION_TIMEOUT,
+};
+pub use sync::{SyncTable, SyncWorker, TantivySqliteSync};
+pub use transaction::StorageTransaction;
+use transaction::{SharedConnection, StorageConnection};
+pub use verify::VerifyReport;
+#[cfg(feature = "vtab")]
+pub use vtab::{register_search_function, DEFAULT_SEARCH_LIMIT};
+pub use watcher::DEFAULT_WATCH_INTERVAL;
+use watcher::{MetaVersion, Watcher};
+
+/// The file tantivy commits the list of segments in the index to.
+pub(crate) const META_FILE: &str = "meta.json";
+/// The list of files which tantivy has created, and so is allowed to delete.
+pub(crate) const MANAGED_FILES: &str = ".managed.json";
+/// How much of a file is read at a time when copying it into or out of the database.
+pub(crate) const COPY_BLOCK_SIZE: usize = 1024 * 1024;
 
 /// The possible errors produced by this library.
 #[derive(Error, Debug)]
@@ -80,17 +169,117 @@ pub enum TantivySqliteStorageError {
     /// An error directly from r2d2
     #[error("r2d2 pool error")]
     Pool(#[from] r2d2::Error),
+    /// A [`SingleConnection`] or [`ReadWriteConnections`] wasn't given back in time by whatever was
+    /// using it
+    #[error("Timed out waiting for a connection")]
+    ConnectionTimeout,
     /// A requested file doesn't exist
     #[error("File does not exist")]
     FileDoesNotExist(PathBuf),
     /// File already exists
     #[error("File already exists")]
     FileAlreadyExists(PathBuf),
+    /// The storage was configured with options which can't be used
+    #[error("Invalid configuration: {0}")]
+    InvalidConfiguration(String),
+    /// A namespace name contains an underscore or characters which can't be used in a table name
+    #[error("Invalid namespace name: {0}")]
+    InvalidNamespace(String),
+    /// The namespace being dropped doesn't exist
+    #[error("Namespace does not exist: {0}")]
+    NamespaceDoesNotExist(String),
+    /// Tried to modify a storage which was opened read-only
+    #[error("Storage is read-only")]
+    ReadOnly,
+    /// The storage was told not to create its schema, but the given table doesn't exist
+    #[error("Table {0} does not exist")]
+    SchemaDoesNotExist(String),
+    /// A file is compressed with a codec which this build can't read, for example because the
+    /// `compression` feature is disabled
+    #[error("Unsupported compression codec: {0}")]
+    UnsupportedCompression(String),
+    /// A compressed chunk couldn't be decompressed. Reading the file it belongs to fails with
+    /// [`TantivySqliteStorageError::CorruptFile`] instead
+    #[error("Invalid compressed data: {0}")]
+    InvalidCompressedData(String),
+    /// An encrypted chunk or the data key failed to authenticate, either because the wrong
+    /// [`EncryptionKey`](crate::TantivySqliteStorageBuilder::encryption) was given or because the data
+    /// has been tampered with
+    #[error("Decryption failed")]
+    DecryptionFailed,
+    /// A chunk couldn't be encrypted
+    #[error("Encryption failed")]
+    EncryptionFailed,
+    /// A file doesn't match the checksum stored alongside it, or is shorter than it should be
+    #[error("File {} is corrupt", .0.display())]
+    CorruptFile(PathBuf),
+    /// The index stored in the database couldn't be opened by tantivy
+    #[error("Invalid index: {0}")]
+    InvalidIndex(String),
+    /// An operation which needs the index to itself found an index writer holding its lock
+    #[error("The index is locked by an index writer")]
+    IndexLocked,
+    /// The index writer lock held through this storage couldn't be renewed before its lease ran out,
+    /// so another writer may have taken it over. Writes fail until the index writer holding it is dropped
+    #[error("The index writer lock was lost")]
+    LockLost,
+    /// The database was written by a newer version of this crate, which this version can't open
+    #[error("Unsupported storage format version {found}, expected version {supported}")]
+    UnsupportedSchemaVersion {
+        /// The version recorded in the database
+        found: u32,
+        /// The version used by this version of the crate
+        supported: u32,
+    },
+    /// The database was written by an earlier version of this crate, and has to be upgraded before
+    /// this storage can open it. Upgrades are only made by a storage which creates its schema, so open
+    /// the database read-write once first. Read-only storages can open earlier versions whose
+    /// tables are already readable as they are
+    #[error("Storage format version {found} needs upgrading to version {supported}, open the database read-write once to upgrade it")]
+    SchemaNeedsMigration {
+        /// The version recorded in the database, or 0 if it predates versioning
+        found: u32,
+        /// The version used by this version of the crate
+        supported: u32,
+    },
+    /// The storage was built with a different kind of [`StorageLayout`] from the one the database
+    /// was created with
+    #[error(
+        "The database uses the {stored:?} layout, but the storage was built with {requested:?}"
+    )]
+    LayoutMismatch {
+        /// The layout given to [`TantivySqliteStorageBuilder::layout`]
+        requested: StorageLayout,
+        /// The layout recorded in the database
+        stored: StorageLayout,
+    },
+    /// An error directly from the standard library's I/O
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    /// Tried to begin a transaction while another one is still open on the same storage
+    #[error("A transaction is already in progress")]
+    TransactionInProgress,
+    /// The index was committed, but the sqlite transaction holding the commit wasn't, so the
+    /// [`TantivySqliteSync`]'s index writer no longer matches the index and has been dropped. Give it
+    /// a new one with [`TantivySqliteSync::set_index_writer`] before syncing again
+    #[error("The sync needs a new index writer")]
+    SyncNeedsIndexWriter(#[source] Option<Box<TantivySqliteStorageError>>),
+    /// An operation of `AsyncTantivySqliteStorage`, from the `tokio` feature, was cancelled by
+    /// dropping its future before it finished
+    #[error("The operation was cancelled")]
+    Cancelled,
+}
+
+/// The current time as stored in the database, in milliseconds since the unix epoch.
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as i64)
 }
 
 impl From<TantivySqliteStorageError> for std::io::Error {
     fn from(e: TantivySqliteStorageError) -> Self {
-        std::io::Error::new(std::io::ErrorKind::Other, e)
+        std::io::Error::other(e)
     }
 }
 
@@ -140,13 +329,288 @@ impl Debug for TantivySqliteStorage {
 }
 
 impl TantivySqliteStorage {
-    /// Creates a new storage.
-    pub fn new(
-        connection_pool: Pool<SqliteConnectionManager>,
+    /// Creates a new storage with the default options, using the layout the database was created
+    /// with, or the [`StorageLayout::SingleBlob`] layout for a new database. Use [`TantivySqliteStorage::builder`] to configure the storage.
+    pub fn new(connections: impl ConnectionProvider) -> Result<Self, TantivySqliteStorageError> {
+        Self::builder(connections).build()
+    }
+
+    /// Returns a builder for configuring a new storage, which gets its connections from
+    /// `connections`. This is usually an r2d2 [`r2d2::Pool`], but see [`ConnectionProvider`] for
+    /// the alternatives.
+    pub fn builder(connections: impl ConnectionProvider) -> TantivySqliteStorageBuilder {
+        TantivySqliteStorageBuilder::new(connections)
+    }
+
+    /// Creates a new storage which lays files out in the database as described by `layout`.
+    pub fn with_layout(
+        connections: impl ConnectionProvider,
+        layout: StorageLayout,
+    ) -> Result<Self, TantivySqliteStorageError> {
+        Self::builder(connections).layout(layout).build()
+    }
+
+    /// Creates a new storage for the index called `namespace`. Each namespace gets its own set of
+    /// tables, prefixed with the namespace's name, so many indexes can share the same database.
+    ///
+    /// Namespaces may only contain ascii letters and digits, and can't start with a digit.
+    pub fn with_namespace(
+        connections: impl ConnectionProvider,
+        namespace: &str,
+        layout: StorageLayout,
+    ) -> Result<Self, TantivySqliteStorageError> {
+        Self::builder(connections)
+            .namespace(namespace)
+            .layout(layout)
+            .build()
+    }
+
+    /// Opens an existing index without creating any tables. Writing or deleting files fails with
+    /// [`TantivySqliteStorageError::ReadOnly`], as does creating an index writer.
+    ///
+    /// This works with connections opened with [`rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY`] or
+    /// an immutable URI such as `file:index.sqlite?immutable=1`, so an index can be shipped inside
+    /// an application bundle. Use [`TantivySqliteStorage::builder`] with
+    /// [`TantivySqliteStorageBuilder::read_only`] to combine this with other options.
+    ///
+    /// Databases written by earlier versions of this crate are read as they are if their tables
+    /// allow it, and otherwise fail with [`TantivySqliteStorageError::SchemaNeedsMigration`] until
+    /// they have been opened read-write once.
+    pub fn open_read_only(
+        connections: impl ConnectionProvider,
+    ) -> Result<Self, TantivySqliteStorageError> {
+        Self::builder(connections).read_only(true).build()
+    }
+
+    /// Lists all the namespaces which have been created in the database with [`TantivySqliteStorage::with_namespace`].
+    /// The default index isn't included.
+    pub fn list_namespaces(
+        connections: &impl ConnectionProvider,
+    ) -> Result<Vec<String>, TantivySqliteStorageError> {
+        let conn = connections.reader()?;
+        namespace::list(&conn)
+    }
+
+    /// Deletes the index in `namespace` along with all of its tables.
+    ///
+    /// Any storage still open for this namespace will fail on every operation afterwards.
+    pub fn drop_namespace(
+        connections: &impl ConnectionProvider,
+        namespace: &str,
+    ) -> Result<(), TantivySqliteStorageError> {
+        namespace::validate(namespace)?;
+
+        let conn = connections.writer()?;
+        namespace::drop(&conn, namespace)
+    }
+
+    /// Replaces the block cache shared by every file handle opened from this storage.
+    /// See [`BlockCacheConfig`] for details. Any previously cached blocks are discarded.
+    pub fn set_block_cache(
+        &self,
+        config: BlockCacheConfig,
+    ) -> Result<(), TantivySqliteStorageError> {
+        self.inner.write().set_block_cache(config)
+    }
+
+    /// How files are laid out in the database, which is the layout recorded in the database unless
+    /// one was given when building the storage.
+    pub fn layout(&self) -> StorageLayout {
+        self.inner.read().storage_layout
+    }
+
+    /// Returns the hit and miss counts of the block cache. All zero if the cache is disabled.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.inner.read().cache_stats()
+    }
+
+    /// Fetches the name and length of every file in a single query, along with the full content
+    /// of any file smaller than `eager_load_threshold` bytes (such as `meta.json` and most `.fast`
+    /// and `.fieldnorm` files). Opening the index afterwards is then answered from memory
+    /// rather than needing a round trip to sqlite for each file. The content is checked against
+    /// its checksum as it is loaded, and corrupt files are left out, so that reading them fails
+    /// with [`TantivySqliteStorageError::CorruptFile`] as usual.
+    ///
+    /// The catalog is kept up to date with changes made through this storage. Files which aren't
+    /// in the catalog are still looked up in sqlite.
+    pub fn load_catalog(
+        &self,
+        eager_load_threshold: usize,
+    ) -> Result<(), TantivySqliteStorageError> {
+        self.inner.write().load_catalog(eager_load_threshold)
+    }
+
+    /// Starts a sqlite transaction which all of tantivy's writes through this storage go into,
+    /// so that they can be committed or rolled back along with your own changes.
+    /// See [`StorageTransaction`] for details.
+    pub fn begin_transaction(&self) -> Result<StorageTransaction, TantivySqliteStorageError> {
+        let conn = self.inner.write().begin_transaction()?;
+        Ok(StorageTransaction::new(self.clone(), conn))
+    }
+
+    /// Re-encrypts the index's data key with `new_key`, so the storage has to be opened with
+    /// `new_key` from now on. The chunks themselves don't need rewriting, so this is cheap
+    /// however large the index is.
+    #[cfg(feature = "encryption")]
+    pub fn rewrap_encryption_key(
+        &self,
+        new_key: &EncryptionKey,
+    ) -> Result<(), TantivySqliteStorageError> {
+        self.inner.read().rewrap_encryption_key(new_key)
+    }
+
+    /// Lists every file in the storage, sorted by path, along with its size, when it was written
+    /// and its checksum. The content of the files isn't read.
+    pub fn list_files(&self) -> Result<Vec<FileInfo>, TantivySqliteStorageError> {
+        let mut files: Vec<FileInfo> = self
+            .inner
+            .read()
+            .stored_files()?
+            .into_iter()
+            .map(FileEntry::info)
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(files)
+    }
+
+    /// Reads every file and compares it with the checksum stored when it was written, and checks
+    /// that every file needed by the segments in `meta.json` is present. See [`VerifyReport`] for
+    /// what is reported. Each file is read in full, so this takes a while for large indexes.
+    ///
+    /// Reading a whole file through [`tantivy::Directory::atomic_read`] checks its checksum as well,
+    /// failing with [`TantivySqliteStorageError::CorruptFile`] if it doesn't match.
+    pub fn verify(&self) -> Result<VerifyReport, TantivySqliteStorageError> {
+        verify::verify(self, &Cancellation::default())
+    }
+
+    /// Deletes the files which tantivy created but which aren't part of the last commit, such as
+    /// those left behind by an index writer which crashed. Tantivy does this itself when an index
+    /// writer commits, so this is only needed for indexes which aren't written to regularly.
+    ///
+    /// Fails with [`TantivySqliteStorageError::IndexLocked`] while an index writer is open, since the
+    /// files it is writing aren't committed yet. Files which an open file handle is still reading from
+    /// are left alone, and reported in [`GcReport::skipped_open`].
+    pub fn gc(&self, options: &GcOptions) -> Result<GcReport, TantivySqliteStorageError> {
+        gc::gc(self, options)
+    }
+
+    /// Copies the last commit into `target`, producing an index which tantivy can open from there.
+    /// Only the files needed by `meta.json` are copied, and `meta.json` itself is written last so
+    /// that the new index can't be opened until it is complete. `progress` is called as each block
+    /// of a file is copied.
+    ///
+    /// Fails with [`TantivySqliteStorageError::FileAlreadyExists`] if `target` already holds an index.
+    /// Commits made while the export is running aren't included.
+    pub fn export_to(
+        &self,
+        target: &dyn Directory,
+        mut progress: impl FnMut(ExportProgress),
+    ) -> Result<(), TantivySqliteStorageError> {
+        export::export(
+            self,
+            ExportTarget::Directory(target),
+            &mut progress,
+            &Cancellation::default(),
+        )
+    }
+
+    /// Like [`TantivySqliteStorage::export_to`], but writes the files into the directory at `path`,
+    /// creating it if needed. The result can be opened with tantivy's `MmapDirectory`.
+    pub fn export_to_path(
+        &self,
+        path: impl AsRef<Path>,
+        mut progress: impl FnMut(ExportProgress),
+    ) -> Result<(), TantivySqliteStorageError> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path)?;
+
+        export::export(
+            self,
+            ExportTarget::Path(path),
+            &mut progress,
+            &Cancellation::default(),
+        )
+    }
+
+    /// Copies the index in `source` into this storage, for example to move an index built with tantivy's
+    /// `MmapDirectory` into sqlite. Only the files needed by `source`'s `meta.json` are copied.
+    ///
+    /// Everything is written in a single transaction with `meta.json` last, so the import either
+    /// happens completely or not at all. Fails with [`TantivySqliteStorageError::FileAlreadyExists`]
+    /// if the storage already holds an index, unless [`ImportOptions::replace`] is set, and with
+    /// [`TantivySqliteStorageError::IndexLocked`] while an index writer is open on this storage.
+    /// Nothing should be writing to `source` during the import.
+    pub fn import_from(
+        &self,
+        source: &dyn Directory,
+        options: &ImportOptions,
+    ) -> Result<(), TantivySqliteStorageError> {
+        import::import(
+            self,
+            ImportSource::Directory(source),
+            options,
+            &Cancellation::default(),
+        )
+    }
+
+    /// Like [`TantivySqliteStorage::import_from`], but reads the index from the directory at `path`,
+    /// such as one written by tantivy's `MmapDirectory`.
+    pub fn import_from_path(
+        &self,
+        path: impl AsRef<Path>,
+        options: &ImportOptions,
+    ) -> Result<(), TantivySqliteStorageError> {
+        import::import(
+            self,
+            ImportSource::Path(path.as_ref()),
+            options,
+            &Cancellation::default(),
+        )
+    }
+
+    /// Copies the whole database into a new sqlite file at `path`, replacing anything already there,
+    /// using sqlite's online backup API. This includes any other namespaces and the application's
+    /// own tables, so the backup can be restored with [`TantivySqliteStorage::restore_from`] or
+    /// opened directly. `progress` is called after each batch of pages is copied.
+    ///
+    /// The backup is a single consistent snapshot of the database, so it never contains half of a
+    /// commit. Readers carry on as normal while it runs, since a file database is read through a
+    /// connection of its own rather than the provider's. In WAL mode so do writers, but with a
+    /// rollback journal, writes wait until the backup is finished.
+    pub fn backup_to(
+        &self,
+        path: impl AsRef<Path>,
+        mut progress: impl FnMut(BackupProgress),
+    ) -> Result<(), TantivySqliteStorageError> {
+        backup::backup(self, path.as_ref(), &mut progress, &Cancellation::default())
+    }
+
+    /// Replaces the whole database with the backup at `path`, written by [`TantivySqliteStorage::backup_to`],
+    /// then fires the watch callbacks so that readers reload the restored index. `progress` is called
+    /// after each batch of pages is copied.
+    ///
+    /// Fails with [`TantivySqliteStorageError::IndexLocked`] while an index writer is open. Other
+    /// connections to the database shouldn't be used until the restore has finished. With the
+    /// `encryption` feature, the backup's data key must be wrapped with the storage's current key, so
+    /// a backup taken before [`TantivySqliteStorage::rewrap_encryption_key`] fails with
+    /// [`TantivySqliteStorageError::DecryptionFailed`] without anything being replaced.
+    pub fn restore_from(
+        &self,
+        path: impl AsRef<Path>,
+        mut progress: impl FnMut(BackupProgress),
+    ) -> Result<(), TantivySqliteStorageError> {
+        backup::restore(self, path.as_ref(), &mut progress, &Cancellation::default())
+    }
+
+    fn from_settings(
+        connections: Arc<dyn ConnectionProvider>,
+        settings: StorageSettings,
     ) -> Result<Self, TantivySqliteStorageError> {
         Ok(Self {
             inner: Arc::new(RwLock::new(TantivySqliteStorageInner::new(
-                connection_pool,
+                connections,
+                settings,
             )?)),
         })
     }
@@ -154,12 +618,18 @@ impl TantivySqliteStorage {
 
 impl Directory for TantivySqliteStorage {
     fn get_file_handle(&self, path: &Path) -> Result<Box<dyn FileHandle>, error::OpenReadError> {
-        let handle_data = self
-            .inner
-            .read()
+        let inner = self.inner.read();
+
+        if let Some(content) = inner.catalog_content(path) {
+            return Ok(Box::new(content));
+        }
+
+        let handle_data = inner
             .read_handle(path)
             .map_err(|e| e.into_open_read_error(path))?;
         let handle = ReadHandle {
+            _open: inner.open_files.open(handle_data.file_id),
+            path: path.to_path_buf(),
             data: handle_data,
             conn: self.inner.clone(),
         };
@@ -207,30 +677,89 @@ impl Directory for TantivySqliteStorage {
         self.inner
             .write()
             .atomic_write(path, data)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+            .map_err(std::io::Error::other)
     }
 
     fn sync_directory(&self) -> std::io::Result<()> {
         Ok(())
     }
 
+    fn acquire_lock(&self, lock: &Lock) -> Result<DirectoryLock, error::LockError> {
+        lock::acquire(self, lock)
+    }
+
     fn watch(&self, watch_callback: WatchCallback) -> tantivy::Result<WatchHandle> {
-        Ok(self.inner.read().watch(watch_callback))
+        let mut inner = self.inner.write();
+        inner.start_watcher(Arc::downgrade(&self.inner))?;
+
+        Ok(inner.watch(watch_callback))
     }
 }
 
 struct TantivySqliteStorageInner {
-    connection_pool: Pool<SqliteConnectionManager>,
+    connections: Arc<dyn ConnectionProvider>,
+    connection_settings: ConnectionSettings,
+    namespace: Option<String>,
+    table_prefix: String,
+    read_only: bool,
+    create_schema: bool,
+    tables: TableNames,
+    lock_lease: Duration,
+    storage_layout: StorageLayout,
+    layout: Box<dyn FileLayout>,
+    block_cache: Option<BlockCache>,
+    catalog: Option<Catalog>,
     watch_callback_list: WatchCallbackList,
+    watch_interval: Option<Duration>,
+    watcher: Option<Watcher>,
+    /// The last committed `meta.json`, used to tell whether the watcher has found a commit made elsewhere.
+    last_meta: Option<MetaVersion>,
+    transaction: Option<SharedConnection>,
+    /// The `meta.json` written during the current transaction, so watchers can be notified once it commits.
+    pending_meta: Option<MetaVersion>,
+    open_files: OpenFiles,
+    /// The leases of the locks held through this storage.
+    leases: Vec<Arc<Lease>>,
+    /// Leases released while a transaction was open, to be released once it finishes.
+    pending_releases: Vec<Arc<Lease>>,
 }
 
 impl TantivySqliteStorageInner {
     fn new(
-        connection_pool: Pool<SqliteConnectionManager>,
+        connections: Arc<dyn ConnectionProvider>,
+        settings: StorageSettings,
     ) -> Result<Self, TantivySqliteStorageError> {
+        let tables = TableNames::new(&settings.table_prefix, settings.namespace.as_deref());
+
+        let stored_layout = {
+            let mut conn = connections.reader()?;
+            conn.configure(&settings.connection)?;
+            migrations::stored_layout(&conn, &tables)?
+        };
+        let storage_layout = StorageLayout::resolve(settings.layout, stored_layout)?;
+
         let ret = Self {
-            connection_pool,
+            connections,
+            connection_settings: settings.connection,
+            namespace: settings.namespace,
+            table_prefix: settings.table_prefix,
+            read_only: settings.read_only,
+            create_schema: settings.create_schema,
+            tables: tables.clone(),
+            lock_lease: settings.lock_lease,
+            storage_layout,
+            layout: storage_layout.build(tables, settings.compression, settings.encryption)?,
+            block_cache: None,
+            catalog: None,
             watch_callback_list: Default::default(),
+            watch_interval: settings.watch_interval,
+            watcher: None,
+            last_meta: None,
+            transaction: None,
+            pending_meta: None,
+            open_files: OpenFiles::default(),
+            leases: Vec::new(),
+            pending_releases: Vec::new(),
         };
 
         ret.init()?;
@@ -241,251 +770,794 @@ impl TantivySqliteStorageInner {
         self.watch_callback_list.subscribe(watch_callback)
     }
 
-    fn exists(&self, path: &Path) -> Result<bool, TantivySqliteStorageError> {
-        let conn = self.connection_pool.get()?;
+    /// Starts polling for commits made elsewhere, unless it is disabled or already running.
+    fn start_watcher(&mut self, inner: Weak<RwLock<Self>>) -> std::io::Result<()> {
+        let Some(interval) = self.watch_interval else {
+            return Ok(());
+        };
 
-        let exists: Option<i32> = conn
-            .query_row(
-                "SELECT 1 FROM tantivy_blobs WHERE filename = ?",
-                [path.as_os_str().as_bytes()],
-                |row| row.get(0),
-            )
-            .optional()?;
+        if self.watcher.is_none() {
+            // A transaction may be holding the only connection, in which case the watcher's first poll
+            // after it finishes catches up instead
+            if self.transaction.is_none() {
+                self.last_meta = self.committed_meta()?;
+            }
+            self.watcher = Some(Watcher::start(inner, interval)?);
+        }
 
-        Ok(exists.is_some())
+        Ok(())
     }
 
-    fn delete(&mut self, path: &Path) -> Result<(), TantivySqliteStorageError> {
-        let conn = self.connection_pool.get()?;
+    /// Fires the watch callbacks if `meta.json` has been changed by something other than this storage.
+    fn poll_external_changes(inner: &RwLock<Self>) -> Result<(), TantivySqliteStorageError> {
+        {
+            let inner = inner.read();
+            // Nothing else can commit until the transaction finishes, and it may be holding the only
+            // connection, which it can't give back while this holds the lock
+            if inner.transaction.is_some() || inner.committed_meta()? == inner.last_meta {
+                return Ok(());
+            }
+        }
 
-        let num_deleted = conn.execute(
-            "DELETE FROM tantivy_blobs WHERE filename = ?",
-            [path.as_os_str().as_bytes()],
-        )?;
+        let mut inner = inner.write();
+        if inner.transaction.is_some() {
+            return Ok(());
+        }
 
-        if num_deleted == 0 {
-            return Err(TantivySqliteStorageError::FileDoesNotExist(
-                path.to_path_buf(),
-            ));
+        // Check again in case this storage wrote it while the lock was released
+        let meta = inner.committed_meta()?;
+        if meta == inner.last_meta {
+            return Ok(());
         }
 
-        Ok(())
+        inner.reload(meta)
     }
 
-    fn create_empty_file(&mut self, path: &Path) -> Result<(), TantivySqliteStorageError> {
-        let conn = self.connection_pool.get()?;
-
-        let num_rows_modified = conn.execute(
-            "INSERT OR IGNORE INTO tantivy_blobs VALUES (?, ?)",
-            [path.as_os_str().as_bytes(), b""],
-        )?;
+    /// Discards everything cached from before the database was changed by something other than
+    /// this storage, and fires the watch callbacks so that readers pick up `meta`.
+    fn reload(&mut self, meta: Option<MetaVersion>) -> Result<(), TantivySqliteStorageError> {
+        self.last_meta = meta;
 
-        if num_rows_modified != 1 {
-            Err(TantivySqliteStorageError::FileAlreadyExists(
-                path.to_path_buf(),
-            ))
-        } else {
-            Ok(())
+        // Files may have been deleted and recreated by whoever made the change
+        if let Some(block_cache) = &self.block_cache {
+            block_cache.clear();
         }
+        if let Some(eager_load_threshold) = self.catalog.as_ref().map(Catalog::eager_load_threshold)
+        {
+            self.load_catalog(eager_load_threshold)?;
+        }
+
+        self.watch_callback_list.broadcast();
+        Ok(())
     }
 
-    fn atomic_write(&mut self, path: &Path, data: &[u8]) -> Result<(), TantivySqliteStorageError> {
-        let conn = self.connection_pool.get()?;
+    /// The version of the committed `meta.json`, which is polled often so is taken from the length and
+    /// checksum stored alongside it rather than its content.
+    fn committed_meta(&self) -> Result<Option<MetaVersion>, TantivySqliteStorageError> {
+        // Not the connection of a transaction in progress, since nothing else can see its changes yet
+        let conn = self.reader()?;
+        let path = Path::new(META_FILE);
 
-        conn.execute(
-            "INSERT OR REPLACE INTO tantivy_blobs VALUES (?, ?)",
-            [path.as_os_str().as_bytes(), data],
-        )?;
+        match self.layout.read_handle(&conn, path) {
+            Ok(ReadHandleData {
+                length,
+                checksum: Some(checksum),
+                ..
+            }) => Ok(Some(MetaVersion { length, checksum })),
+            // Encrypted, or written by a version of this crate which didn't store checksums
+            Ok(_) => Ok(Some(MetaVersion::of(
+                &self.layout.atomic_read(&conn, path)?,
+            ))),
+            Err(TantivySqliteStorageError::FileDoesNotExist(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
 
-        if path == Path::new("meta.json") {
-            self.watch_callback_list.broadcast();
+    fn exists(&self, path: &Path) -> Result<bool, TantivySqliteStorageError> {
+        if self.catalog_entry(path).is_some() {
+            return Ok(true);
         }
 
-        Ok(())
+        let conn = self.connection()?;
+        self.layout.exists(&conn, path)
     }
 
-    fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, TantivySqliteStorageError> {
-        let conn = self.connection_pool.get()?;
+    fn delete(&mut self, path: &Path) -> Result<(), TantivySqliteStorageError> {
+        self.check_writable()?;
+        let conn = self.write_connection()?;
 
-        let content = conn
-            .query_row(
-                "SELECT content FROM tantivy_blobs WHERE filename = ?",
-                [path.as_os_str().as_bytes()],
-                |row| row.get(0),
-            )
-            .optional()?;
+        self.invalidate_cache(&conn, path)?;
+        if let Some(catalog) = &mut self.catalog {
+            catalog.remove(path);
+        }
 
-        content.ok_or_else(|| TantivySqliteStorageError::FileDoesNotExist(path.to_path_buf()))
+        self.layout.delete(&conn, path)
     }
 
-    fn read_handle(&self, path: &Path) -> Result<ReadHandleData, TantivySqliteStorageError> {
-        let conn = self.connection_pool.get()?;
-
-        let handle_data = conn
-            .query_row(
-                "SELECT rowid, length(content) FROM tantivy_blobs WHERE filename = ?",
-                [path.as_os_str().as_bytes()],
-                |row| Ok((row.get(0)?, row.get(1)?)),
-            )
-            .optional()?;
+    /// Deletes the file unless a file handle is reading from it. Returns whether it was deleted.
+    fn delete_unless_open(
+        &mut self,
+        path: &Path,
+        file_id: i64,
+    ) -> Result<bool, TantivySqliteStorageError> {
+        // New handles are only opened with the read lock held, so none can appear until this returns
+        if self.open_files.is_open(file_id) {
+            return Ok(false);
+        }
 
-        handle_data
-            .map(|(rowid, length)| ReadHandleData { rowid, length })
-            .ok_or_else(|| TantivySqliteStorageError::FileDoesNotExist(path.to_path_buf()))
+        self.delete(path)?;
+        Ok(true)
     }
 
-    fn read_bytes(
-        &self,
-        rowid: i64,
-        range: Range<usize>,
-    ) -> Result<OwnedBytes, TantivySqliteStorageError> {
-        let conn = self.connection_pool.get()?;
+    /// Returns the number of pages which were freed.
+    fn incremental_vacuum(&self) -> Result<u64, TantivySqliteStorageError> {
+        let conn = self.write_connection()?;
 
-        let blob = conn.blob_open(DatabaseName::Main, "tantivy_blobs", "content", rowid, true)?;
+        let freelist_count = |conn: &rusqlite::Connection| -> rusqlite::Result<u64> {
+            conn.query_row("PRAGMA freelist_count", [], |row| row.get(0))
+        };
 
-        let mut buf = vec![0; range.len()];
+        let before = freelist_count(&conn)?;
+        conn.execute_batch("PRAGMA incremental_vacuum")?;
+        let after = freelist_count(&conn)?;
 
-        blob.read_at_exact(&mut buf, range.start)?;
-        Ok(OwnedBytes::new(buf))
+        Ok(before.saturating_sub(after))
     }
 
-    fn init(&self) -> Result<(), TantivySqliteStorageError> {
-        let conn = self.connection_pool.get()?;
+    fn create_empty_file(&mut self, path: &Path) -> Result<(), TantivySqliteStorageError> {
+        self.check_writable()?;
+        let conn = self.write_connection()?;
 
-        conn.execute("CREATE TABLE IF NOT EXISTS tantivy_blobs (filename TEXT UNIQUE NOT NULL, content BLOB NOT NULL)", [])?;
-        Ok(())
+        self.layout.create_empty_file(&conn, path)?;
+        self.refresh_catalog(&conn, path)
     }
-}
-
-struct ReadHandleData {
-    rowid: i64,
-    length: usize,
-}
-
-struct ReadHandle {
-    data: ReadHandleData,
-    conn: Arc<RwLock<TantivySqliteStorageInner>>,
-}
 
-impl std::fmt::Debug for ReadHandle {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "ReadHandle({})", self.data.rowid)
+    fn part_size(&self) -> usize {
+        self.layout.part_size()
     }
-}
 
-impl HasLen for ReadHandle {
-    fn len(&self) -> usize {
-        self.data.length
+    fn write_part(
+        &self,
+        path: &Path,
+        part: i64,
+        data: &[u8],
+    ) -> Result<(), TantivySqliteStorageError> {
+        self.check_writable()?;
+        let conn = self.write_connection()?;
+        self.layout.write_part(&conn, path, part, data)
     }
-}
 
-impl FileHandle for ReadHandle {
-    fn read_bytes(&self, range: Range<usize>) -> std::io::Result<OwnedBytes> {
-        Ok(self.conn.read().read_bytes(self.data.rowid, range)?)
+    fn finish_parts(
+        &mut self,
+        path: &Path,
+        length: usize,
+        checksum: u32,
+        finished: bool,
+    ) -> Result<(), TantivySqliteStorageError> {
+        self.check_writable()?;
+        let conn = self.write_connection()?;
+
+        self.invalidate_cache(&conn, path)?;
+        self.layout
+            .finish_parts(&conn, path, length, checksum, finished)?;
+        self.refresh_catalog(&conn, path)
     }
-}
 
-struct TantivySqliteStorageWritePtr {
-    data: Cursor<Vec<u8>>,
-    path: PathBuf,
-    storage: TantivySqliteStorage,
-}
+    fn atomic_write(&mut self, path: &Path, data: &[u8]) -> Result<(), TantivySqliteStorageError> {
+        self.check_writable()?;
+        let conn = self.write_connection()?;
 
-impl TantivySqliteStorageWritePtr {
-    fn new(path: &Path, storage: TantivySqliteStorage) -> Self {
-        Self {
-            data: Cursor::new(Vec::new()),
-            path: path.to_path_buf(),
-            storage,
+        self.invalidate_cache(&conn, path)?;
+        self.layout.atomic_write(&conn, path, data)?;
+        self.refresh_catalog(&conn, path)?;
+
+        if path == Path::new(META_FILE) {
+            if self.transaction.is_some() {
+                self.pending_meta = Some(MetaVersion::of(data));
+            } else {
+                self.last_meta = Some(MetaVersion::of(data));
+                self.watch_callback_list.broadcast();
+            }
         }
-    }
-}
 
-impl Write for TantivySqliteStorageWritePtr {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.data.write(buf)
+        Ok(())
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.storage.atomic_write(&self.path, self.data.get_ref())
-    }
-}
+    /// Takes the lock if it is free, and keeps track of its lease until it is released.
+    ///
+    /// Locks are kept out of transactions, so that other processes see them straight away and a
+    /// rollback can't undo them. While a transaction is open it holds the database's write lock,
+    /// so nobody else can take or change a lock until it finishes. The leases are only written
+    /// once it has, by [`TantivySqliteStorageInner::write_leases`].
+    fn try_acquire_lock(
+        &mut self,
+        name: &Path,
+        owner: &str,
+        lease: Duration,
+    ) -> Result<Option<Arc<Lease>>, TantivySqliteStorageError> {
+        let taken_at = Instant::now();
+        let acquired = match &self.transaction {
+            // Nothing is written until the transaction finishes, so this counts the locks taken
+            // and released by the storage since it began as well as those in the table
+            Some(conn) => {
+                let holder = lock::holder(&conn.lock(), &self.tables.locks, name)?;
 
-impl TerminatingWrite for TantivySqliteStorageWritePtr {
-    fn terminate_ref(&mut self, _: tantivy::directory::AntiCallToken) -> std::io::Result<()> {
-        self.flush()
+                !self.leases.iter().any(|held| held.name == name)
+                    && holder.is_none_or(|owner| {
+                        self.pending_releases
+                            .iter()
+                            .any(|released| released.owner == owner)
+                    })
+            }
+            None => lock::try_acquire(&*self.writer()?, &self.tables.locks, name, owner, lease)?,
+        };
+        if !acquired {
+            return Ok(None);
+        }
+
+        let lease = Arc::new(Lease::new(
+            name.to_path_buf(),
+            owner.to_string(),
+            lease,
+            taken_at,
+        ));
+        self.leases.push(lease.clone());
+        Ok(Some(lease))
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use uuid::Uuid;
+    fn renew_lock(&self, lease: &Lease) -> Result<bool, TantivySqliteStorageError> {
+        let renewed_at = Instant::now();
+        let renewed = self.transaction.is_some()
+            || lock::renew(
+                &*self.writer()?,
+                &self.tables.locks,
+                &lease.name,
+                &lease.owner,
+                lease.duration,
+            )?;
+        if renewed {
+            lease.renewed(renewed_at);
+        }
 
-    fn create_in_memory_database_string() -> String {
-        format!("file:{}?mode=memory&cache=shared", Uuid::new_v4())
+        Ok(renewed)
     }
 
-    fn in_memory_connection_manager() -> SqliteConnectionManager {
-        // see https://github.com/ivanceras/r2d2-sqlite/pull/45
-        SqliteConnectionManager::file(create_in_memory_database_string())
+    fn release_lock(&mut self, lease: &Arc<Lease>) -> Result<(), TantivySqliteStorageError> {
+        self.leases.retain(|held| !Arc::ptr_eq(held, lease));
+
+        if self.transaction.is_some() {
+            self.pending_releases.push(lease.clone());
+            return Ok(());
+        }
+
+        lock::release(
+            &*self.writer()?,
+            &self.tables.locks,
+            &lease.name,
+            &lease.owner,
+        )
     }
 
-    #[test]
-    fn can_successfully_init() -> Result<(), Box<dyn std::error::Error>> {
-        let manager = in_memory_connection_manager();
+    /// Writes out the leases which were taken, renewed or released while they couldn't be written,
+    /// such as while a transaction was open or a restore was copying the database. A lease which has
+    /// been taken over in the meantime is lost.
+    fn write_leases(
+        &mut self,
+        conn: &rusqlite::Connection,
+    ) -> Result<(), TantivySqliteStorageError> {
+        for lease in self.pending_releases.drain(..) {
+            lock::release(conn, &self.tables.locks, &lease.name, &lease.owner)?;
+        }
 
-        let pool = Pool::builder().max_size(4).build(manager)?;
+        for lease in &self.leases {
+            if lease.is_lost() {
+                continue;
+            }
 
-        let _storage = TantivySqliteStorage::new(pool)?;
+            let renewed_at = Instant::now();
+            if lock::try_acquire(
+                conn,
+                &self.tables.locks,
+                &lease.name,
+                &lease.owner,
+                lease.duration,
+            )? {
+                lease.renewed(renewed_at);
+            } else {
+                lease.set_lost();
+            }
+        }
 
         Ok(())
     }
 
-    #[test]
-    fn can_read_and_write() -> Result<(), Box<dyn std::error::Error>> {
-        let manager = in_memory_connection_manager();
-        let pool = Pool::builder().max_size(4).build(manager)?;
+    #[cfg(feature = "encryption")]
+    fn rewrap_encryption_key(
+        &self,
+        new_key: &EncryptionKey,
+    ) -> Result<(), TantivySqliteStorageError> {
+        self.check_writable()?;
 
-        let storage = TantivySqliteStorage::new(pool)?;
+        let encryption = self.layout.encryption().ok_or_else(|| {
+            TantivySqliteStorageError::InvalidConfiguration("the storage isn't encrypted".into())
+        })?;
 
-        let data = b"hello, world!";
-        let path = Path::new("some/file/path.txt");
-        storage.atomic_write(path, data)?;
+        let conn = self.write_connection()?;
+        encryption.rewrap(&conn, new_key)
+    }
 
-        let content = storage.atomic_read(path)?;
+    fn begin_transaction(&mut self) -> Result<SharedConnection, TantivySqliteStorageError> {
+        if self.transaction.is_some() {
+            return Err(TantivySqliteStorageError::TransactionInProgress);
+        }
 
-        assert_eq!(content, data);
+        let conn = if self.read_only {
+            self.reader()?
+        } else {
+            self.writer()?
+        };
+        // Take the write lock straight away so that tantivy's writes can't fail part way through
+        conn.execute_batch(if self.read_only {
+            "BEGIN"
+        } else {
+            "BEGIN IMMEDIATE"
+        })?;
 
-        Ok(())
+        let conn = Arc::new(Mutex::new(conn));
+        self.transaction = Some(conn.clone());
+
+        Ok(conn)
     }
 
-    #[test]
-    fn returns_error_if_file_does_not_exist() -> Result<(), Box<dyn std::error::Error>> {
-        let manager = in_memory_connection_manager();
-        let pool = Pool::builder().max_size(4).build(manager)?;
+    fn finish_transaction(&mut self, commit: bool) -> Result<(), TantivySqliteStorageError> {
+        let Some(conn) = self.transaction.take() else {
+            return Ok(());
+        };
+        let conn = conn.lock();
 
-        let storage = TantivySqliteStorage::new(pool)?;
+        let result = if commit {
+            conn.execute_batch("COMMIT")
+        } else {
+            conn.execute_batch("ROLLBACK")
+        };
 
-        let path = Path::new("some/file/path.txt");
-        let error = storage.atomic_read(path).unwrap_err();
+        // A failed commit can leave the transaction open, and it mustn't be given back like that
+        let rolled_back = !commit || result.is_err();
+        if !conn.is_autocommit() {
+            conn.execute_batch("ROLLBACK")?;
+        }
 
-        assert!(matches!(error, error::OpenReadError::FileDoesNotExist(_)));
+        // If this fails, the heartbeats can't renew the leases either, and they are lost
+        let _ = self.write_leases(&conn);
+        drop(conn);
 
-        Ok(())
-    }
+        if rolled_back {
+            self.pending_meta = None;
 
-    #[test]
-    fn error_to_delete_empty_files() -> Result<(), Box<dyn std::error::Error>> {
-        let manager = in_memory_connection_manager();
-        let pool = Pool::builder().max_size(4).build(manager)?;
+            // Anything cached during the transaction may no longer exist
+            if let Some(block_cache) = &self.block_cache {
+                block_cache.clear();
+            }
+            if let Some(eager_load_threshold) =
+                self.catalog.as_ref().map(Catalog::eager_load_threshold)
+            {
+                self.load_catalog(eager_load_threshold)?;
+            }
+        } else if let Some(meta) = self.pending_meta.take() {
+            self.last_meta = Some(meta);
+            self.watch_callback_list.broadcast();
+        }
 
-        let storage = TantivySqliteStorage::new(pool)?;
+        Ok(result?)
+    }
 
-        let path = Path::new("some/file/path.txt");
-        let error = storage.delete(path).unwrap_err();
+    fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, TantivySqliteStorageError> {
+        if let Some(content) = self.catalog_content(path) {
+            return Ok(content.to_vec());
+        }
 
-        assert!(matches!(error, error::DeleteError::FileDoesNotExist(_)));
+        let conn = self.connection()?;
+        self.layout.atomic_read(&conn, path)
+    }
+
+    /// Every file in the database, ignoring the catalog.
+    fn stored_files(&self) -> Result<Vec<FileEntry>, TantivySqliteStorageError> {
+        let conn = self.connection()?;
+        self.layout.list_files(&conn, 0)
+    }
+
+    /// Checks a file in the database against its checksum, ignoring the catalog.
+    fn check_stored_file(&self, path: &Path) -> Result<(), TantivySqliteStorageError> {
+        let conn = self.connection()?;
+        self.layout.check_file(&conn, path)
+    }
+
+    fn read_handle(&self, path: &Path) -> Result<ReadHandleData, TantivySqliteStorageError> {
+        if let Some(entry) = self.catalog_entry(path) {
+            return Ok(entry.handle);
+        }
+
+        let conn = self.connection()?;
+        self.layout.read_handle(&conn, path)
+    }
+
+    fn read_bytes(
+        &self,
+        handle: &ReadHandleData,
+        range: Range<usize>,
+    ) -> Result<OwnedBytes, TantivySqliteStorageError> {
+        let Some(block_cache) = &self.block_cache else {
+            let conn = self.connection()?;
+            return self.layout.read_bytes(&conn, handle, range);
+        };
+
+        // Only fetch a connection if some of the blocks aren't already cached
+        let mut conn = None;
+        block_cache.read_bytes(handle, range, |block_range| {
+            let conn = match &mut conn {
+                Some(conn) => conn,
+                None => conn.insert(self.connection()?),
+            };
+
+            self.layout.read_bytes(conn, handle, block_range)
+        })
+    }
+
+    fn set_block_cache(
+        &mut self,
+        config: BlockCacheConfig,
+    ) -> Result<(), TantivySqliteStorageError> {
+        self.block_cache = BlockCache::new(config)?;
+        Ok(())
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.block_cache
+            .as_ref()
+            .map(BlockCache::stats)
+            .unwrap_or_default()
+    }
+
+    fn load_catalog(
+        &mut self,
+        eager_load_threshold: usize,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let conn = self.connection()?;
+
+        let files = self.layout.list_files(&conn, eager_load_threshold)?;
+        self.catalog = Some(Catalog::new(eager_load_threshold, files));
+
+        Ok(())
+    }
+
+    fn catalog_entry(&self, path: &Path) -> Option<&CatalogEntry> {
+        self.catalog.as_ref()?.get(path)
+    }
+
+    fn catalog_content(&self, path: &Path) -> Option<OwnedBytes> {
+        self.catalog_entry(path)?.content.clone()
+    }
+
+    /// Updates the catalog's entry for `path` after it has been written to.
+    fn refresh_catalog(
+        &mut self,
+        conn: &rusqlite::Connection,
+        path: &Path,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let Some(catalog) = &mut self.catalog else {
+            return Ok(());
+        };
+
+        let handle = self.layout.read_handle(conn, path)?;
+        let content = if handle.length < catalog.eager_load_threshold() {
+            Some(self.layout.atomic_read(conn, path)?)
+        } else {
+            None
+        };
+
+        catalog.insert(path, handle, content);
+        Ok(())
+    }
+
+    /// Evicts the blocks for `path` from the cache before its content is changed.
+    fn invalidate_cache(
+        &self,
+        conn: &rusqlite::Connection,
+        path: &Path,
+    ) -> Result<(), TantivySqliteStorageError> {
+        let Some(block_cache) = &self.block_cache else {
+            return Ok(());
+        };
+
+        match self.layout.read_handle(conn, path) {
+            Ok(handle) => {
+                block_cache.invalidate(handle.file_id);
+                Ok(())
+            }
+            Err(TantivySqliteStorageError::FileDoesNotExist(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The connection of the transaction in progress if there is one, otherwise a reader.
+    fn connection(&self) -> Result<StorageConnection, TantivySqliteStorageError> {
+        Ok(match &self.transaction {
+            Some(conn) => StorageConnection::Transaction(conn.lock_arc()),
+            None => StorageConnection::Provided(self.reader()?),
+        })
+    }
+
+    /// The connection of the transaction in progress if there is one, otherwise a writer.
+    fn write_connection(&self) -> Result<StorageConnection, TantivySqliteStorageError> {
+        Ok(match &self.transaction {
+            Some(conn) => StorageConnection::Transaction(conn.lock_arc()),
+            None => StorageConnection::Provided(self.writer()?),
+        })
+    }
+
+    fn reader(&self) -> Result<ProvidedConnection, TantivySqliteStorageError> {
+        let mut conn = self.connections.reader()?;
+        conn.configure(&self.connection_settings)?;
+
+        Ok(conn)
+    }
+
+    fn writer(&self) -> Result<ProvidedConnection, TantivySqliteStorageError> {
+        let mut conn = self.connections.writer()?;
+        conn.configure(&self.connection_settings)?;
+
+        Ok(conn)
+    }
+
+    fn check_writable(&self) -> Result<(), TantivySqliteStorageError> {
+        if self.read_only {
+            Err(TantivySqliteStorageError::ReadOnly)
+        } else if self
+            .leases
+            .iter()
+            .any(|lease| lease.name == INDEX_WRITER_LOCK.filepath && lease.is_lost())
+        {
+            Err(TantivySqliteStorageError::LockLost)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn init(&self) -> Result<(), TantivySqliteStorageError> {
+        let conn = if self.read_only {
+            self.connection()?
+        } else {
+            self.write_connection()?
+        };
+
+        if !self.create_schema {
+            let mut required_tables = self.layout.required_tables();
+            if !self.read_only {
+                required_tables.push(&self.tables.locks);
+            }
+
+            for table in required_tables {
+                if !migrations::table_exists(&conn, table)? {
+                    return Err(TantivySqliteStorageError::SchemaDoesNotExist(
+                        table.to_string(),
+                    ));
+                }
+            }
+
+            migrations::check(
+                &conn,
+                &self.tables,
+                self.read_only,
+                &self.layout.read_columns(),
+            )?;
+            return self.layout.open(&conn, self.read_only);
+        }
+
+        migrations::run(&conn, &self.tables)?;
+        migrations::record_layout(&conn, &self.tables, self.storage_layout)?;
+
+        if let Some(namespace) = &self.namespace {
+            namespace::register(&conn, namespace, &self.table_prefix)?;
+        }
+
+        lock::init(&conn, &self.tables.locks)?;
+        self.layout.init(&conn)?;
+        self.layout.open(&conn, self.read_only)
+    }
+}
+
+struct ReadHandle {
+    path: PathBuf,
+    data: ReadHandleData,
+    conn: Arc<RwLock<TantivySqliteStorageInner>>,
+    /// Stops [`TantivySqliteStorage::gc`] deleting the file while it is being read.
+    _open: OpenFile,
+}
+
+impl std::fmt::Debug for ReadHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ReadHandle({})", self.data.file_id)
+    }
+}
+
+impl HasLen for ReadHandle {
+    fn len(&self) -> usize {
+        self.data.length
+    }
+}
+
+impl FileHandle for ReadHandle {
+    fn read_bytes(&self, range: Range<usize>) -> std::io::Result<OwnedBytes> {
+        let in_bounds = range.end <= self.data.length;
+
+        match self.conn.read().read_bytes(&self.data, range) {
+            // The layouts come up short when a chunk or part of the file is missing
+            Err(TantivySqliteStorageError::Sqlite(rusqlite::Error::BlobSizeError)) if in_bounds => {
+                Err(TantivySqliteStorageError::CorruptFile(self.path.clone()).into())
+            }
+            result => Ok(result.map_err(layout::mark_corrupt(&self.path))?),
+        }
+    }
+}
+
+/// Streams data into sqlite in parts of the layout's part size, so that writing
+/// a large segment file doesn't require holding the entire file in memory.
+struct TantivySqliteStorageWritePtr {
+    buffer: Vec<u8>,
+    part_size: usize,
+    next_part: i64,
+    /// The CRC32C of everything written so far.
+    checksum: u32,
+    path: PathBuf,
+    storage: TantivySqliteStorage,
+}
+
+impl TantivySqliteStorageWritePtr {
+    fn new(path: &Path, storage: TantivySqliteStorage) -> Self {
+        let part_size = storage.inner.read().part_size();
+
+        Self {
+            buffer: Vec::with_capacity(part_size),
+            part_size,
+            next_part: 0,
+            checksum: crc32c::crc32c(b""),
+            path: path.to_path_buf(),
+            storage,
+        }
+    }
+
+    /// Sends the buffered data to sqlite. Unless the buffer contains a full part, it is kept
+    /// around so that the same part can be rewritten once more data arrives.
+    fn write_buffered_part(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        self.storage
+            .inner
+            .read()
+            .write_part(&self.path, self.next_part, &self.buffer)?;
+
+        if self.buffer.len() == self.part_size {
+            self.next_part += 1;
+            self.buffer.clear();
+        }
+
+        Ok(())
+    }
+
+    /// The number of bytes written so far.
+    fn len(&self) -> usize {
+        self.next_part as usize * self.part_size + self.buffer.len()
+    }
+
+    fn finish(&mut self, finished: bool) -> std::io::Result<()> {
+        self.write_buffered_part()?;
+
+        Ok(self.storage.inner.write().finish_parts(
+            &self.path,
+            self.len(),
+            self.checksum,
+            finished,
+        )?)
+    }
+}
+
+impl Write for TantivySqliteStorageWritePtr {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.part_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        self.checksum = crc32c::crc32c_append(self.checksum, &buf[..len]);
+
+        if self.buffer.len() == self.part_size {
+            self.write_buffered_part()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.finish(false)
+    }
+}
+
+impl TerminatingWrite for TantivySqliteStorageWritePtr {
+    fn terminate_ref(&mut self, _: tantivy::directory::AntiCallToken) -> std::io::Result<()> {
+        self.finish(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use r2d2::Pool;
+    use r2d2_sqlite::SqliteConnectionManager;
+    use uuid::Uuid;
+
+    fn create_in_memory_database_string() -> String {
+        format!("file:{}?mode=memory&cache=shared", Uuid::new_v4())
+    }
+
+    fn in_memory_connection_manager() -> SqliteConnectionManager {
+        // see https://github.com/ivanceras/r2d2-sqlite/pull/45
+        SqliteConnectionManager::file(create_in_memory_database_string())
+    }
+
+    #[test]
+    fn can_successfully_init() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let _storage = TantivySqliteStorage::new(pool)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn can_read_and_write() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::new(pool)?;
+
+        let data = b"hello, world!";
+        let path = Path::new("some/file/path.txt");
+        storage.atomic_write(path, data)?;
+
+        let content = storage.atomic_read(path)?;
+
+        assert_eq!(content, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn returns_error_if_file_does_not_exist() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::new(pool)?;
+
+        let path = Path::new("some/file/path.txt");
+        let error = storage.atomic_read(path).unwrap_err();
+
+        assert!(matches!(error, error::OpenReadError::FileDoesNotExist(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn error_to_delete_empty_files() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::new(pool)?;
+
+        let path = Path::new("some/file/path.txt");
+        let error = storage.delete(path).unwrap_err();
+
+        assert!(matches!(error, error::DeleteError::FileDoesNotExist(_)));
 
         Ok(())
     }
@@ -575,4 +1647,2535 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn can_write_files_larger_than_a_single_part() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+
+        let part_size = storage.inner.read().part_size();
+        let data: Vec<u8> = (0..part_size * 5 / 2).map(|i| i as u8).collect();
+        let path = Path::new("some/file/path.txt");
+
+        let mut write_ptr = storage.open_write(path)?;
+        write_ptr.write_all(&data)?;
+        write_ptr.terminate()?;
+
+        let content = storage.atomic_read(path)?;
+        assert_eq!(content, data);
+
+        let remaining_parts: i64 =
+            pool.get()?
+                .query_row("SELECT COUNT(*) FROM tantivy_blob_parts", [], |row| {
+                    row.get(0)
+                })?;
+        assert_eq!(remaining_parts, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn flushing_makes_partial_writes_visible() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::new(pool)?;
+
+        let path = Path::new("some/file/path.txt");
+
+        let mut write_ptr = storage.open_write(path)?;
+        write_ptr.write_all(b"hello, ")?;
+        write_ptr.flush()?;
+
+        assert_eq!(storage.atomic_read(path)?, b"hello, ");
+
+        write_ptr.write_all(b"world!")?;
+        write_ptr.terminate()?;
+
+        assert_eq!(storage.atomic_read(path)?, b"hello, world!");
+
+        Ok(())
+    }
+
+    #[test]
+    fn flushing_doesnt_copy_the_file_into_its_blob() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+        pool.get()?.execute_batch(
+            "CREATE TABLE blob_writes (filename BLOB NOT NULL);
+             CREATE TRIGGER count_inserts AFTER INSERT ON tantivy_blobs BEGIN
+                 INSERT INTO blob_writes VALUES (NEW.filename);
+             END;
+             CREATE TRIGGER count_updates AFTER UPDATE OF content ON tantivy_blobs BEGIN
+                 INSERT INTO blob_writes VALUES (NEW.filename);
+             END;",
+        )?;
+
+        let part_size = storage.inner.read().part_size();
+        let data: Vec<u8> = (0..part_size * 5 / 2).map(|i| i as u8).collect();
+        let path = Path::new("some/file/path.txt");
+
+        let mut write_ptr = storage.open_write(path)?;
+        for piece in data.chunks(part_size / 2) {
+            write_ptr.write_all(piece)?;
+            write_ptr.flush()?;
+        }
+
+        // Flushed data is read from the parts while the file is being written
+        let handle = storage.get_file_handle(path)?;
+        assert_eq!(handle.len(), data.len());
+        assert_eq!(
+            handle.read_bytes(part_size - 3..part_size + 3)?.as_slice(),
+            &data[part_size - 3..part_size + 3]
+        );
+        assert_eq!(storage.atomic_read(path)?, data);
+
+        let rowid = || -> rusqlite::Result<i64> {
+            pool.get().unwrap().query_row(
+                "SELECT rowid FROM tantivy_blobs WHERE filename = ?",
+                [b"some/file/path.txt"],
+                |row| row.get(0),
+            )
+        };
+        let created_rowid = rowid()?;
+
+        write_ptr.terminate()?;
+        assert_eq!(storage.atomic_read(path)?, data);
+
+        // Once when the file was created, and once more when it was finished
+        let blob_writes: i64 =
+            pool.get()?
+                .query_row("SELECT COUNT(*) FROM blob_writes", [], |row| row.get(0))?;
+        assert_eq!(blob_writes, 2);
+
+        // The row is updated in place, so handles and cached blocks keep referring to it
+        assert_eq!(rowid()?, created_rowid);
+        storage.atomic_write(path, b"replaced")?;
+        assert_eq!(rowid()?, created_rowid);
+        assert_eq!(storage.atomic_read(path)?, b"replaced");
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunked_layout_reads_ranges_across_chunks() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage =
+            TantivySqliteStorage::with_layout(pool, StorageLayout::Chunked { chunk_size: 4 })?;
+
+        let data = b"hello, world!";
+        let path = Path::new("some/file/path.txt");
+        storage.atomic_write(path, data)?;
+
+        let file_handle = storage.get_file_handle(path)?;
+
+        assert_eq!(file_handle.len(), 13);
+        assert_eq!(&*file_handle.read_bytes(3..10)?, b"lo, wor");
+        assert_eq!(&*file_handle.read_bytes(12..13)?, b"!");
+        assert_eq!(&*file_handle.read_bytes(0..13)?, data);
+        assert_eq!(storage.atomic_read(path)?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunked_layout_streams_writes_into_chunks() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::with_layout(
+            pool.clone(),
+            StorageLayout::Chunked { chunk_size: 4 },
+        )?;
+
+        let path = Path::new("some/file/path.txt");
+
+        let mut write_ptr = storage.open_write(path)?;
+        write_ptr.write_all(b"hello, ")?;
+        write_ptr.flush()?;
+
+        assert_eq!(storage.atomic_read(path)?, b"hello, ");
+
+        write_ptr.write_all(b"world!")?;
+        write_ptr.terminate()?;
+
+        assert_eq!(storage.atomic_read(path)?, b"hello, world!");
+        assert_eq!(&*storage.get_file_handle(path)?.read_bytes(6..9)?, b" wo");
+
+        let num_chunks: i64 =
+            pool.get()?
+                .query_row("SELECT COUNT(*) FROM tantivy_chunks", [], |row| row.get(0))?;
+        assert_eq!(num_chunks, 4);
+
+        storage.delete(path)?;
+
+        let num_chunks: i64 =
+            pool.get()?
+                .query_row("SELECT COUNT(*) FROM tantivy_chunks", [], |row| row.get(0))?;
+        assert_eq!(num_chunks, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_size_must_not_be_zero() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let error =
+            TantivySqliteStorage::with_layout(pool, StorageLayout::Chunked { chunk_size: 0 })
+                .unwrap_err();
+
+        assert!(matches!(
+            error,
+            TantivySqliteStorageError::InvalidConfiguration(_)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunked_layout_can_store_an_index() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{collector::TopDocs, doc, query::QueryParser, schema, Index};
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage =
+            TantivySqliteStorage::with_layout(pool, StorageLayout::Chunked { chunk_size: 128 })?;
+
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT | schema::STORED);
+        let index = Index::open_or_create(storage, schema_builder.build())?;
+
+        let mut index_writer = index.writer(15_000_000)?;
+        index_writer.add_document(doc!(title => "The Old Man and the Sea"))?;
+        index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+        index_writer.commit()?;
+
+        let searcher = index.reader()?.searcher();
+        let query = QueryParser::for_index(&index, vec![title]).parse_query("sea")?;
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(10))?;
+
+        assert_eq!(top_docs.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn namespaces_are_kept_separate() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let articles = TantivySqliteStorage::with_namespace(
+            pool.clone(),
+            "articles",
+            StorageLayout::default(),
+        )?;
+        let comments = TantivySqliteStorage::with_namespace(
+            pool.clone(),
+            "comments",
+            StorageLayout::Chunked { chunk_size: 4 },
+        )?;
+        let default = TantivySqliteStorage::new(pool.clone())?;
+
+        let path = Path::new("meta.json");
+        articles.atomic_write(path, b"articles")?;
+        comments.atomic_write(path, b"comments")?;
+
+        assert_eq!(articles.atomic_read(path)?, b"articles");
+        assert_eq!(comments.atomic_read(path)?, b"comments");
+        assert!(!default.exists(path)?);
+
+        assert_eq!(
+            TantivySqliteStorage::list_namespaces(&pool)?,
+            vec!["articles".to_string(), "comments".to_string()]
+        );
+
+        TantivySqliteStorage::drop_namespace(&pool, "articles")?;
+
+        assert_eq!(
+            TantivySqliteStorage::list_namespaces(&pool)?,
+            vec!["comments".to_string()]
+        );
+        assert_eq!(comments.atomic_read(path)?, b"comments");
+
+        let error = TantivySqliteStorage::drop_namespace(&pool, "articles").unwrap_err();
+        assert!(matches!(
+            error,
+            TantivySqliteStorageError::NamespaceDoesNotExist(_)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn namespaces_dont_collide_across_table_prefixes() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        // Would share the tables of the namespace `a` under the default prefix
+        let error = TantivySqliteStorage::builder(pool.clone())
+            .table_prefix("a_tantivy")
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            TantivySqliteStorageError::InvalidConfiguration(_)
+        ));
+
+        let path = Path::new("meta.json");
+        TantivySqliteStorage::with_namespace(pool.clone(), "a", StorageLayout::default())?
+            .atomic_write(path, b"tantivy")?;
+        TantivySqliteStorage::builder(pool.clone())
+            .table_prefix("search")
+            .namespace("a")
+            .build()?
+            .atomic_write(path, b"search")?;
+
+        assert_eq!(
+            TantivySqliteStorage::list_namespaces(&pool)?,
+            vec!["a".to_string()]
+        );
+
+        TantivySqliteStorage::drop_namespace(&pool, "a")?;
+
+        let num_tables: i64 = pool.get()?.query_row(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name LIKE 'a\\_%' ESCAPE '\\'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(num_tables, 0);
+        assert!(TantivySqliteStorage::list_namespaces(&pool)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_namespaces() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        for namespace in [
+            "",
+            "1abc",
+            "drop table; --",
+            "with space",
+            "with_underscore",
+        ] {
+            let error = TantivySqliteStorage::with_namespace(
+                pool.clone(),
+                namespace,
+                StorageLayout::default(),
+            )
+            .unwrap_err();
+
+            assert!(matches!(
+                error,
+                TantivySqliteStorageError::InvalidNamespace(_)
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn block_cache_serves_repeated_reads() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::new(pool)?;
+        storage.set_block_cache(BlockCacheConfig {
+            capacity: 64,
+            block_size: 4,
+        })?;
+
+        let data = b"hello, world!";
+        let path = Path::new("some/file/path.txt");
+        storage.atomic_write(path, data)?;
+
+        let file_handle = storage.get_file_handle(path)?;
+
+        assert_eq!(&*file_handle.read_bytes(3..10)?, b"lo, wor");
+        assert_eq!(storage.cache_stats().misses, 3);
+        assert_eq!(storage.cache_stats().hits, 0);
+
+        assert_eq!(&*file_handle.read_bytes(4..6)?, b"o,");
+        assert_eq!(&*file_handle.read_bytes(11..13)?, b"d!");
+        assert_eq!(storage.cache_stats().hits, 2);
+        assert_eq!(storage.cache_stats().misses, 4);
+        assert_eq!(storage.cache_stats().cached_bytes, 13);
+
+        Ok(())
+    }
+
+    #[test]
+    fn block_cache_is_invalidated_by_writes() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::new(pool)?;
+        storage.set_block_cache(BlockCacheConfig {
+            capacity: 64,
+            block_size: 4,
+        })?;
+
+        let path = Path::new("some/file/path.txt");
+        storage.atomic_write(path, b"hello, world!")?;
+        assert_eq!(&*storage.get_file_handle(path)?.read_bytes(0..5)?, b"hello");
+
+        storage.atomic_write(path, b"howdy, world!")?;
+        assert_eq!(&*storage.get_file_handle(path)?.read_bytes(0..5)?, b"howdy");
+
+        storage.delete(path)?;
+        storage.atomic_write(path, b"hiya!")?;
+        assert_eq!(&*storage.get_file_handle(path)?.read_bytes(0..5)?, b"hiya!");
+
+        assert_eq!(storage.cache_stats().hits, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn block_cache_only_invalidates_the_blocks_of_one_file(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let cache = BlockCache::new(BlockCacheConfig {
+            capacity: 12,
+            block_size: 4,
+        })?
+        .expect("the cache is enabled");
+        let handle = |file_id| ReadHandleData {
+            file_id,
+            length: 12,
+            chunk_size: None,
+            compression: None,
+            encrypted: false,
+            unfinished: false,
+            checksum: None,
+        };
+        let load = |range: Range<usize>| Ok(OwnedBytes::new(vec![0; range.len()]));
+
+        cache.read_bytes(&handle(1), 0..12, load)?;
+        // Evicts the first block of file 1
+        cache.read_bytes(&handle(2), 0..4, load)?;
+        assert_eq!(cache.stats().cached_bytes, 12);
+
+        cache.invalidate(1);
+        assert_eq!(cache.stats().cached_bytes, 4);
+
+        cache.read_bytes(&handle(2), 0..4, load)?;
+        cache.read_bytes(&handle(1), 4..8, load)?;
+        assert_eq!(cache.stats().hits, 1);
+        assert_eq!(cache.stats().misses, 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn catalog_answers_without_querying_sqlite() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+
+        let small_path = Path::new("meta.json");
+        let large_path = Path::new("segment.idx");
+        storage.atomic_write(small_path, b"{}")?;
+        storage.atomic_write(large_path, b"hello, world!")?;
+
+        storage.load_catalog(8)?;
+
+        // Remove the files behind the catalog's back to check it is being used
+        pool.get()?.execute("DELETE FROM tantivy_blobs", [])?;
+
+        assert!(storage.exists(small_path)?);
+        assert!(storage.exists(large_path)?);
+        assert_eq!(storage.atomic_read(small_path)?, b"{}");
+        assert_eq!(
+            &*storage.get_file_handle(small_path)?.read_bytes(0..2)?,
+            b"{}"
+        );
+        assert_eq!(storage.get_file_handle(large_path)?.len(), 13);
+
+        Ok(())
+    }
+
+    #[test]
+    fn catalog_is_kept_up_to_date() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage =
+            TantivySqliteStorage::with_layout(pool, StorageLayout::Chunked { chunk_size: 4 })?;
+        storage.load_catalog(DEFAULT_EAGER_LOAD_THRESHOLD)?;
+
+        let path = Path::new("some/file/path.txt");
+        assert!(!storage.exists(path)?);
+
+        storage.atomic_write(path, b"hello, world!")?;
+        assert_eq!(storage.atomic_read(path)?, b"hello, world!");
+
+        storage.delete(path)?;
+        assert!(!storage.exists(path)?);
+
+        let mut write_ptr = storage.open_write(path)?;
+        write_ptr.write_all(b"howdy")?;
+        write_ptr.terminate()?;
+
+        assert_eq!(&*storage.get_file_handle(path)?.read_bytes(0..5)?, b"howdy");
+
+        Ok(())
+    }
+
+    #[test]
+    fn builder_uses_the_table_prefix() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::builder(pool.clone())
+            .table_prefix("search")
+            .build()?;
+        storage.atomic_write(Path::new("foo"), b"hello")?;
+
+        let conn = pool.get()?;
+        let count: i64 =
+            conn.query_row("SELECT COUNT(*) FROM search_blobs", [], |row| row.get(0))?;
+        assert_eq!(count, 1);
+
+        let error = TantivySqliteStorage::builder(pool)
+            .table_prefix("not a prefix")
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            TantivySqliteStorageError::InvalidConfiguration(_)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn builder_can_require_an_existing_schema() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let error = TantivySqliteStorage::builder(pool.clone())
+            .create_schema(false)
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            TantivySqliteStorageError::SchemaDoesNotExist(table) if table == "tantivy_blobs"
+        ));
+
+        TantivySqliteStorage::new(pool.clone())?.atomic_write(Path::new("foo"), b"hello")?;
+
+        let storage = TantivySqliteStorage::builder(pool)
+            .create_schema(false)
+            .build()?;
+        assert_eq!(storage.atomic_read(Path::new("foo"))?, b"hello");
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_storage_rejects_writes() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        TantivySqliteStorage::new(pool.clone())?.atomic_write(Path::new("foo"), b"hello")?;
+
+        let storage = TantivySqliteStorage::builder(pool.clone())
+            .read_only(true)
+            .build()?;
+        assert_eq!(storage.atomic_read(Path::new("foo"))?, b"hello");
+        assert!(storage.atomic_write(Path::new("foo"), b"bye").is_err());
+        assert!(storage.delete(Path::new("foo")).is_err());
+        assert!(storage.open_write(Path::new("bar")).is_err());
+        assert_eq!(storage.atomic_read(Path::new("foo"))?, b"hello");
+
+        let error = TantivySqliteStorage::builder(pool)
+            .read_only(true)
+            .create_schema(true)
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            TantivySqliteStorageError::InvalidConfiguration(_)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_commits_index_with_application_data() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{doc, schema, Index};
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+        pool.get()?
+            .execute("CREATE TABLE articles (title TEXT NOT NULL)", [])?;
+
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+
+        let mut index_writer = index.writer(15_000_000)?;
+
+        let transaction = storage.begin_transaction()?;
+        assert!(matches!(
+            storage.begin_transaction(),
+            Err(TantivySqliteStorageError::TransactionInProgress)
+        ));
+
+        transaction
+            .connection()
+            .execute("INSERT INTO articles VALUES ('Of Mice and Men')", [])?;
+        index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+        index_writer.commit()?;
+        transaction.commit()?;
+
+        let count: i64 = pool
+            .get()?
+            .query_row("SELECT COUNT(*) FROM articles", [], |row| row.get(0))?;
+        assert_eq!(count, 1);
+
+        let index = Index::open(TantivySqliteStorage::new(pool)?)?;
+        assert_eq!(index.reader()?.searcher().num_docs(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn transaction_rollback_undoes_index_commit() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{doc, schema, Index};
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+        pool.get()?
+            .execute("CREATE TABLE articles (title TEXT NOT NULL)", [])?;
+
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+        storage.load_catalog(DEFAULT_EAGER_LOAD_THRESHOLD)?;
+
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+        let meta_before = storage.atomic_read(Path::new("meta.json"))?;
+
+        let mut index_writer = index.writer(15_000_000)?;
+
+        let transaction = storage.begin_transaction()?;
+        transaction
+            .connection()
+            .execute("INSERT INTO articles VALUES ('Of Mice and Men')", [])?;
+        index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+        index_writer.commit()?;
+        assert_ne!(storage.atomic_read(Path::new("meta.json"))?, meta_before);
+        transaction.rollback()?;
+        drop(index_writer);
+
+        assert_eq!(storage.atomic_read(Path::new("meta.json"))?, meta_before);
+
+        let count: i64 = pool
+            .get()?
+            .query_row("SELECT COUNT(*) FROM articles", [], |row| row.get(0))?;
+        assert_eq!(count, 0);
+
+        let index = Index::open(TantivySqliteStorage::new(pool)?)?;
+        assert_eq!(index.reader()?.searcher().num_docs(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn index_writer_lock_excludes_other_storages() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{schema, Index, TantivyError};
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::builder(pool.clone())
+            .lock_lease(Duration::from_millis(300))
+            .build()?;
+        let index = Index::open_or_create(storage, schema::Schema::builder().build())?;
+        let index_writer = index.writer_with_num_threads(1, 15_000_000)?;
+
+        // Outlive the lease, so the lock is only still held because it has been renewed
+        std::thread::sleep(Duration::from_secs(1));
+
+        let other_index = Index::open(TantivySqliteStorage::new(pool.clone())?)?;
+        assert!(matches!(
+            other_index.writer_with_num_threads(1, 15_000_000),
+            Err(TantivyError::LockFailure(error::LockError::LockBusy, _))
+        ));
+
+        drop(index_writer);
+        other_index.writer_with_num_threads(1, 15_000_000)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_fail_once_the_index_writer_lock_is_lost() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{doc, schema, Index};
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::builder(pool.clone())
+            .lock_lease(Duration::from_millis(300))
+            .build()?;
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+        let mut index_writer = index.writer_with_num_threads(1, 15_000_000)?;
+
+        // Another writer took the lock over after the lease ran out
+        pool.get()?
+            .execute("UPDATE tantivy_locks SET owner = 'someone else'", [])?;
+        std::thread::sleep(Duration::from_millis(300));
+
+        assert!(matches!(
+            storage.inner.write().atomic_write(Path::new("foo"), b"bar"),
+            Err(TantivySqliteStorageError::LockLost)
+        ));
+        index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+        assert!(index_writer.commit().is_err());
+        assert_eq!(index.reader()?.searcher().num_docs(), 0);
+
+        drop(index_writer);
+        storage.atomic_write(Path::new("foo"), b"bar")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn streamed_writes_stop_before_the_lease_runs_out() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{schema, Index};
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::builder(pool.clone())
+            .lock_lease(Duration::from_millis(400))
+            .build()?;
+        let index = Index::open_or_create(storage.clone(), schema::Schema::builder().build())?;
+        let index_writer = index.writer_with_num_threads(1, 15_000_000)?;
+
+        let part = vec![0; storage.inner.read().part_size()];
+        let mut write_ptr = storage.open_write(Path::new("foo"))?;
+        write_ptr.write_all(&part)?;
+
+        // Renewals fail from now on, but the lease is given up before it could have run out
+        pool.get()?.execute(
+            "ALTER TABLE tantivy_locks RENAME TO tantivy_locks_elsewhere",
+            [],
+        )?;
+        std::thread::sleep(Duration::from_millis(320));
+
+        let error = write_ptr.write_all(&part).unwrap_err();
+        assert!(matches!(
+            error.get_ref().and_then(|e| e.downcast_ref()),
+            Some(TantivySqliteStorageError::LockLost)
+        ));
+
+        pool.get()?.execute(
+            "ALTER TABLE tantivy_locks_elsewhere RENAME TO tantivy_locks",
+            [],
+        )?;
+        drop(index_writer);
+
+        Ok(())
+    }
+
+    #[test]
+    fn locks_are_kept_out_of_transactions() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{schema, Index, TantivyError};
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::builder(pool.clone())
+            .lock_lease(Duration::from_millis(300))
+            .build()?;
+        let index = Index::open_or_create(storage.clone(), schema::Schema::builder().build())?;
+        let index_writer = index.writer_with_num_threads(1, 15_000_000)?;
+
+        // One lock is held from before the transaction, and the other is taken during it. The
+        // transaction outlives both leases.
+        let transaction = storage.begin_transaction()?;
+        let other_lock = storage.acquire_lock(&Lock {
+            filepath: PathBuf::from("other.lock"),
+            is_blocking: false,
+        })?;
+        std::thread::sleep(Duration::from_millis(600));
+        transaction.rollback()?;
+
+        let num_held: i64 = pool.get()?.query_row(
+            "SELECT COUNT(*) FROM tantivy_locks WHERE expires_at > ?",
+            [now_millis()],
+            |row| row.get(0),
+        )?;
+        assert_eq!(num_held, 2);
+
+        let other_storage = TantivySqliteStorage::new(pool.clone())?;
+        assert!(matches!(
+            Index::open(other_storage.clone())?.writer_with_num_threads(1, 15_000_000),
+            Err(TantivyError::LockFailure(error::LockError::LockBusy, _))
+        ));
+        assert!(matches!(
+            other_storage.acquire_lock(&Lock {
+                filepath: PathBuf::from("other.lock"),
+                is_blocking: false,
+            }),
+            Err(error::LockError::LockBusy)
+        ));
+
+        // Released during a transaction, and gone once it has finished
+        let transaction = storage.begin_transaction()?;
+        drop((index_writer, other_lock));
+        transaction.commit()?;
+
+        let num_locks: i64 =
+            pool.get()?
+                .query_row("SELECT COUNT(*) FROM tantivy_locks", [], |row| row.get(0))?;
+        assert_eq!(num_locks, 0);
+        storage.atomic_write(Path::new("foo"), b"bar")?;
+
+        // Released and taken again during a transaction, which only lets one holder have it
+        let meta_lock = Lock {
+            filepath: PathBuf::from("meta.lock"),
+            is_blocking: false,
+        };
+        let held = storage.acquire_lock(&meta_lock)?;
+        let transaction = storage.begin_transaction()?;
+        drop(held);
+        let held = storage.acquire_lock(&meta_lock)?;
+        assert!(matches!(
+            storage.acquire_lock(&meta_lock),
+            Err(error::LockError::LockBusy)
+        ));
+        transaction.commit()?;
+
+        assert!(matches!(
+            other_storage.acquire_lock(&meta_lock),
+            Err(error::LockError::LockBusy)
+        ));
+        drop(held);
+
+        Ok(())
+    }
+
+    #[test]
+    fn stale_locks_can_be_taken_over() -> Result<(), Box<dyn std::error::Error>> {
+        use std::os::unix::prelude::OsStrExt;
+        use tantivy::{directory::INDEX_WRITER_LOCK, schema, Index};
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+        let index = Index::open_or_create(storage, schema::Schema::builder().build())?;
+
+        // Left behind by a process which crashed while holding the lock
+        pool.get()?.execute(
+            "INSERT INTO tantivy_locks VALUES (?, 'crashed', 1, 0)",
+            [INDEX_WRITER_LOCK.filepath.as_os_str().as_bytes()],
+        )?;
+
+        let index_writer = index.writer_with_num_threads(1, 15_000_000)?;
+        drop(index_writer);
+
+        let num_locks: i64 =
+            pool.get()?
+                .query_row("SELECT COUNT(*) FROM tantivy_locks", [], |row| row.get(0))?;
+        assert_eq!(num_locks, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn readers_notice_commits_from_other_connections() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{doc, schema, Index, ReloadPolicy};
+
+        let path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+
+        // Separate pools without a shared cache behave like separate processes
+        let writer_pool = Pool::builder().build(SqliteConnectionManager::file(&path))?;
+        let reader_pool = Pool::builder().build(SqliteConnectionManager::file(&path))?;
+
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let writer_index = Index::open_or_create(
+            TantivySqliteStorage::new(writer_pool)?,
+            schema_builder.build(),
+        )?;
+
+        let reader_storage = TantivySqliteStorage::builder(reader_pool)
+            .watch_interval(Some(Duration::from_millis(20)))
+            .build()?;
+        let reader = Index::open(reader_storage)?
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommit)
+            .try_into()?;
+        assert_eq!(reader.searcher().num_docs(), 0);
+
+        let mut index_writer = writer_index.writer_with_num_threads(1, 15_000_000)?;
+        index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+        index_writer.commit()?;
+
+        let mut num_docs = 0;
+        for _ in 0..100 {
+            num_docs = reader.searcher().num_docs();
+            if num_docs == 1 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(num_docs, 1);
+
+        drop(reader);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn can_open_read_only_databases() -> Result<(), Box<dyn std::error::Error>> {
+        use rusqlite::OpenFlags;
+        use tantivy::{collector::Count, doc, query::AllQuery, schema, Index};
+
+        let path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+
+        {
+            let pool = Pool::builder().build(SqliteConnectionManager::file(&path))?;
+
+            let mut schema_builder = schema::Schema::builder();
+            let title = schema_builder.add_text_field("title", schema::TEXT);
+            let index =
+                Index::open_or_create(TantivySqliteStorage::new(pool)?, schema_builder.build())?;
+
+            let mut index_writer = index.writer_with_num_threads(1, 15_000_000)?;
+            index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+            index_writer.commit()?;
+        }
+
+        let read_only_manager = SqliteConnectionManager::file(&path)
+            .with_flags(OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX);
+        let immutable_manager =
+            SqliteConnectionManager::file(format!("file:{}?immutable=1", path.display()));
+
+        for manager in [read_only_manager, immutable_manager] {
+            let storage = TantivySqliteStorage::open_read_only(Pool::builder().build(manager)?)?;
+
+            let index = Index::open(storage.clone())?;
+            let searcher = index.reader()?.searcher();
+            assert_eq!(searcher.search(&AllQuery, &Count)?, 1);
+
+            assert!(index.writer_with_num_threads(1, 15_000_000).is_err());
+            assert!(matches!(
+                storage
+                    .inner
+                    .write()
+                    .atomic_write(Path::new("meta.json"), b"{}"),
+                Err(TantivySqliteStorageError::ReadOnly)
+            ));
+            assert!(matches!(
+                storage.inner.write().delete(Path::new("meta.json")),
+                Err(TantivySqliteStorageError::ReadOnly)
+            ));
+            assert!(storage.open_write(Path::new("new_file")).is_err());
+        }
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn compression_keeps_random_access_reads() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::builder(pool.clone())
+            .chunk_size(1024)
+            .compression(CompressionConfig::default())
+            .build()?;
+
+        let content: Vec<u8> = (0..10_000u32)
+            .flat_map(|i| (i / 100).to_le_bytes())
+            .collect();
+        storage.atomic_write(Path::new("foo.idx"), &content)?;
+        storage.atomic_write(Path::new("foo.store"), &content)?;
+
+        {
+            let mut writer = storage.open_write(Path::new("bar.idx"))?;
+            writer.write_all(&content)?;
+            writer.terminate()?;
+        }
+
+        for path in ["foo.idx", "foo.store", "bar.idx"] {
+            let handle = storage.get_file_handle(Path::new(path))?;
+            assert_eq!(handle.len(), content.len());
+            assert_eq!(
+                handle.read_bytes(1000..3100)?.as_slice(),
+                &content[1000..3100]
+            );
+            assert_eq!(storage.atomic_read(Path::new(path))?, content);
+        }
+
+        let conn = pool.get()?;
+        let stored_size = |filename: &str| -> rusqlite::Result<(Option<String>, i64)> {
+            conn.query_row(
+                "SELECT compression, SUM(length(content)) FROM tantivy_files JOIN tantivy_chunks ON file_id = id WHERE filename = ?",
+                [filename.as_bytes()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+        };
+
+        let (compression, size) = stored_size("foo.idx")?;
+        assert_eq!(compression.as_deref(), Some("lz4"));
+        assert!((size as usize) < content.len() / 4);
+
+        let (compression, size) = stored_size("foo.store")?;
+        assert_eq!(compression, None);
+        assert_eq!(size as usize, content.len());
+
+        // The database is chunked, so only a new database defaults to the single blob layout
+        let reopened = TantivySqliteStorage::builder(pool)
+            .compression(CompressionConfig::default())
+            .build()?;
+        assert_eq!(reopened.atomic_read(Path::new("foo.idx"))?, content);
+
+        let error = TantivySqliteStorage::builder(Pool::new(in_memory_connection_manager())?)
+            .compression(CompressionConfig::default())
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            TantivySqliteStorageError::InvalidConfiguration(_)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn encryption_keeps_random_access_reads() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+        let key = EncryptionKey::generate();
+
+        let storage = TantivySqliteStorage::builder(pool.clone())
+            .chunk_size(1024)
+            .encryption(key.clone())
+            .build()?;
+
+        let content: Vec<u8> = (0..10_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        storage.atomic_write(Path::new("foo.idx"), &content)?;
+
+        {
+            let mut writer = storage.open_write(Path::new("bar.idx"))?;
+            writer.write_all(&content)?;
+            writer.terminate()?;
+        }
+
+        let conn = pool.get()?;
+        let first_chunk: Vec<u8> = conn.query_row(
+            "SELECT content FROM tantivy_chunks JOIN tantivy_files ON file_id = id WHERE filename = ? AND chunk_index = 0",
+            [b"foo.idx"],
+            |row| row.get(0),
+        )?;
+        assert!(!first_chunk
+            .windows(16)
+            .any(|window| window == &content[..16]));
+
+        let reopened = TantivySqliteStorage::builder(pool)
+            .chunk_size(1024)
+            .encryption(key)
+            .build()?;
+
+        for path in ["foo.idx", "bar.idx"] {
+            let handle = reopened.get_file_handle(Path::new(path))?;
+            assert_eq!(handle.len(), content.len());
+            assert_eq!(
+                handle.read_bytes(1000..3100)?.as_slice(),
+                &content[1000..3100]
+            );
+            assert_eq!(reopened.atomic_read(Path::new(path))?, content);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn encryption_key_can_be_rewrapped() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+        let old_key = EncryptionKey::generate();
+        let new_key = EncryptionKey::generate();
+
+        let open = |key: &EncryptionKey| {
+            TantivySqliteStorage::builder(pool.clone())
+                .chunk_size(1024)
+                .encryption(key.clone())
+                .build()
+        };
+
+        let storage = open(&old_key)?;
+        storage.atomic_write(Path::new("foo"), b"some content")?;
+
+        assert!(matches!(
+            open(&new_key).unwrap_err(),
+            TantivySqliteStorageError::DecryptionFailed
+        ));
+
+        storage.rewrap_encryption_key(&new_key)?;
+
+        assert!(matches!(
+            open(&old_key).unwrap_err(),
+            TantivySqliteStorageError::DecryptionFailed
+        ));
+        assert_eq!(
+            open(&new_key)?.atomic_read(Path::new("foo"))?,
+            b"some content"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn encrypted_files_authenticate_their_length() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+        let key = EncryptionKey::generate();
+
+        let open = || {
+            TantivySqliteStorage::builder(pool.clone())
+                .chunk_size(1024)
+                .encryption(key.clone())
+                .build()
+        };
+
+        let storage = open()?;
+        let content: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        storage.atomic_write(Path::new("foo"), &content)?;
+
+        let conn = pool.get()?;
+        let checksum: Option<u32> = conn.query_row(
+            "SELECT checksum FROM tantivy_files WHERE filename = ?",
+            [b"foo"],
+            |row| row.get(0),
+        )?;
+        assert_eq!(checksum, None);
+        assert_eq!(storage.verify()?, VerifyReport::default());
+
+        storage.atomic_write(Path::new("bar"), &content)?;
+        storage.atomic_write(Path::new("baz"), &content)?;
+        storage.atomic_write(Path::new("qux"), &content)?;
+
+        let file_id = |path: &str| -> rusqlite::Result<i64> {
+            conn.query_row(
+                "SELECT id FROM tantivy_files WHERE filename = ?",
+                [path.as_bytes()],
+                |row| row.get(0),
+            )
+        };
+
+        // Dropping the last chunk and shortening the file to match
+        conn.execute(
+            "DELETE FROM tantivy_chunks WHERE file_id = ? AND chunk_index = 2",
+            [file_id("foo")?],
+        )?;
+        conn.execute(
+            "UPDATE tantivy_files SET length = 2048 WHERE id = ?",
+            [file_id("foo")?],
+        )?;
+        // The same again, with the tag removed so there is nothing to check it against
+        conn.execute(
+            "DELETE FROM tantivy_chunks WHERE file_id = ? AND chunk_index = 2",
+            [file_id("bar")?],
+        )?;
+        conn.execute(
+            "UPDATE tantivy_files SET length = 2048, length_tag = NULL WHERE id = ?",
+            [file_id("bar")?],
+        )?;
+        // Replacing the content with plaintext and marking the file as unencrypted
+        conn.execute(
+            "DELETE FROM tantivy_chunks WHERE file_id = ?",
+            [file_id("baz")?],
+        )?;
+        conn.execute(
+            "INSERT INTO tantivy_chunks VALUES (?, 0, ?)",
+            rusqlite::params![file_id("baz")?, &content[..1024]],
+        )?;
+        conn.execute(
+            "UPDATE tantivy_files SET length = 1024, encrypted = 0 WHERE id = ?",
+            [file_id("baz")?],
+        )?;
+
+        let reopened = open()?;
+        for path in ["foo", "bar", "baz"] {
+            assert!(matches!(
+                reopened.inner.read().check_stored_file(Path::new(path)),
+                Err(TantivySqliteStorageError::CorruptFile(corrupt)) if corrupt == Path::new(path)
+            ));
+        }
+
+        // The untouched file is still listed, read and cached alongside them
+        assert_eq!(reopened.list_files()?.len(), 4);
+        assert_eq!(
+            reopened.verify()?.corrupt,
+            vec![
+                PathBuf::from("bar"),
+                PathBuf::from("baz"),
+                PathBuf::from("foo")
+            ]
+        );
+        reopened.load_catalog(1 << 20)?;
+        assert_eq!(reopened.atomic_read(Path::new("qux"))?, content);
+        assert!(reopened.atomic_read(Path::new("bar")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunked_file_ids_are_not_reused() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::with_layout(
+            pool.clone(),
+            StorageLayout::Chunked { chunk_size: 1024 },
+        )?;
+        storage.atomic_write(Path::new("foo"), b"foo")?;
+
+        // Deleting the file with the highest id would otherwise let the next file take it
+        storage.delete(Path::new("foo"))?;
+        storage.atomic_write(Path::new("bar"), b"bar")?;
+
+        let file_id: i64 = pool.get()?.query_row(
+            "SELECT id FROM tantivy_files WHERE filename = ?",
+            [b"bar"],
+            |row| row.get(0),
+        )?;
+        assert_eq!(file_id, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verify_reports_corrupt_missing_and_orphaned_files() -> Result<(), Box<dyn std::error::Error>>
+    {
+        use std::os::unix::prelude::OsStrExt;
+        use tantivy::{doc, schema, Index};
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT | schema::STORED);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+
+        let mut index_writer = index.writer(15_000_000)?;
+        index_writer.add_document(doc!(title => "The Old Man and the Sea"))?;
+        index_writer.commit()?;
+        drop(index_writer);
+
+        assert_eq!(storage.verify()?, VerifyReport::default());
+
+        let segment_files: Vec<PathBuf> = index.searchable_segment_metas()?[0]
+            .list_files()
+            .into_iter()
+            .collect();
+        let corrupt = segment_files
+            .iter()
+            .find(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "store")
+            })
+            .unwrap();
+        let missing = segment_files
+            .iter()
+            .find(|path| path.extension().is_some_and(|extension| extension == "pos"))
+            .unwrap();
+
+        let conn = pool.get()?;
+        conn.execute(
+            "UPDATE tantivy_blobs SET content = zeroblob(length(content)) WHERE filename = ?",
+            [corrupt.as_os_str().as_bytes()],
+        )?;
+        conn.execute(
+            "DELETE FROM tantivy_blobs WHERE filename = ?",
+            [missing.as_os_str().as_bytes()],
+        )?;
+        storage.atomic_write(Path::new("orphan.idx"), b"left behind")?;
+
+        // A file still being written has no checksum yet, but isn't unchecked
+        let mut write_ptr = storage.open_write(Path::new("unfinished.idx"))?;
+        write_ptr.write_all(b"in progress")?;
+        write_ptr.flush()?;
+
+        let report = storage.verify()?;
+        assert!(!report.is_ok());
+        assert_eq!(report.corrupt, vec![corrupt.clone()]);
+        assert_eq!(report.missing, vec![missing.clone()]);
+        assert_eq!(
+            report.orphaned,
+            vec![PathBuf::from("orphan.idx"), PathBuf::from("unfinished.idx")]
+        );
+        assert!(report.unchecked.is_empty());
+
+        assert!(matches!(
+            storage.inner.read().check_stored_file(corrupt),
+            Err(TantivySqliteStorageError::CorruptFile(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn chunked_layout_detects_truncated_files() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::with_layout(
+            pool.clone(),
+            StorageLayout::Chunked { chunk_size: 4 },
+        )?;
+
+        {
+            let mut writer = storage.open_write(Path::new("foo"))?;
+            writer.write_all(b"hello world")?;
+            writer.terminate()?;
+        }
+        assert_eq!(storage.atomic_read(Path::new("foo"))?, b"hello world");
+
+        pool.get()?
+            .execute("DELETE FROM tantivy_chunks WHERE chunk_index = 2", [])?;
+
+        assert!(matches!(
+            storage.inner.read().atomic_read(Path::new("foo")),
+            Err(TantivySqliteStorageError::CorruptFile(_))
+        ));
+        assert_eq!(storage.verify()?.corrupt, vec![PathBuf::from("foo")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn gc_deletes_unreferenced_managed_files() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{doc, schema, Index};
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+        let storage = TantivySqliteStorage::new(pool)?;
+
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT | schema::STORED);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+
+        let mut index_writer = index.writer(15_000_000)?;
+        index_writer.add_document(doc!(title => "The Old Man and the Sea"))?;
+        index_writer.commit()?;
+
+        assert!(matches!(
+            storage.gc(&GcOptions::default()),
+            Err(TantivySqliteStorageError::IndexLocked)
+        ));
+        drop(index_writer);
+
+        // Pretend a writer crashed after creating some files
+        let mut managed: Vec<PathBuf> =
+            serde_json::from_slice(&storage.atomic_read(Path::new(".managed.json"))?)?;
+        managed.extend(["crashed.idx".into(), "held.idx".into()]);
+        storage.atomic_write(Path::new(".managed.json"), &serde_json::to_vec(&managed)?)?;
+        storage.atomic_write(Path::new("crashed.idx"), b"left behind")?;
+        storage.atomic_write(Path::new("held.idx"), b"still being read")?;
+        storage.atomic_write(Path::new("unmanaged.idx"), b"not tantivy's")?;
+
+        let held = storage.get_file_handle(Path::new("held.idx"))?;
+
+        let report = storage.gc(&GcOptions::default())?;
+        assert_eq!(report.deleted, vec![PathBuf::from("crashed.idx")]);
+        assert_eq!(report.reclaimed_bytes, 11);
+        assert_eq!(report.skipped_open, vec![PathBuf::from("held.idx")]);
+        assert_eq!(held.read_bytes(0..5)?.as_slice(), b"still");
+
+        drop(held);
+        let report = storage.gc(&GcOptions {
+            incremental_vacuum: true,
+        })?;
+        assert_eq!(report.deleted, vec![PathBuf::from("held.idx")]);
+        assert!(report.skipped_open.is_empty());
+
+        assert!(storage.exists(Path::new("unmanaged.idx"))?);
+        assert!(storage.verify()?.is_ok());
+        assert_eq!(index.reader()?.searcher().num_docs(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn list_files_reports_sizes_and_timestamps() -> Result<(), Box<dyn std::error::Error>> {
+        for layout in [
+            StorageLayout::SingleBlob,
+            StorageLayout::Chunked { chunk_size: 4 },
+        ] {
+            let manager = in_memory_connection_manager();
+            let pool = Pool::builder().max_size(4).build(manager)?;
+            let storage = TantivySqliteStorage::with_layout(pool, layout)?;
+
+            storage.atomic_write(Path::new("foo"), b"hello")?;
+            {
+                let mut writer = storage.open_write(Path::new("bar"))?;
+                writer.write_all(b"hello world")?;
+                writer.terminate()?;
+            }
+
+            let files = storage.list_files()?;
+            assert_eq!(
+                files.iter().map(|file| &file.path).collect::<Vec<_>>(),
+                vec![Path::new("bar"), Path::new("foo")]
+            );
+            assert_eq!(files[0].size, 11);
+            assert_eq!(files[0].checksum, Some(crc32c::crc32c(b"hello world")));
+            assert_eq!(files[1].size, 5);
+            assert_eq!(files[1].checksum, Some(crc32c::crc32c(b"hello")));
+
+            let created_at = files[1].created_at.unwrap();
+            assert_eq!(files[1].updated_at, Some(created_at));
+
+            std::thread::sleep(Duration::from_millis(5));
+            storage.atomic_write(Path::new("foo"), b"goodbye")?;
+
+            let foo = storage.list_files()?.remove(1);
+            assert_eq!(foo.size, 7);
+            assert_eq!(foo.created_at, Some(created_at));
+            assert!(foo.updated_at.unwrap() > created_at);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrates_databases_from_before_versioning() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let conn = pool.get()?;
+        conn.execute_batch(
+            "CREATE TABLE tantivy_blobs (filename TEXT UNIQUE NOT NULL, content BLOB NOT NULL);
+            INSERT INTO tantivy_blobs VALUES (CAST('foo' AS BLOB), x'010203');",
+        )?;
+
+        // The blobs table doesn't have the columns which are read now
+        assert!(matches!(
+            TantivySqliteStorage::open_read_only(pool.clone()),
+            Err(TantivySqliteStorageError::SchemaNeedsMigration { found: 0, .. })
+        ));
+
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+        assert_eq!(storage.atomic_read(Path::new("foo"))?, vec![1, 2, 3]);
+        assert_eq!(storage.list_files()?[0].checksum, None);
+        assert_eq!(storage.verify()?.unchecked, vec![PathBuf::from("foo")]);
+
+        let version: u32 = conn.query_row(
+            "SELECT value FROM tantivy_storage_meta WHERE key = 'format_version'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(version, migrations::CURRENT_VERSION);
+
+        TantivySqliteStorage::open_read_only(pool)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_older_versions_without_migrating_them() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        // A database written before the format was versioned which already has the columns read now
+        TantivySqliteStorage::new(pool.clone())?.atomic_write(Path::new("foo"), b"bar")?;
+        pool.get()?.execute(
+            "UPDATE tantivy_storage_meta SET value = 0 WHERE key = 'format_version'",
+            [],
+        )?;
+
+        let storage = TantivySqliteStorage::open_read_only(pool.clone())?;
+        assert_eq!(storage.atomic_read(Path::new("foo"))?, b"bar");
+        assert!(matches!(
+            TantivySqliteStorage::builder(pool.clone())
+                .create_schema(false)
+                .build(),
+            Err(TantivySqliteStorageError::SchemaNeedsMigration { found: 0, .. })
+        ));
+
+        // The blobs table as first released has no checksums or timestamps
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+        pool.get()?.execute(
+            "CREATE TABLE tantivy_blobs (filename TEXT UNIQUE NOT NULL, content BLOB NOT NULL)",
+            [],
+        )?;
+
+        let error = TantivySqliteStorage::open_read_only(pool).unwrap_err();
+        assert!(matches!(
+            error,
+            TantivySqliteStorageError::SchemaNeedsMigration { found: 0, .. }
+        ));
+        assert!(error
+            .to_string()
+            .contains("open the database read-write once"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_open_newer_versions() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        TantivySqliteStorage::new(pool.clone())?;
+        pool.get()?.execute(
+            "UPDATE tantivy_storage_meta SET value = 99 WHERE key = 'format_version'",
+            [],
+        )?;
+
+        for result in [
+            TantivySqliteStorage::new(pool.clone()),
+            TantivySqliteStorage::open_read_only(pool),
+        ] {
+            assert!(matches!(
+                result,
+                Err(TantivySqliteStorageError::UnsupportedSchemaVersion { found: 99, .. })
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn exports_an_openable_index() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{
+            collector::TopDocs, directory::RamDirectory, doc, query::QueryParser, schema, Index,
+        };
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+        let storage =
+            TantivySqliteStorage::with_layout(pool, StorageLayout::Chunked { chunk_size: 64 })?;
+
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT | schema::STORED);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+
+        let mut index_writer = index.writer(15_000_000)?;
+        index_writer.add_document(doc!(title => "The Old Man and the Sea"))?;
+        index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+        index_writer.commit()?;
+
+        let target = RamDirectory::create();
+        let mut updates = vec![];
+        storage.export_to(&target, |progress| updates.push(progress))?;
+
+        let last = *updates.last().unwrap();
+        assert_eq!(last.files_copied, last.total_files);
+        assert_eq!(last.bytes_copied, last.total_bytes);
+        assert!(updates
+            .windows(2)
+            .all(|w| w[0].bytes_copied <= w[1].bytes_copied));
+
+        let exported = Index::open(target.clone())?;
+        let searcher = exported.reader()?.searcher();
+        let query = QueryParser::for_index(&exported, vec![title]).parse_query("mice")?;
+        assert_eq!(searcher.search(&query, &TopDocs::with_limit(10))?.len(), 1);
+
+        assert!(matches!(
+            storage.export_to(&target, |_| {}),
+            Err(TantivySqliteStorageError::FileAlreadyExists(_))
+        ));
+
+        let path = std::env::temp_dir().join(format!("tantivy-export-{}", Uuid::new_v4()));
+        storage.export_to_path(&path, |_| {})?;
+        for file in exported
+            .searchable_segment_metas()?
+            .iter()
+            .flat_map(|s| s.list_files())
+        {
+            if file.extension() != Some("del".as_ref()) {
+                assert_eq!(std::fs::read(path.join(&file))?, target.atomic_read(&file)?);
+            }
+        }
+        assert_eq!(
+            std::fs::read(path.join("meta.json"))?,
+            storage.atomic_read(Path::new("meta.json"))?
+        );
+        std::fs::remove_dir_all(path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn imports_an_index_from_another_directory() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{directory::RamDirectory, doc, schema, Index};
+
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT | schema::STORED);
+        let schema = schema_builder.build();
+
+        let create_source = |titles: &[&str]| -> tantivy::Result<RamDirectory> {
+            let directory = RamDirectory::create();
+            let index = Index::create(directory.clone(), schema.clone(), Default::default())?;
+            let mut index_writer = index.writer(15_000_000)?;
+            for t in titles {
+                index_writer.add_document(doc!(title => *t))?;
+            }
+            index_writer.commit()?;
+            Ok(directory)
+        };
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+        let storage = TantivySqliteStorage::new(pool)?;
+
+        let source = create_source(&["The Old Man and the Sea", "Of Mice and Men"])?;
+        storage.import_from(&source, &ImportOptions::default())?;
+        assert_eq!(
+            Index::open(storage.clone())?
+                .reader()?
+                .searcher()
+                .num_docs(),
+            2
+        );
+        assert!(storage.verify()?.is_ok());
+
+        let replacement = create_source(&["Frankenstein"])?;
+        assert!(matches!(
+            storage.import_from(&replacement, &ImportOptions::default()),
+            Err(TantivySqliteStorageError::FileAlreadyExists(_))
+        ));
+        storage.import_from(&replacement, &ImportOptions { replace: true })?;
+        assert_eq!(
+            Index::open(storage.clone())?
+                .reader()?
+                .searcher()
+                .num_docs(),
+            1
+        );
+
+        let report = storage.verify()?;
+        assert!(report.is_ok());
+        assert!(report.orphaned.is_empty());
+
+        // Round trip through the file system
+        let path = std::env::temp_dir().join(format!("tantivy-import-{}", Uuid::new_v4()));
+        storage.export_to_path(&path, |_| {})?;
+
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+        let imported =
+            TantivySqliteStorage::with_layout(pool, StorageLayout::Chunked { chunk_size: 64 })?;
+        imported.import_from_path(&path, &ImportOptions::default())?;
+        std::fs::remove_dir_all(path)?;
+
+        assert_eq!(Index::open(imported)?.reader()?.searcher().num_docs(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn backs_up_and_restores_the_database() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{doc, schema, Index, ReloadPolicy};
+
+        let path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+        let backup_path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+
+        let pool = Pool::builder().build(SqliteConnectionManager::file(&path))?;
+        let storage = TantivySqliteStorage::new(pool)?;
+
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommit)
+            .try_into()?;
+
+        let mut index_writer = index.writer_with_num_threads(1, 15_000_000)?;
+        index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+        index_writer.commit()?;
+
+        let mut updates = vec![];
+        storage.backup_to(&backup_path, |progress| updates.push(progress))?;
+        let last = *updates.last().unwrap();
+        assert!(last.total_pages > 0);
+        assert_eq!(last.pages_copied, last.total_pages);
+
+        index_writer.add_document(doc!(title => "Frankenstein"))?;
+        index_writer.commit()?;
+
+        assert!(matches!(
+            storage.restore_from(&backup_path, |_| {}),
+            Err(TantivySqliteStorageError::IndexLocked)
+        ));
+        drop(index_writer);
+
+        reader.reload()?;
+        assert_eq!(reader.searcher().num_docs(), 2);
+
+        storage.restore_from(&backup_path, |_| {})?;
+
+        let mut num_docs = 0;
+        for _ in 0..100 {
+            num_docs = reader.searcher().num_docs();
+            if num_docs == 1 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(num_docs, 1);
+        assert!(storage.verify()?.is_ok());
+
+        drop(reader);
+        std::fs::remove_file(path)?;
+        std::fs::remove_file(backup_path)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "vtab")]
+    #[test]
+    fn search_results_can_be_joined_in_sql() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{doc, schema, Index};
+
+        // Not an in-memory database, since those use a shared cache
+        let path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+        let pool = Pool::builder().build(SqliteConnectionManager::file(&path))?;
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+
+        let mut schema_builder = schema::Schema::builder();
+        let id = schema_builder.add_u64_field("id", schema::STORED);
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage, schema_builder.build())?;
+
+        let conn = pool.get()?;
+        conn.execute_batch(
+            "CREATE TABLE articles (id INTEGER PRIMARY KEY, author TEXT);
+             INSERT INTO articles VALUES (1, 'Hemingway'), (2, 'Steinbeck'), (3, 'Shelley');",
+        )?;
+
+        let mut index_writer = index.writer(15_000_000)?;
+        index_writer.add_document(doc!(id => 1u64, title => "The Old Man and the Sea"))?;
+        index_writer.add_document(doc!(id => 2u64, title => "Of Mice and Men"))?;
+        index_writer.add_document(doc!(id => 3u64, title => "Frankenstein"))?;
+        index_writer.commit()?;
+
+        register_search_function(&conn, "tantivy_search", &index, vec![title])?;
+
+        let authors = |query: &str| -> rusqlite::Result<Vec<String>> {
+            let mut statement = conn.prepare(
+                "SELECT articles.author FROM tantivy_search(?) AS s
+                 JOIN articles ON articles.id = json_extract(s.doc, '$.id[0]')
+                 ORDER BY s.score DESC",
+            )?;
+            let authors = statement.query_map([query], |row| row.get(0))?.collect();
+            authors
+        };
+
+        assert_eq!(authors("men OR sea")?.len(), 2);
+        assert_eq!(authors("mice")?, vec!["Steinbeck".to_string()]);
+        assert!(authors("title:(")
+            .unwrap_err()
+            .to_string()
+            .contains("Syntax"));
+
+        let num_results: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM tantivy_search('men OR sea OR frankenstein', 1)",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(num_results, 1);
+
+        drop(conn);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn sync_applies_table_changes_exactly_once() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{collector::TopDocs, query::QueryParser, schema, Index};
+
+        let pool = Pool::builder().build(in_memory_connection_manager())?;
+        let conn = pool.get()?;
+        conn.execute_batch(
+            "CREATE TABLE articles (id INTEGER PRIMARY KEY, title TEXT, author TEXT);
+             INSERT INTO articles VALUES (1, 'Of Mice and Men', 'Steinbeck'), (2, 'Frankenstein', 'Shelley');",
+        )?;
+
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+        let mut schema_builder = schema::Schema::builder();
+        let id = schema_builder.add_i64_field("id", schema::INDEXED | schema::STORED);
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+        let reader = index.reader()?;
+
+        let tables = || vec![SyncTable::new("articles", "id", id).column("title", title)];
+        let mut sync =
+            TantivySqliteSync::new(storage.clone(), index.writer(15_000_000)?, tables())?;
+
+        let search = |query: &str| -> Result<Vec<i64>, Box<dyn std::error::Error>> {
+            reader.reload()?;
+            let searcher = reader.searcher();
+            let query = QueryParser::for_index(&index, vec![title]).parse_query(query)?;
+
+            let mut ids = searcher
+                .search(&query, &TopDocs::with_limit(10))?
+                .into_iter()
+                .map(|(_, address)| {
+                    Ok(searcher
+                        .doc(address)?
+                        .get_first(id)
+                        .unwrap()
+                        .as_i64()
+                        .unwrap())
+                })
+                .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+            ids.sort();
+            Ok(ids)
+        };
+
+        // The rows which were there before the triggers were installed
+        assert_eq!(sync.sync()?, 2);
+        assert_eq!(search("mice OR frankenstein")?, vec![1, 2]);
+
+        conn.execute_batch(
+            "INSERT INTO articles VALUES (3, 'The Old Man and the Sea', 'Hemingway');
+             UPDATE articles SET title = 'The Modern Prometheus' WHERE id = 2;
+             UPDATE articles SET author = 'Mary Shelley' WHERE id = 2;
+             DELETE FROM articles WHERE id = 1;",
+        )?;
+        assert_eq!(sync.sync()?, 3);
+        assert_eq!(search("mice OR frankenstein")?, Vec::<i64>::new());
+        assert_eq!(search("prometheus OR sea")?, vec![2, 3]);
+        assert_eq!(sync.sync()?, 0);
+
+        // A new sync carries on from the last applied change rather than starting again
+        drop(sync);
+        conn.execute("INSERT INTO articles VALUES (4, 'Dracula', 'Stoker')", [])?;
+        let mut sync = TantivySqliteSync::new(storage, index.writer(15_000_000)?, tables())?;
+        assert_eq!(sync.sync()?, 1);
+        reader.reload()?;
+        assert_eq!(reader.searcher().num_docs(), 3);
+        drop(sync);
+
+        // Dropping the index's tables must take the triggers with them
+        let storage =
+            TantivySqliteStorage::with_namespace(pool.clone(), "other", StorageLayout::SingleBlob)?;
+        let index = Index::open_or_create(storage.clone(), index.schema())?;
+        TantivySqliteSync::new(storage, index.writer(15_000_000)?, tables())?;
+        TantivySqliteStorage::drop_namespace(&pool, "other")?;
+        conn.execute("DELETE FROM articles WHERE id = 4", [])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_worker_applies_changes_in_the_background() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{schema, Index};
+
+        let pool = Pool::builder().build(in_memory_connection_manager())?;
+        let conn = pool.get()?;
+        conn.execute(
+            "CREATE TABLE articles (id INTEGER PRIMARY KEY, title TEXT)",
+            [],
+        )?;
+
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+        let mut schema_builder = schema::Schema::builder();
+        let id = schema_builder.add_i64_field("id", schema::INDEXED);
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+        let reader = index.reader()?;
+
+        let worker = TantivySqliteSync::new(
+            storage,
+            index.writer(15_000_000)?,
+            vec![SyncTable::new("articles", "id", id).column("title", title)],
+        )?
+        .spawn(Duration::from_millis(10))?;
+
+        conn.execute(
+            "INSERT INTO articles (title) VALUES ('Of Mice and Men')",
+            [],
+        )?;
+
+        let start = std::time::Instant::now();
+        while reader.searcher().num_docs() == 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "the change was never applied: {:?}",
+                worker.take_error()
+            );
+            std::thread::sleep(Duration::from_millis(10));
+            reader.reload()?;
+        }
+
+        let mut sync = worker.stop();
+        assert_eq!(sync.sync()?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sync_worker_reports_failed_syncs() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{schema, Index};
+
+        let pool = Pool::builder().build(in_memory_connection_manager())?;
+        let conn = pool.get()?;
+        conn.execute(
+            "CREATE TABLE articles (id INTEGER PRIMARY KEY, title TEXT)",
+            [],
+        )?;
+
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+        let mut schema_builder = schema::Schema::builder();
+        let id = schema_builder.add_i64_field("id", schema::INDEXED);
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+        let reader = index.reader()?;
+
+        let worker = TantivySqliteSync::new(
+            storage,
+            index.writer(15_000_000)?,
+            vec![SyncTable::new("articles", "id", id).column("title", title)],
+        )?
+        .spawn(Duration::from_millis(10))?;
+
+        // The change can't be applied until the table is renamed back
+        conn.execute_batch(
+            "ALTER TABLE articles RENAME TO old_articles;
+            INSERT INTO old_articles (title) VALUES ('Of Mice and Men');",
+        )?;
+
+        let start = std::time::Instant::now();
+        let error = loop {
+            if let Some(error) = worker.take_error() {
+                break error;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert!(matches!(error, TantivySqliteStorageError::Sqlite(_)));
+
+        conn.execute("ALTER TABLE old_articles RENAME TO articles", [])?;
+        while reader.searcher().num_docs() == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+            reader.reload()?;
+        }
+        assert!(worker.take_error().is_none());
+
+        drop(worker);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_replaces_its_index_writer_after_a_failed_transaction(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{schema, Index};
+
+        let pool = Pool::builder().build(in_memory_connection_manager())?;
+        let conn = pool.get()?;
+        conn.execute(
+            "CREATE TABLE articles (id INTEGER PRIMARY KEY, title TEXT)",
+            [],
+        )?;
+
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+        let mut schema_builder = schema::Schema::builder();
+        let id = schema_builder.add_i64_field("id", schema::INDEXED);
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+        let reader = index.reader()?;
+
+        let mut sync = TantivySqliteSync::new(
+            storage.clone(),
+            index.writer(15_000_000)?,
+            vec![SyncTable::new("articles", "id", id).column("title", title)],
+        )?;
+
+        // Fails recording the last applied change, after tantivy has committed
+        conn.execute_batch(
+            "INSERT INTO articles (title) VALUES ('Of Mice and Men');
+             CREATE TRIGGER fail_sync BEFORE INSERT ON tantivy_storage_meta
+             WHEN NEW.key = 'sync_last_change_id' BEGIN SELECT RAISE(ABORT, 'injected failure'); END;",
+        )?;
+        assert!(matches!(
+            sync.sync(),
+            Err(TantivySqliteStorageError::SyncNeedsIndexWriter(Some(_)))
+        ));
+        assert!(matches!(
+            sync.sync(),
+            Err(TantivySqliteStorageError::SyncNeedsIndexWriter(None))
+        ));
+        reader.reload()?;
+        assert_eq!(reader.searcher().num_docs(), 0);
+
+        conn.execute("DROP TRIGGER fail_sync", [])?;
+        sync.set_index_writer(index.writer(15_000_000)?);
+        assert_eq!(sync.sync()?, 1);
+
+        conn.execute(
+            "INSERT INTO articles (title) VALUES ('The Old Man and the Sea')",
+            [],
+        )?;
+        assert_eq!(sync.sync()?, 1);
+        reader.reload()?;
+        assert_eq!(reader.searcher().num_docs(), 2);
+        assert!(storage.verify()?.is_ok());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_storage_runs_operations_and_waits_for_commits(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{doc, schema, Index, ReloadPolicy};
+
+        let pool = Pool::builder().build(in_memory_connection_manager())?;
+        let storage = AsyncTantivySqliteStorage::new(TantivySqliteStorage::new(pool)?);
+
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.storage().clone(), schema_builder.build())?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        let watcher = storage.watch().await?;
+
+        let writer_index = index.clone();
+        tokio::task::spawn_blocking(move || -> tantivy::Result<()> {
+            let mut index_writer = writer_index.writer_with_num_threads(1, 15_000_000)?;
+            index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+            index_writer.commit()?;
+            Ok(())
+        })
+        .await??;
+
+        tokio::time::timeout(Duration::from_secs(10), watcher.changed()).await?;
+        storage.reload_reader(&reader).await?;
+        assert_eq!(reader.searcher().num_docs(), 1);
+
+        assert!(storage.verify().await?.is_ok());
+        assert!(!storage.list_files().await?.is_empty());
+
+        let target = tantivy::directory::RamDirectory::create();
+        storage.export_to(target.clone(), |_| {}).await?;
+        assert_eq!(Index::open(target)?.reader()?.searcher().num_docs(), 1);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn cancelled_operations_stop_part_way() -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{doc, schema, Index};
+
+        let pool = Pool::builder().build(in_memory_connection_manager())?;
+        let storage = TantivySqliteStorage::new(pool)?;
+
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+        let mut index_writer = index.writer_with_num_threads(1, 15_000_000)?;
+        index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+        index_writer.commit()?;
+
+        let cancellation = Cancellation::default();
+        cancellation.cancel();
+
+        assert!(matches!(
+            verify::verify(&storage, &cancellation),
+            Err(TantivySqliteStorageError::Cancelled)
+        ));
+
+        let path = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&path)?;
+        assert!(matches!(
+            export::export(
+                &storage,
+                ExportTarget::Path(&path),
+                &mut |_| {},
+                &cancellation
+            ),
+            Err(TantivySqliteStorageError::Cancelled)
+        ));
+        assert!(!path.join("meta.json").exists());
+        std::fs::remove_dir_all(path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn single_connection_storage_works_with_a_private_in_memory_database(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use rusqlite::Connection;
+        use tantivy::{collector::Count, doc, query::QueryParser, schema, Index};
+
+        let connections =
+            SingleConnection::new(Connection::open_in_memory()?).timeout(Duration::from_secs(5));
+        let storage = TantivySqliteStorage::builder(connections)
+            .watch_interval(Some(Duration::from_millis(10)))
+            .build()?;
+
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+        let reader = index.reader()?;
+        let mut index_writer = index.writer(15_000_000)?;
+
+        let transaction = storage.begin_transaction()?;
+        transaction.connection().execute_batch(
+            "CREATE TABLE articles (title TEXT NOT NULL);
+             INSERT INTO articles VALUES ('Of Mice and Men');",
+        )?;
+        index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+        index_writer.commit()?;
+        // The watcher mustn't wait for the connection the transaction is holding
+        std::thread::sleep(Duration::from_millis(50));
+        transaction.commit()?;
+
+        reader.reload()?;
+        let query = QueryParser::for_index(&index, vec![title]).parse_query("mice")?;
+        assert_eq!(reader.searcher().search(&query, &Count)?, 1);
+        assert!(storage.verify()?.is_ok());
+
+        let transaction = storage.begin_transaction()?;
+        let count: i64 =
+            transaction
+                .connection()
+                .query_row("SELECT COUNT(*) FROM articles", [], |row| row.get(0))?;
+        assert_eq!(count, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_write_connections_keep_reading_while_the_writer_is_busy(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use rusqlite::{Connection, OpenFlags};
+        use tantivy::{doc, schema, Index};
+
+        let path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+        let writer = Connection::open(&path)?;
+        let reader = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let connections =
+            ReadWriteConnections::new(reader, writer).timeout(Duration::from_millis(100));
+
+        let storage = TantivySqliteStorage::new(connections.clone())?;
+        let mut schema_builder = schema::Schema::builder();
+        let title = schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+
+        let mut index_writer = index.writer_with_num_threads(1, 15_000_000)?;
+        index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+        index_writer.commit()?;
+        drop(index_writer);
+        // Reloading takes tantivy's meta lock, which is written to the database
+        let reader = index.reader()?;
+
+        let busy_writer = connections.writer()?;
+        assert_eq!(reader.searcher().num_docs(), 1);
+        assert!(storage.verify()?.is_ok());
+        assert!(matches!(
+            storage
+                .inner
+                .write()
+                .atomic_write(Path::new("notes.txt"), b"written"),
+            Err(TantivySqliteStorageError::ConnectionTimeout)
+        ));
+
+        drop(busy_writer);
+        storage
+            .inner
+            .write()
+            .atomic_write(Path::new("notes.txt"), b"written")?;
+        assert_eq!(storage.atomic_read(Path::new("notes.txt"))?, b"written");
+
+        drop((reader, index, storage, connections));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn connection_settings_are_applied_once_per_connection(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(in_memory_connection_manager())?;
+        let storage = TantivySqliteStorage::builder(pool.clone())
+            .synchronous(Synchronous::Off)
+            .build()?;
+
+        let synchronous = |conn: &rusqlite::Connection| -> rusqlite::Result<i64> {
+            conn.query_row("PRAGMA synchronous", [], |row| row.get(0))
+        };
+        assert_eq!(synchronous(&*pool.get()?)?, 0);
+
+        // Changed behind the storage's back, which it doesn't notice since it doesn't set it again
+        pool.get()?.pragma_update(None, "synchronous", "FULL")?;
+        storage.exists(Path::new("meta.json"))?;
+        assert_eq!(synchronous(&*pool.get()?)?, 2);
+
+        let connections = SingleConnection::new(rusqlite::Connection::open_in_memory()?);
+        let storage = TantivySqliteStorage::builder(connections.clone())
+            .synchronous(Synchronous::Off)
+            .build()?;
+        connections
+            .reader()?
+            .pragma_update(None, "synchronous", "FULL")?;
+        storage.exists(Path::new("meta.json"))?;
+        assert_eq!(synchronous(&*connections.reader()?)?, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn restoring_doesnt_bring_back_locks_held_during_the_backup(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use tantivy::{schema, Index};
+
+        let path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+        let backup_path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+
+        let pool = Pool::builder().build(SqliteConnectionManager::file(&path))?;
+        let storage = TantivySqliteStorage::new(pool)?;
+
+        let mut schema_builder = schema::Schema::builder();
+        schema_builder.add_text_field("title", schema::TEXT);
+        let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+
+        let index_writer = index.writer_with_num_threads(1, 15_000_000)?;
+        storage.backup_to(&backup_path, |_| {})?;
+        drop(index_writer);
+
+        storage.restore_from(&backup_path, |_| {})?;
+
+        // The lock held by the index writer during the backup would otherwise last for its lease
+        drop(index.writer_with_num_threads(1, 15_000_000)?);
+
+        drop((index, storage));
+        std::fs::remove_file(path)?;
+        std::fs::remove_file(backup_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn backups_dont_hold_the_reader() -> Result<(), Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+        let backup_path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+
+        let connections = SingleConnection::new(rusqlite::Connection::open(&path)?)
+            .timeout(Duration::from_millis(100));
+        let storage = TantivySqliteStorage::new(connections)?;
+        storage.atomic_write(Path::new("foo"), b"bar")?;
+
+        let mut reads = vec![];
+        storage.backup_to(&backup_path, |_| {
+            reads.push(storage.inner.read().reader().map(drop));
+        })?;
+        assert!(!reads.is_empty());
+        assert!(reads.iter().all(Result::is_ok));
+
+        drop(storage);
+        std::fs::remove_file(path)?;
+        std::fs::remove_file(backup_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn restoring_keeps_the_storages_locks() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+        let backup_path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+
+        let storage = TantivySqliteStorage::builder(pool.clone())
+            .lock_lease(Duration::from_millis(300))
+            .build()?;
+        // Big enough to take several steps to copy
+        storage.atomic_write(Path::new("foo"), &vec![1; 5 << 20])?;
+        storage.backup_to(&backup_path, |_| {})?;
+
+        let other_lock = storage.acquire_lock(&Lock {
+            filepath: PathBuf::from("other.lock"),
+            is_blocking: false,
+        })?;
+        let mut num_steps = 0;
+        storage.restore_from(&backup_path, |_| {
+            num_steps += 1;
+            std::thread::sleep(Duration::from_millis(100));
+        })?;
+        assert!(num_steps > 3);
+
+        assert!(!storage
+            .inner
+            .read()
+            .leases
+            .iter()
+            .any(|lease| lease.is_lost()));
+        assert!(matches!(
+            TantivySqliteStorage::new(pool)?.acquire_lock(&Lock {
+                filepath: PathBuf::from("other.lock"),
+                is_blocking: false,
+            }),
+            Err(error::LockError::LockBusy)
+        ));
+
+        drop(other_lock);
+        std::fs::remove_file(backup_path)?;
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn restoring_checks_the_encryption_key_first() -> Result<(), Box<dyn std::error::Error>> {
+        let old_key = EncryptionKey::generate();
+        let new_key = EncryptionKey::generate();
+        let backup_path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+        let other_backup_path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+
+        let open = |key: &EncryptionKey| -> Result<_, Box<dyn std::error::Error>> {
+            let pool = Pool::builder()
+                .max_size(4)
+                .build(in_memory_connection_manager())?;
+            Ok(TantivySqliteStorage::builder(pool)
+                .chunk_size(1024)
+                .encryption(key.clone())
+                .build()?)
+        };
+
+        let storage = open(&old_key)?;
+        storage.atomic_write(Path::new("foo"), b"old content")?;
+        storage.backup_to(&backup_path, |_| {})?;
+
+        storage.rewrap_encryption_key(&new_key)?;
+        storage.atomic_write(Path::new("foo"), b"new content")?;
+
+        assert!(matches!(
+            storage.restore_from(&backup_path, |_| {}),
+            Err(TantivySqliteStorageError::DecryptionFailed)
+        ));
+        assert_eq!(storage.atomic_read(Path::new("foo"))?, b"new content");
+
+        // Another index under the same key, which has a data key of its own
+        let other_storage = open(&new_key)?;
+        other_storage.atomic_write(Path::new("foo"), b"other content")?;
+        other_storage.backup_to(&other_backup_path, |_| {})?;
+
+        storage.restore_from(&other_backup_path, |_| {})?;
+        assert_eq!(storage.atomic_read(Path::new("foo"))?, b"other content");
+
+        std::fs::remove_file(backup_path)?;
+        std::fs::remove_file(other_backup_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn missing_chunks_are_reported_as_corrupt() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::with_layout(
+            pool.clone(),
+            StorageLayout::Chunked { chunk_size: 4 },
+        )?;
+        storage.atomic_write(Path::new("foo"), b"hello world")?;
+        storage.atomic_write(Path::new("bar"), b"goodbye world")?;
+        let file_handle = storage.get_file_handle(Path::new("foo"))?;
+
+        pool.get()?.execute(
+            "DELETE FROM tantivy_chunks WHERE chunk_index = 1 AND file_id = (SELECT id FROM tantivy_files WHERE filename = ?)",
+            [b"foo"],
+        )?;
+
+        assert_eq!(storage.verify()?.corrupt, vec![PathBuf::from("foo")]);
+
+        let error = file_handle.read_bytes(2..6).unwrap_err();
+        assert!(matches!(
+            error.get_ref().and_then(|e| e.downcast_ref()),
+            Some(TantivySqliteStorageError::CorruptFile(path)) if path == Path::new("foo")
+        ));
+        assert_eq!(&*file_handle.read_bytes(8..11)?, b"rld");
+
+        Ok(())
+    }
+
+    #[test]
+    fn catalog_only_caches_checked_content() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::with_layout(
+            pool.clone(),
+            StorageLayout::Chunked { chunk_size: 4 },
+        )?;
+        storage.atomic_write(Path::new("foo"), b"hello world")?;
+        storage.atomic_write(Path::new("bar"), b"goodbye world")?;
+
+        // A chunk past the end of the file, as left by a writer which hasn't flushed yet
+        pool.get()?.execute(
+            "INSERT INTO tantivy_chunks SELECT id, 3, CAST('junk' AS BLOB) FROM tantivy_files WHERE filename = ?",
+            [b"foo"],
+        )?;
+        storage.load_catalog(1024)?;
+        assert_eq!(&storage.atomic_read(Path::new("foo"))?, b"hello world");
+        assert_eq!(
+            &*storage
+                .get_file_handle(Path::new("foo"))?
+                .read_bytes(0..11)?,
+            b"hello world"
+        );
+
+        pool.get()?.execute(
+            "UPDATE tantivy_chunks SET content = CAST('hullo' AS BLOB) WHERE chunk_index = 1 AND file_id = (SELECT id FROM tantivy_files WHERE filename = ?)",
+            [b"bar"],
+        )?;
+        storage.load_catalog(1024)?;
+        assert!(matches!(
+            storage.inner.read().atomic_read(Path::new("bar")),
+            Err(TantivySqliteStorageError::CorruptFile(path)) if path == Path::new("bar")
+        ));
+        assert_eq!(&storage.atomic_read(Path::new("foo"))?, b"hello world");
+
+        let pool = Pool::builder()
+            .max_size(4)
+            .build(in_memory_connection_manager())?;
+        let storage = TantivySqliteStorage::new(pool.clone())?;
+        storage.atomic_write(Path::new("foo"), b"hello world")?;
+        pool.get()?.execute(
+            "UPDATE tantivy_blobs SET content = CAST('hello wurld' AS BLOB) WHERE filename = ?",
+            [b"foo"],
+        )?;
+        storage.load_catalog(1024)?;
+        assert!(matches!(
+            storage.inner.read().atomic_read(Path::new("foo")),
+            Err(TantivySqliteStorageError::CorruptFile(path)) if path == Path::new("foo")
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn layout_is_recorded_in_the_database() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::with_layout(
+            pool.clone(),
+            StorageLayout::Chunked { chunk_size: 16 },
+        )?;
+        storage.atomic_write(Path::new("foo"), b"hello world")?;
+
+        let reopened = TantivySqliteStorage::new(pool.clone())?;
+        assert_eq!(reopened.layout(), StorageLayout::Chunked { chunk_size: 16 });
+        assert_eq!(reopened.atomic_read(Path::new("foo"))?, b"hello world");
+
+        let resized = TantivySqliteStorage::with_layout(
+            pool.clone(),
+            StorageLayout::Chunked { chunk_size: 32 },
+        )?;
+        assert_eq!(resized.atomic_read(Path::new("foo"))?, b"hello world");
+
+        assert!(matches!(
+            TantivySqliteStorage::with_layout(pool, StorageLayout::SingleBlob),
+            Err(TantivySqliteStorageError::LayoutMismatch {
+                requested: StorageLayout::SingleBlob,
+                stored: StorageLayout::Chunked { chunk_size: 32 },
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn polling_for_changes_doesnt_read_meta_json() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::builder(pool.clone())
+            .watch_interval(None)
+            .build()?;
+        let other = TantivySqliteStorage::new(pool.clone())?;
+
+        storage.atomic_write(Path::new("meta.json"), b"first commit")?;
+
+        // Reading the content would find that it no longer matches its checksum
+        pool.get()?.execute(
+            "UPDATE tantivy_blobs SET content = zeroblob(length(content)) WHERE filename = ?",
+            [b"meta.json"],
+        )?;
+        TantivySqliteStorageInner::poll_external_changes(&storage.inner)?;
+        assert_eq!(
+            storage.inner.read().last_meta,
+            Some(MetaVersion::of(b"first commit"))
+        );
+
+        other.atomic_write(Path::new("meta.json"), b"second commit")?;
+        TantivySqliteStorageInner::poll_external_changes(&storage.inner)?;
+        assert_eq!(
+            storage.inner.read().last_meta,
+            Some(MetaVersion::of(b"second commit"))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn corrupt_compressed_chunks_are_reported_as_corrupt() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let manager = in_memory_connection_manager();
+        let pool = Pool::builder().max_size(4).build(manager)?;
+
+        let storage = TantivySqliteStorage::builder(pool.clone())
+            .chunk_size(1024)
+            .compression(CompressionConfig::default())
+            .build()?;
+        storage.atomic_write(Path::new("foo.idx"), &[7; 4096])?;
+        let file_handle = storage.get_file_handle(Path::new("foo.idx"))?;
+
+        // The size prefix of the second chunk now claims it is 4 GiB
+        pool.get()?.execute(
+            "UPDATE tantivy_chunks SET content = CAST(X'FFFFFFFF' || substr(content, 5) AS BLOB) WHERE chunk_index = 1",
+            [],
+        )?;
+
+        assert!(matches!(
+            storage.inner.read().atomic_read(Path::new("foo.idx")),
+            Err(TantivySqliteStorageError::CorruptFile(_))
+        ));
+        let error = file_handle.read_bytes(1000..1100).unwrap_err();
+        assert!(matches!(
+            error.get_ref().and_then(|e| e.downcast_ref()),
+            Some(TantivySqliteStorageError::CorruptFile(path)) if path == Path::new("foo.idx")
+        ));
+        assert_eq!(storage.verify()?.corrupt, vec![PathBuf::from("foo.idx")]);
+
+        Ok(())
+    }
 }
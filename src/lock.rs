@@ -0,0 +1,327 @@
+//! Implements tantivy's directory locks as rows in a lock table rather than as lock files. Each lock
+//! has a lease which is renewed by a background thread for as long as it is held, so a lock left
+//! behind by a crashed process expires instead of blocking writers forever. A writer whose lease
+//! ran out can't know what has happened to the index since, so its writes fail from then on.
+
+use std::{
+    os::unix::prelude::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use rusqlite::{params, types::Value, Connection, OptionalExtension};
+use tantivy::directory::{error::LockError, DirectoryLock, Lock, INDEX_WRITER_LOCK};
+
+use crate::{now_millis, TantivySqliteStorage, TantivySqliteStorageError};
+
+/// The default length of time a lock stays valid without being renewed.
+pub const DEFAULT_LOCK_LEASE: Duration = Duration::from_secs(30);
+
+/// Blocking locks are retried with the same policy as tantivy uses for its lock files.
+const BLOCKING_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+const BLOCKING_RETRIES: usize = 100;
+
+pub(crate) fn init(conn: &Connection, table: &str) -> Result<(), TantivySqliteStorageError> {
+    conn.execute(&format!("CREATE TABLE IF NOT EXISTS {table} (name TEXT PRIMARY KEY NOT NULL, owner TEXT NOT NULL, pid INTEGER NOT NULL, expires_at INTEGER NOT NULL)"), [])?;
+    Ok(())
+}
+
+/// Takes the lock if nobody else holds it or the previous holder's lease has expired.
+pub(crate) fn try_acquire(
+    conn: &Connection,
+    table: &str,
+    name: &Path,
+    owner: &str,
+    lease: Duration,
+) -> Result<bool, TantivySqliteStorageError> {
+    let now = now_millis();
+
+    let num_rows_modified = conn.execute(
+        &format!(
+            "INSERT INTO {table} VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (name) DO UPDATE SET owner = excluded.owner, pid = excluded.pid, expires_at = excluded.expires_at
+            WHERE owner = excluded.owner OR expires_at < ?5"
+        ),
+        params![
+            name.as_os_str().as_bytes(),
+            owner,
+            std::process::id(),
+            now + lease.as_millis() as i64,
+            now
+        ],
+    )?;
+
+    Ok(num_rows_modified == 1)
+}
+
+/// The owner of the lock, if someone holds it with a lease which hasn't expired.
+pub(crate) fn holder(
+    conn: &Connection,
+    table: &str,
+    name: &Path,
+) -> Result<Option<String>, TantivySqliteStorageError> {
+    Ok(conn
+        .query_row(
+            &format!("SELECT owner FROM {table} WHERE name = ? AND expires_at >= ?"),
+            params![name.as_os_str().as_bytes(), now_millis()],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+/// Extends the lease. Returns `false` if the lock has been taken over by someone else since it expired.
+pub(crate) fn renew(
+    conn: &Connection,
+    table: &str,
+    name: &Path,
+    owner: &str,
+    lease: Duration,
+) -> Result<bool, TantivySqliteStorageError> {
+    let num_rows_modified = conn.execute(
+        &format!("UPDATE {table} SET expires_at = ? WHERE name = ? AND owner = ?"),
+        params![
+            now_millis() + lease.as_millis() as i64,
+            name.as_os_str().as_bytes(),
+            owner
+        ],
+    )?;
+
+    Ok(num_rows_modified == 1)
+}
+
+pub(crate) fn release(
+    conn: &Connection,
+    table: &str,
+    name: &Path,
+    owner: &str,
+) -> Result<(), TantivySqliteStorageError> {
+    conn.execute(
+        &format!("DELETE FROM {table} WHERE name = ? AND owner = ?"),
+        params![name.as_os_str().as_bytes(), owner],
+    )?;
+
+    Ok(())
+}
+
+/// A row of the lock table, as read by [`held`].
+pub(crate) type HeldLock = (Value, String, i64, i64);
+
+/// Every lock in the table, held or expired.
+pub(crate) fn held(
+    conn: &Connection,
+    table: &str,
+) -> Result<Vec<HeldLock>, TantivySqliteStorageError> {
+    let mut statement =
+        conn.prepare(&format!("SELECT name, owner, pid, expires_at FROM {table}"))?;
+    let locks = statement
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(locks)
+}
+
+/// Replaces every lock in the table with `locks`. Used after a restore, since the locks copied from
+/// the backup were held by whoever was using the index when it was taken, and would otherwise block
+/// everyone until their leases expire.
+pub(crate) fn replace(
+    conn: &Connection,
+    table: &str,
+    locks: &[HeldLock],
+) -> Result<(), TantivySqliteStorageError> {
+    conn.execute(&format!("DELETE FROM {table}"), [])?;
+    for (name, owner, pid, expires_at) in locks {
+        conn.execute(
+            &format!("INSERT INTO {table} VALUES (?, ?, ?, ?)"),
+            params![name, owner, pid, expires_at],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Implements [`tantivy::Directory::acquire_lock`] for the storage.
+pub(crate) fn acquire(
+    storage: &TantivySqliteStorage,
+    lock: &Lock,
+) -> Result<DirectoryLock, LockError> {
+    let (read_only, lease) = {
+        let inner = storage.inner.read();
+        (inner.read_only, inner.lock_lease)
+    };
+
+    // Nothing can change a read-only index through this storage, so only the writer lock matters
+    if read_only {
+        if lock.filepath == INDEX_WRITER_LOCK.filepath {
+            return Err(LockError::IoError(
+                TantivySqliteStorageError::ReadOnly.into(),
+            ));
+        }
+
+        return Ok(DirectoryLock::from(Box::new(())));
+    }
+
+    let owner = new_owner_id();
+    let mut retries = if lock.is_blocking {
+        BLOCKING_RETRIES
+    } else {
+        0
+    };
+
+    loop {
+        let lease = storage
+            .inner
+            .write()
+            .try_acquire_lock(&lock.filepath, &owner, lease)
+            .map_err(|e| LockError::IoError(e.into()))?;
+
+        if let Some(lease) = lease {
+            let guard = LockGuard::new(storage.clone(), lease).map_err(LockError::IoError)?;
+            return Ok(DirectoryLock::from(Box::new(guard)));
+        }
+
+        if retries == 0 {
+            return Err(LockError::LockBusy);
+        }
+
+        retries -= 1;
+        thread::sleep(BLOCKING_RETRY_INTERVAL);
+    }
+}
+
+/// Takes the index writer lock without blocking, for maintenance which can't run alongside an index writer.
+pub(crate) fn acquire_writer_lock(
+    storage: &TantivySqliteStorage,
+) -> Result<DirectoryLock, TantivySqliteStorageError> {
+    acquire(storage, &INDEX_WRITER_LOCK).map_err(|e| match e {
+        LockError::LockBusy => TantivySqliteStorageError::IndexLocked,
+        LockError::IoError(e) => TantivySqliteStorageError::Io(e),
+    })
+}
+
+/// Identifies a single acquisition of a lock, so that only the holder can renew or release it.
+fn new_owner_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    format!(
+        "{}-{}-{}",
+        std::process::id(),
+        now_millis(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// A lock held through a storage, shared by its [`LockGuard`], the heartbeat renewing it and the
+/// storage itself, which stops writing once the index writer lock's lease may have run out.
+#[derive(Debug)]
+pub(crate) struct Lease {
+    pub(crate) name: PathBuf,
+    pub(crate) owner: String,
+    pub(crate) duration: Duration,
+    /// When the last successful renewal was started, which is when the lease was extended from.
+    renewed_at: Mutex<Instant>,
+    lost: AtomicBool,
+}
+
+impl Lease {
+    pub(crate) fn new(name: PathBuf, owner: String, duration: Duration, taken_at: Instant) -> Self {
+        Self {
+            name,
+            owner,
+            duration,
+            renewed_at: Mutex::new(taken_at),
+            lost: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn renewed(&self, renewed_at: Instant) {
+        *self.renewed_at.lock() = renewed_at;
+    }
+
+    pub(crate) fn set_lost(&self) {
+        self.lost.store(true, Ordering::Release);
+    }
+
+    /// Whether the lease has been lost, or is about to run out without having been renewed. A
+    /// quarter of the lease is kept back, so that this is noticed before anyone else can take the
+    /// lock over, without waiting for the heartbeat to wake up. Once lost, it stays lost.
+    pub(crate) fn is_lost(&self) -> bool {
+        if self.lost.load(Ordering::Acquire) {
+            return true;
+        }
+
+        if self.renewed_at.lock().elapsed() >= self.duration - self.duration / 4 {
+            self.set_lost();
+            return true;
+        }
+
+        false
+    }
+}
+
+/// Keeps the lease of a held lock renewed until it is dropped, and then releases the lock.
+struct LockGuard {
+    storage: TantivySqliteStorage,
+    lease: Arc<Lease>,
+    stop_heartbeat: Option<Sender<()>>,
+    heartbeat: Option<JoinHandle<()>>,
+}
+
+impl LockGuard {
+    /// Starts renewing a lock which has just been acquired. The lock is released again if that fails.
+    fn new(storage: TantivySqliteStorage, lease: Arc<Lease>) -> std::io::Result<Self> {
+        let (stop_heartbeat, stopped) = mpsc::channel::<()>();
+
+        let mut guard = Self {
+            storage,
+            lease,
+            stop_heartbeat: Some(stop_heartbeat),
+            heartbeat: None,
+        };
+
+        guard.heartbeat = Some({
+            let storage = guard.storage.clone();
+            let lease = guard.lease.clone();
+
+            thread::Builder::new()
+                .name("tantivy-sqlite-lock".into())
+                .spawn(move || {
+                    while let Err(RecvTimeoutError::Timeout) =
+                        stopped.recv_timeout(lease.duration / 3)
+                    {
+                        match storage.inner.read().renew_lock(&lease) {
+                            Ok(true) => {}
+                            // Failing to renew is fine as long as it succeeds before the lease runs out
+                            Err(_) if !lease.is_lost() => {}
+                            Ok(false) | Err(_) => {
+                                lease.set_lost();
+                                break;
+                            }
+                        }
+                    }
+                })?
+        });
+
+        Ok(guard)
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        drop(self.stop_heartbeat.take());
+        if let Some(heartbeat) = self.heartbeat.take() {
+            let _ = heartbeat.join();
+        }
+
+        // If this fails, the lock will expire once its lease runs out
+        let _ = self.storage.inner.write().release_lock(&self.lease);
+    }
+}
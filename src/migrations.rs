@@ -0,0 +1,252 @@
+//! Records which version of the storage format a database uses, and upgrades databases written by
+//! earlier versions of this crate.
+//!
+//! Every index has a `tantivy_storage_meta` table holding its format version. When the schema is
+//! created, any migrations newer than that version are applied in order, inside the same transaction
+//! as the rest of the schema setup. Migrations only change tables which already exist, since tables
+//! created afterwards get the latest schema straight away.
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    layout::Savepoint, namespace::TableNames, StorageLayout, TantivySqliteStorageError,
+    DEFAULT_CHUNK_SIZE,
+};
+
+/// Changes the schema from the previous version to `version`.
+struct Migration {
+    version: u32,
+    apply: fn(&Connection, &TableNames) -> Result<(), TantivySqliteStorageError>,
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    apply: add_file_metadata_columns,
+}];
+
+/// The version of the storage format written by this version of the crate.
+pub(crate) const CURRENT_VERSION: u32 = 1;
+
+const VERSION_KEY: &str = "format_version";
+const LAYOUT_KEY: &str = "layout";
+const CHUNK_SIZE_KEY: &str = "chunk_size";
+
+/// Creates the storage meta table if needed and brings the existing tables up to [`CURRENT_VERSION`].
+/// Fails with [`TantivySqliteStorageError::UnsupportedSchemaVersion`] if the database was written by a
+/// newer version of this crate.
+pub(crate) fn run(conn: &Connection, tables: &TableNames) -> Result<(), TantivySqliteStorageError> {
+    let transaction = Savepoint::new(conn)?;
+
+    transaction.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (key TEXT PRIMARY KEY NOT NULL, value)",
+            tables.storage_meta
+        ),
+        [],
+    )?;
+
+    let version = version(&transaction, tables)?.unwrap_or(0);
+    if version > CURRENT_VERSION {
+        return Err(TantivySqliteStorageError::UnsupportedSchemaVersion {
+            found: version,
+            supported: CURRENT_VERSION,
+        });
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        (migration.apply)(&transaction, tables)?;
+    }
+
+    transaction.execute(
+        &format!(
+            "INSERT OR REPLACE INTO {} VALUES (?, ?)",
+            tables.storage_meta
+        ),
+        params![VERSION_KEY, CURRENT_VERSION],
+    )?;
+
+    transaction.commit()
+}
+
+/// Checks that the database is already at [`CURRENT_VERSION`], for storages which don't create their
+/// schema. Read-only storages can also open databases written by earlier versions, as long as the
+/// tables already have every column in `read_columns`.
+pub(crate) fn check(
+    conn: &Connection,
+    tables: &TableNames,
+    read_only: bool,
+    read_columns: &[(&str, &[&str])],
+) -> Result<(), TantivySqliteStorageError> {
+    let version = if table_exists(conn, &tables.storage_meta)? {
+        version(conn, tables)?.unwrap_or(0)
+    } else {
+        0
+    };
+
+    if version > CURRENT_VERSION {
+        return Err(TantivySqliteStorageError::UnsupportedSchemaVersion {
+            found: version,
+            supported: CURRENT_VERSION,
+        });
+    }
+
+    if version < CURRENT_VERSION && !(read_only && has_columns(conn, read_columns)?) {
+        return Err(TantivySqliteStorageError::SchemaNeedsMigration {
+            found: version,
+            supported: CURRENT_VERSION,
+        });
+    }
+
+    Ok(())
+}
+
+/// Whether every table exists with all of the given columns.
+fn has_columns(
+    conn: &Connection,
+    tables: &[(&str, &[&str])],
+) -> Result<bool, TantivySqliteStorageError> {
+    for (table, columns) in tables {
+        for column in *columns {
+            if !column_exists(conn, table, column)? {
+                return Ok(false);
+            }
+        }
+    }
+
+    Ok(true)
+}
+
+fn version(
+    conn: &Connection,
+    tables: &TableNames,
+) -> Result<Option<u32>, TantivySqliteStorageError> {
+    meta_value(conn, tables, VERSION_KEY)
+}
+
+/// The layout the database was created with, or `None` if the schema hasn't been created yet.
+/// Databases created before the layout was recorded are taken to be chunked if they only have the
+/// chunked layout's tables.
+pub(crate) fn stored_layout(
+    conn: &Connection,
+    tables: &TableNames,
+) -> Result<Option<StorageLayout>, TantivySqliteStorageError> {
+    let layout: Option<String> = if table_exists(conn, &tables.storage_meta)? {
+        meta_value(conn, tables, LAYOUT_KEY)?
+    } else {
+        None
+    };
+
+    match layout.as_deref() {
+        Some("single_blob") => Ok(Some(StorageLayout::SingleBlob)),
+        Some("chunked") => Ok(Some(StorageLayout::Chunked {
+            chunk_size: meta_value(conn, tables, CHUNK_SIZE_KEY)?.unwrap_or(DEFAULT_CHUNK_SIZE),
+        })),
+        Some(layout) => Err(TantivySqliteStorageError::InvalidConfiguration(format!(
+            "the database uses an unknown layout {layout:?}"
+        ))),
+        None if table_exists(conn, &tables.blobs)? => Ok(Some(StorageLayout::SingleBlob)),
+        None if table_exists(conn, &tables.files)? => Ok(Some(StorageLayout::Chunked {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        })),
+        None => Ok(None),
+    }
+}
+
+/// Records the layout used to create the schema, so that it can be checked when the database is opened again.
+pub(crate) fn record_layout(
+    conn: &Connection,
+    tables: &TableNames,
+    layout: StorageLayout,
+) -> Result<(), TantivySqliteStorageError> {
+    let insert = format!(
+        "INSERT OR REPLACE INTO {} VALUES (?, ?)",
+        tables.storage_meta
+    );
+
+    match layout {
+        StorageLayout::SingleBlob => {
+            conn.execute(&insert, params![LAYOUT_KEY, "single_blob"])?;
+        }
+        StorageLayout::Chunked { chunk_size } => {
+            conn.execute(&insert, params![LAYOUT_KEY, "chunked"])?;
+            conn.execute(&insert, params![CHUNK_SIZE_KEY, chunk_size])?;
+        }
+    }
+
+    Ok(())
+}
+
+fn meta_value<T: rusqlite::types::FromSql>(
+    conn: &Connection,
+    tables: &TableNames,
+    key: &str,
+) -> Result<Option<T>, TantivySqliteStorageError> {
+    Ok(conn
+        .query_row(
+            &format!("SELECT value FROM {} WHERE key = ?", tables.storage_meta),
+            [key],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+pub(crate) fn table_exists(
+    conn: &Connection,
+    table: &str,
+) -> Result<bool, TantivySqliteStorageError> {
+    let exists: Option<i32> = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?",
+            [table],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(exists.is_some())
+}
+
+/// Whether `table` exists and has `column`.
+fn column_exists(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+) -> Result<bool, TantivySqliteStorageError> {
+    Ok(conn.query_row(
+        &format!("SELECT COUNT(*) > 0 FROM pragma_table_info('{table}') WHERE name = ?"),
+        [column],
+        |row| row.get(0),
+    )?)
+}
+
+/// Adds a column to a table created by an earlier version of this crate. Databases written before
+/// the format was versioned may already have some of the columns added by the first migration.
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), TantivySqliteStorageError> {
+    if !column_exists(conn, table, column)? {
+        conn.execute(
+            &format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"),
+            [],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Version 1 adds checksums and timestamps to the single blob layout's table. The chunked layout's
+/// tables were first created at version 1, so they never need migrating.
+fn add_file_metadata_columns(
+    conn: &Connection,
+    tables: &TableNames,
+) -> Result<(), TantivySqliteStorageError> {
+    if table_exists(conn, &tables.blobs)? {
+        for column in ["checksum", "created_at", "updated_at"] {
+            add_column_if_missing(conn, &tables.blobs, column, "INTEGER")?;
+        }
+    }
+
+    Ok(())
+}
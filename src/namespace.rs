@@ -0,0 +1,164 @@
+//! Allows multiple indexes to live in the same database by prefixing the names of their tables.
+
+use rusqlite::Connection;
+
+use crate::{migrations::table_exists, sync, TantivySqliteStorageError};
+
+/// Records which namespaces have been created, so they can be listed and dropped.
+const NAMESPACES_TABLE: &str = "tantivy_namespaces";
+
+/// The default start of every table name.
+pub(crate) const DEFAULT_TABLE_PREFIX: &str = "tantivy";
+
+/// The names of all the tables used by a single index.
+#[derive(Debug, Clone)]
+pub(crate) struct TableNames {
+    pub(crate) blobs: String,
+    pub(crate) blob_parts: String,
+    pub(crate) files: String,
+    pub(crate) chunks: String,
+    pub(crate) locks: String,
+    pub(crate) keys: String,
+    pub(crate) storage_meta: String,
+    /// The change log written by the triggers of [`TantivySqliteSync`](crate::TantivySqliteSync).
+    pub(crate) changes: String,
+}
+
+impl TableNames {
+    /// The tables for the index in `namespace`, or for the default index if `namespace` is `None`.
+    pub(crate) fn new(table_prefix: &str, namespace: Option<&str>) -> Self {
+        let prefix = match namespace {
+            Some(namespace) => format!("{namespace}_{table_prefix}"),
+            None => table_prefix.to_string(),
+        };
+
+        Self {
+            blobs: format!("{prefix}_blobs"),
+            blob_parts: format!("{prefix}_blob_parts"),
+            files: format!("{prefix}_files"),
+            chunks: format!("{prefix}_chunks"),
+            locks: format!("{prefix}_locks"),
+            keys: format!("{prefix}_keys"),
+            storage_meta: format!("{prefix}_storage_meta"),
+            changes: format!("{prefix}_changes"),
+        }
+    }
+
+    fn all(&self) -> [&str; 8] {
+        [
+            &self.blobs,
+            &self.blob_parts,
+            &self.files,
+            &self.chunks,
+            &self.locks,
+            &self.keys,
+            &self.storage_meta,
+            &self.changes,
+        ]
+    }
+}
+
+/// Namespaces end up in table names, so are restricted to characters which are valid in an
+/// unquoted sqlite identifier. They can't contain underscores either, since the namespace is
+/// separated from the table prefix by one, and `a` with the prefix `b_c` would otherwise share its
+/// tables with `a_b` and the prefix `c`.
+pub(crate) fn validate(namespace: &str) -> Result<(), TantivySqliteStorageError> {
+    if is_name_part(namespace) {
+        Ok(())
+    } else {
+        Err(TantivySqliteStorageError::InvalidNamespace(
+            namespace.to_string(),
+        ))
+    }
+}
+
+/// Table prefixes follow the same rule as namespaces, since the prefix `a_tantivy` would otherwise
+/// share its tables with the namespace `a` under the default prefix.
+pub(crate) fn validate_table_prefix(table_prefix: &str) -> Result<(), TantivySqliteStorageError> {
+    if is_name_part(table_prefix) {
+        Ok(())
+    } else {
+        Err(TantivySqliteStorageError::InvalidConfiguration(format!(
+            "table prefix {table_prefix:?} must only contain ascii letters and digits, and can't start with a digit"
+        )))
+    }
+}
+
+fn is_name_part(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric())
+}
+
+pub(crate) fn register(
+    conn: &Connection,
+    namespace: &str,
+    table_prefix: &str,
+) -> Result<(), TantivySqliteStorageError> {
+    conn.execute(
+        &format!("CREATE TABLE IF NOT EXISTS {NAMESPACES_TABLE} (name TEXT NOT NULL, table_prefix TEXT NOT NULL, PRIMARY KEY (name, table_prefix))"),
+        [],
+    )?;
+    conn.execute(
+        &format!("INSERT OR IGNORE INTO {NAMESPACES_TABLE} VALUES (?, ?)"),
+        [namespace, table_prefix],
+    )?;
+
+    Ok(())
+}
+
+pub(crate) fn list(conn: &Connection) -> Result<Vec<String>, TantivySqliteStorageError> {
+    if !table_exists(conn, NAMESPACES_TABLE)? {
+        return Ok(vec![]);
+    }
+
+    let mut statement = conn.prepare(&format!(
+        "SELECT DISTINCT name FROM {NAMESPACES_TABLE} ORDER BY name"
+    ))?;
+    let names = statement
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+
+    Ok(names)
+}
+
+/// Drops all the tables belonging to `namespace` in a single transaction, under every table prefix
+/// it has been created with.
+pub(crate) fn drop(conn: &Connection, namespace: &str) -> Result<(), TantivySqliteStorageError> {
+    let transaction = conn.unchecked_transaction()?;
+
+    let table_prefixes: Vec<String> = if table_exists(&transaction, NAMESPACES_TABLE)? {
+        let mut statement = transaction.prepare(&format!(
+            "SELECT table_prefix FROM {NAMESPACES_TABLE} WHERE name = ?"
+        ))?;
+        let table_prefixes = statement
+            .query_map([namespace], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        table_prefixes
+    } else {
+        vec![]
+    };
+
+    if table_prefixes.is_empty() {
+        return Err(TantivySqliteStorageError::NamespaceDoesNotExist(
+            namespace.to_string(),
+        ));
+    }
+
+    transaction.execute(
+        &format!("DELETE FROM {NAMESPACES_TABLE} WHERE name = ?"),
+        [namespace],
+    )?;
+
+    for table_prefix in table_prefixes {
+        let tables = TableNames::new(&table_prefix, Some(namespace));
+        sync::drop_triggers(&transaction, &tables)?;
+        for table in tables.all() {
+            transaction.execute(&format!("DROP TABLE IF EXISTS {table}"), [])?;
+        }
+    }
+
+    transaction.commit()?;
+    Ok(())
+}
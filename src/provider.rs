@@ -0,0 +1,302 @@
+//! Where a storage gets its sqlite connections from. A storage takes a connection for each
+//! operation and gives it back straight afterwards, except while a [`StorageTransaction`](crate::StorageTransaction)
+//! holds on to one until it finishes.
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::{Condvar, Mutex};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+
+use crate::{builder::ConnectionSettings, TantivySqliteStorageError};
+
+/// How long [`SingleConnection`] and [`ReadWriteConnections`] wait for their connection to be given
+/// back by default. The same as r2d2's default connection timeout.
+pub const DEFAULT_CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Hands out the connections used by a [`TantivySqliteStorage`](crate::TantivySqliteStorage).
+///
+/// Implemented for r2d2's [`Pool`], [`SingleConnection`] and [`ReadWriteConnections`]. To use another
+/// pool, implement [`ConnectionProvider::reader`] to take a connection from it and wrap it in a
+/// [`ProvidedConnection`] which gives it back when dropped.
+///
+/// Every connection must be to the same database. Providers handing out more than one connection at
+/// a time can't use a private in-memory database, since each connection would see a different one.
+pub trait ConnectionProvider: Send + Sync + 'static {
+    /// A connection which is only used to read.
+    fn reader(&self) -> Result<ProvidedConnection, TantivySqliteStorageError>;
+
+    /// A connection which is used to write, as well as for transactions. Defaults to
+    /// [`ConnectionProvider::reader`] for providers which don't tell the two apart.
+    fn writer(&self) -> Result<ProvidedConnection, TantivySqliteStorageError> {
+        self.reader()
+    }
+}
+
+/// A connection handed out by a [`ConnectionProvider`], which goes back to it when dropped.
+pub struct ProvidedConnection(Box<dyn ConnectionGuard>);
+
+impl ProvidedConnection {
+    /// Wraps anything which dereferences to a connection, such as a pool's guard. There is nowhere to
+    /// remember what has been done to the connection, so the storage's
+    /// [`journal_mode`](crate::TantivySqliteStorageBuilder::journal_mode) and
+    /// [`synchronous`](crate::TantivySqliteStorageBuilder::synchronous) pragmas are set each time it is
+    /// provided. Set them when opening the connection instead to avoid that.
+    pub fn new(conn: impl DerefMut<Target = Connection> + Send + 'static) -> Self {
+        Self(Box::new(Untracked(conn)))
+    }
+
+    /// Applies `settings` unless they were the last ones applied to the underlying connection.
+    pub(crate) fn configure(
+        &mut self,
+        settings: &ConnectionSettings,
+    ) -> Result<(), TantivySqliteStorageError> {
+        if self.0.applied_settings() == Some(settings) {
+            return Ok(());
+        }
+
+        settings.apply(self)?;
+        self.0.set_applied_settings(settings.clone());
+        Ok(())
+    }
+}
+
+impl Deref for ProvidedConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.0
+    }
+}
+
+impl DerefMut for ProvidedConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        &mut self.0
+    }
+}
+
+impl fmt::Debug for ProvidedConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ProvidedConnection").field(&**self).finish()
+    }
+}
+
+impl ConnectionProvider for Pool<SqliteConnectionManager> {
+    fn reader(&self) -> Result<ProvidedConnection, TantivySqliteStorageError> {
+        Ok(ProvidedConnection(Box::new(self.get()?)))
+    }
+}
+
+/// A guard for a connection which may remember the settings applied to the connection, so that
+/// they only need applying once for as long as the connection stays open.
+trait ConnectionGuard: DerefMut<Target = Connection> + Send {
+    fn applied_settings(&self) -> Option<&ConnectionSettings>;
+
+    fn set_applied_settings(&mut self, settings: ConnectionSettings);
+}
+
+/// A connection from a provider outside of this crate, which can't remember anything.
+struct Untracked<T>(T);
+
+impl<T: DerefMut<Target = Connection>> Deref for Untracked<T> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.0
+    }
+}
+
+impl<T: DerefMut<Target = Connection>> DerefMut for Untracked<T> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        &mut self.0
+    }
+}
+
+impl<T: DerefMut<Target = Connection> + Send> ConnectionGuard for Untracked<T> {
+    fn applied_settings(&self) -> Option<&ConnectionSettings> {
+        None
+    }
+
+    fn set_applied_settings(&mut self, _settings: ConnectionSettings) {}
+}
+
+/// Stored in the extensions of a pooled connection, which last as long as the connection itself.
+struct AppliedSettings(ConnectionSettings);
+
+impl ConnectionGuard for PooledConnection<SqliteConnectionManager> {
+    fn applied_settings(&self) -> Option<&ConnectionSettings> {
+        PooledConnection::extensions(self)
+            .get::<AppliedSettings>()
+            .map(|applied| &applied.0)
+    }
+
+    fn set_applied_settings(&mut self, settings: ConnectionSettings) {
+        PooledConnection::extensions_mut(self).insert(AppliedSettings(settings));
+    }
+}
+
+/// A single connection shared by everything using the storage, for applications which already
+/// own a [`Connection`] rather than a pool. Operations take turns with it, waiting up to the
+/// [`SingleConnection::timeout`] for it to be given back before failing with
+/// [`TantivySqliteStorageError::ConnectionTimeout`].
+///
+/// Since there is only one connection, a [`StorageTransaction`](crate::StorageTransaction) blocks
+/// everything else until it finishes, including tantivy's readers and the storage's watcher.
+/// This can also work with a private in-memory database, unlike providers with several connections.
+#[derive(Debug, Clone)]
+pub struct SingleConnection {
+    slot: Arc<ConnectionSlot>,
+}
+
+impl SingleConnection {
+    /// Shares `conn`, waiting up to [`DEFAULT_CONNECTION_TIMEOUT`] for it.
+    pub fn new(conn: Connection) -> Self {
+        Self {
+            slot: Arc::new(ConnectionSlot::new(conn, DEFAULT_CONNECTION_TIMEOUT)),
+        }
+    }
+
+    /// Changes how long operations wait for the connection.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.slot.set_timeout(timeout);
+        self
+    }
+}
+
+impl ConnectionProvider for SingleConnection {
+    fn reader(&self) -> Result<ProvidedConnection, TantivySqliteStorageError> {
+        self.slot.take()
+    }
+}
+
+/// A dedicated pair of connections, one which only reads and one which writes. Reads carry on
+/// while a write is in progress, which suits databases in WAL mode. Each connection is used by one
+/// operation at a time, which wait up to the [`ReadWriteConnections::timeout`] for their turn
+/// before failing with [`TantivySqliteStorageError::ConnectionTimeout`].
+///
+/// The reader can be opened with [`rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY`]. Both must be to
+/// the same database file, or to the same shared-cache in-memory database. Tantivy's locks are rows
+/// in the database, so reloading a reader still needs a turn with the writer.
+#[derive(Debug, Clone)]
+pub struct ReadWriteConnections {
+    reader: Arc<ConnectionSlot>,
+    writer: Arc<ConnectionSlot>,
+}
+
+impl ReadWriteConnections {
+    /// Uses `reader` for reading and `writer` for everything else, waiting up to
+    /// [`DEFAULT_CONNECTION_TIMEOUT`] for either of them.
+    pub fn new(reader: Connection, writer: Connection) -> Self {
+        Self {
+            reader: Arc::new(ConnectionSlot::new(reader, DEFAULT_CONNECTION_TIMEOUT)),
+            writer: Arc::new(ConnectionSlot::new(writer, DEFAULT_CONNECTION_TIMEOUT)),
+        }
+    }
+
+    /// Changes how long operations wait for a connection.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.reader.set_timeout(timeout);
+        self.writer.set_timeout(timeout);
+        self
+    }
+}
+
+impl ConnectionProvider for ReadWriteConnections {
+    fn reader(&self) -> Result<ProvidedConnection, TantivySqliteStorageError> {
+        self.reader.take()
+    }
+
+    fn writer(&self) -> Result<ProvidedConnection, TantivySqliteStorageError> {
+        self.writer.take()
+    }
+}
+
+/// A connection which is lent to one user at a time, along with the settings last applied to it.
+#[derive(Debug)]
+struct ConnectionSlot {
+    conn: Mutex<Option<(Connection, Option<ConnectionSettings>)>>,
+    returned: Condvar,
+    timeout: Mutex<Duration>,
+}
+
+impl ConnectionSlot {
+    fn new(conn: Connection, timeout: Duration) -> Self {
+        Self {
+            conn: Mutex::new(Some((conn, None))),
+            returned: Condvar::new(),
+            timeout: Mutex::new(timeout),
+        }
+    }
+
+    fn set_timeout(&self, timeout: Duration) {
+        *self.timeout.lock() = timeout;
+    }
+
+    fn take(self: &Arc<Self>) -> Result<ProvidedConnection, TantivySqliteStorageError> {
+        let deadline = Instant::now() + *self.timeout.lock();
+        let mut conn = self.conn.lock();
+
+        let (conn, applied) = loop {
+            if let Some(conn) = conn.take() {
+                break conn;
+            }
+            if self.returned.wait_until(&mut conn, deadline).timed_out() {
+                return Err(TantivySqliteStorageError::ConnectionTimeout);
+            }
+        };
+
+        Ok(ProvidedConnection(Box::new(LentConnection {
+            slot: self.clone(),
+            conn: Some(conn),
+            applied,
+        })))
+    }
+}
+
+/// Gives the connection back to its slot when dropped.
+struct LentConnection {
+    slot: Arc<ConnectionSlot>,
+    conn: Option<Connection>,
+    applied: Option<ConnectionSettings>,
+}
+
+impl Deref for LentConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+            .as_ref()
+            .expect("the connection is only taken when dropped")
+    }
+}
+
+impl DerefMut for LentConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn
+            .as_mut()
+            .expect("the connection is only taken when dropped")
+    }
+}
+
+impl ConnectionGuard for LentConnection {
+    fn applied_settings(&self) -> Option<&ConnectionSettings> {
+        self.applied.as_ref()
+    }
+
+    fn set_applied_settings(&mut self, settings: ConnectionSettings) {
+        self.applied = Some(settings);
+    }
+}
+
+impl Drop for LentConnection {
+    fn drop(&mut self) {
+        *self.slot.conn.lock() = self.conn.take().map(|conn| (conn, self.applied.take()));
+        self.slot.returned.notify_one();
+    }
+}
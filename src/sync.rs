@@ -0,0 +1,568 @@
+//! Keeps an index in step with application tables in the same database. Triggers on each table
+//! record which rows changed in a change log, and [`TantivySqliteSync`] reindexes those rows.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use rusqlite::{types::Value, Connection, OptionalExtension};
+use tantivy::{
+    schema::{Field, FieldType, Schema},
+    Document, IndexWriter, Term,
+};
+
+use crate::{
+    layout::Savepoint, namespace::TableNames, TantivySqliteStorage, TantivySqliteStorageError,
+};
+
+/// The most changes applied by a single index commit, so that syncing a large backlog doesn't
+/// hold the whole of it in memory.
+const SYNC_BATCH_SIZE: usize = 1000;
+
+/// The key in the storage meta table holding the id of the last change applied to the index.
+const LAST_CHANGE_KEY: &str = "sync_last_change_id";
+
+/// An application table kept in the index by [`TantivySqliteSync`], and the fields its columns are
+/// indexed into. Each row becomes a document, identified by its integer `key_column`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncTable {
+    table: String,
+    key_column: String,
+    key_field: Field,
+    columns: Vec<(String, Field)>,
+}
+
+impl SyncTable {
+    /// Indexes the rows of `table`, storing `key_column` in `key_field`, which must be an indexed
+    /// `i64` field. The key column is usually the table's `INTEGER PRIMARY KEY` or `rowid`.
+    pub fn new(table: impl Into<String>, key_column: impl Into<String>, key_field: Field) -> Self {
+        Self {
+            table: table.into(),
+            key_column: key_column.into(),
+            key_field,
+            columns: Vec::new(),
+        }
+    }
+
+    /// Also indexes `column` into `field`. `NULL`s are left out of the document, and other values
+    /// must fit the type of the field, except that anything can go in a text field.
+    pub fn column(mut self, column: impl Into<String>, field: Field) -> Self {
+        self.columns.push((column.into(), field));
+        self
+    }
+
+    fn validate(&self, schema: &Schema) -> Result<(), TantivySqliteStorageError> {
+        match schema.get_field_entry(self.key_field).field_type() {
+            FieldType::I64(options) if options.is_indexed() => Ok(()),
+            _ => Err(TantivySqliteStorageError::InvalidConfiguration(format!(
+                "the key field of {} must be an indexed i64 field",
+                self.table
+            ))),
+        }
+    }
+
+    /// The columns which make a difference to the document, so that updates to any other column
+    /// don't need reindexing.
+    fn watched_columns(&self) -> String {
+        std::iter::once(&self.key_column)
+            .chain(self.columns.iter().map(|(column, _)| column))
+            .map(|column| quote_identifier(column))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn trigger(&self, tables: &TableNames, event: &str) -> String {
+        quote_identifier(&format!("{}_{}_{event}", tables.changes, self.table))
+    }
+
+    /// The document for the row with `key` as it is now, or `None` if it has been deleted.
+    fn document(
+        &self,
+        conn: &Connection,
+        schema: &Schema,
+        key: i64,
+    ) -> Result<Option<Document>, TantivySqliteStorageError> {
+        let columns = if self.columns.is_empty() {
+            "NULL".to_string()
+        } else {
+            self.columns
+                .iter()
+                .map(|(column, _)| quote_identifier(column))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        let values: Option<Vec<Value>> = conn
+            .query_row(
+                &format!(
+                    "SELECT {columns} FROM {} WHERE {} = ?",
+                    quote_identifier(&self.table),
+                    quote_identifier(&self.key_column)
+                ),
+                [key],
+                |row| (0..self.columns.len()).map(|i| row.get(i)).collect(),
+            )
+            .optional()?;
+
+        let Some(values) = values else {
+            return Ok(None);
+        };
+
+        let mut document = Document::new();
+        document.add_i64(self.key_field, key);
+
+        for ((column, field), value) in self.columns.iter().zip(values) {
+            let field_type = schema.get_field_entry(*field).field_type();
+
+            match (field_type, value) {
+                (_, Value::Null) => {}
+                (FieldType::Str(_), Value::Text(text)) => document.add_text(*field, text),
+                (FieldType::Str(_), Value::Integer(integer)) => {
+                    document.add_text(*field, integer.to_string())
+                }
+                (FieldType::Str(_), Value::Real(real)) => {
+                    document.add_text(*field, real.to_string())
+                }
+                (FieldType::Str(_), Value::Blob(blob)) => {
+                    document.add_text(*field, String::from_utf8_lossy(&blob))
+                }
+                (FieldType::I64(_), Value::Integer(integer)) => document.add_i64(*field, integer),
+                (FieldType::U64(_), Value::Integer(integer)) if integer >= 0 => {
+                    document.add_u64(*field, integer as u64)
+                }
+                (FieldType::F64(_), Value::Integer(integer)) => {
+                    document.add_f64(*field, integer as f64)
+                }
+                (FieldType::F64(_), Value::Real(real)) => document.add_f64(*field, real),
+                (FieldType::Bytes(_), Value::Blob(blob)) => document.add_bytes(*field, blob),
+                (_, value) => {
+                    return Err(TantivySqliteStorageError::InvalidConfiguration(format!(
+                        "{:?} in column {column} of {} can't be indexed into field {}",
+                        value.data_type(),
+                        self.table,
+                        schema.get_field_name(*field)
+                    )))
+                }
+            }
+        }
+
+        Ok(Some(document))
+    }
+}
+
+/// Keeps an index up to date with changes to application tables in the same database.
+///
+/// Creating a sync installs triggers on each [`SyncTable`], which record the key of every inserted,
+/// updated or deleted row in the `tantivy_changes` table. [`TantivySqliteSync::sync`] then reindexes
+/// those rows from their current content and commits. The id of the last change applied is stored
+/// in the same sqlite transaction as the index commit, so each change is applied exactly once even if
+/// the process stops part way through. Rows already in a table when its triggers are first installed
+/// are recorded as well, so the first sync indexes them.
+///
+/// ```
+/// # use r2d2::Pool;
+/// # use r2d2_sqlite::SqliteConnectionManager;
+/// use tantivy::{collector::Count, query::QueryParser, schema::{Schema, INDEXED, TEXT}, Index};
+/// use tantivy_sqlite_storage::{SyncTable, TantivySqliteStorage, TantivySqliteSync};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let connection_manager = SqliteConnectionManager::file("file:tantivy-sync-example?mode=memory&cache=shared");
+/// # let pool = Pool::builder().max_size(4).build(connection_manager)?;
+/// let conn = pool.get()?;
+/// conn.execute("CREATE TABLE articles (id INTEGER PRIMARY KEY, title TEXT)", [])?;
+///
+/// let storage = TantivySqliteStorage::new(pool.clone())?;
+/// let mut schema_builder = Schema::builder();
+/// let id = schema_builder.add_i64_field("id", INDEXED);
+/// let title = schema_builder.add_text_field("title", TEXT);
+/// let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+///
+/// let mut sync = TantivySqliteSync::new(
+///     storage,
+///     index.writer(15_000_000)?,
+///     vec![SyncTable::new("articles", "id", id).column("title", title)],
+/// )?;
+///
+/// conn.execute("INSERT INTO articles (title) VALUES ('Of Mice and Men')", [])?;
+/// assert_eq!(sync.sync()?, 1);
+///
+/// let query = QueryParser::for_index(&index, vec![title]).parse_query("mice")?;
+/// assert_eq!(index.reader()?.searcher().search(&query, &Count)?, 1);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Use [`TantivySqliteSync::spawn`] to sync in the background instead. Only one sync should run for
+/// each index, and its index writer shouldn't be used for anything else, since the sync may roll it
+/// back when applying changes fails.
+pub struct TantivySqliteSync {
+    storage: TantivySqliteStorage,
+    /// `None` once dropped because it no longer matches the index, see [`TantivySqliteStorageError::SyncNeedsIndexWriter`].
+    index_writer: Option<IndexWriter>,
+    tables: Vec<SyncTable>,
+}
+
+impl fmt::Debug for TantivySqliteSync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TantivySqliteSync")
+            .field("storage", &self.storage)
+            .field("tables", &self.tables)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TantivySqliteSync {
+    /// Installs the triggers for `tables`, replacing any installed for them before, and creates the
+    /// change log if it doesn't exist. `index_writer` must write to an index stored in `storage`.
+    pub fn new(
+        storage: TantivySqliteStorage,
+        index_writer: IndexWriter,
+        tables: Vec<SyncTable>,
+    ) -> Result<Self, TantivySqliteStorageError> {
+        let schema = index_writer.index().schema();
+        for table in &tables {
+            table.validate(&schema)?;
+        }
+
+        let mut key_fields = HashSet::new();
+        if !tables
+            .iter()
+            .all(|table| key_fields.insert(table.key_field))
+        {
+            return Err(TantivySqliteStorageError::InvalidConfiguration(
+                "every synced table needs its own key field".into(),
+            ));
+        }
+
+        {
+            let inner = storage.inner.read();
+            inner.check_writable()?;
+            let conn = inner.writer()?;
+            install(&conn, &inner.tables, &tables)?;
+        }
+
+        Ok(Self {
+            storage,
+            index_writer: Some(index_writer),
+            tables,
+        })
+    }
+
+    /// Applies every change recorded so far and commits the index, returning how many changes were
+    /// applied. Nothing is committed if there are no changes.
+    ///
+    /// If this fails, the index writer is rolled back so that the changes are applied again by the
+    /// next sync. The exception is if the index commit succeeded but the sqlite transaction holding it
+    /// didn't, since tantivy can't roll back a commit it believes has happened. The index writer is
+    /// dropped instead, and this fails with [`TantivySqliteStorageError::SyncNeedsIndexWriter`] until
+    /// it is replaced with [`TantivySqliteSync::set_index_writer`].
+    pub fn sync(&mut self) -> Result<usize, TantivySqliteStorageError> {
+        let mut applied = 0;
+
+        loop {
+            let batch = self.apply_batch().inspect_err(|_| {
+                if let Some(index_writer) = &mut self.index_writer {
+                    let _ = index_writer.rollback();
+                }
+            })?;
+            applied += batch;
+
+            if batch < SYNC_BATCH_SIZE {
+                return Ok(applied);
+            }
+        }
+    }
+
+    /// Applies up to [`SYNC_BATCH_SIZE`] changes in a single index commit.
+    fn apply_batch(&mut self) -> Result<usize, TantivySqliteStorageError> {
+        let index_writer = self
+            .index_writer
+            .as_mut()
+            .ok_or(TantivySqliteStorageError::SyncNeedsIndexWriter(None))?;
+        let tables = self.storage.inner.read().tables.clone();
+        let schema = index_writer.index().schema();
+
+        let transaction = self.storage.begin_transaction()?;
+
+        let (changes, documents) = {
+            let conn = transaction.connection();
+            let last_change = last_change(&conn, &tables)?;
+
+            let mut statement = conn.prepare(&format!(
+                "SELECT id, table_name, row_key FROM {} WHERE id > ? ORDER BY id LIMIT ?",
+                tables.changes
+            ))?;
+            let changes: Vec<(i64, String, i64)> = statement
+                .query_map((last_change, SYNC_BATCH_SIZE), |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })?
+                .collect::<Result<_, _>>()?;
+
+            // Every change to a row is applied by reindexing it as it is now, so once is enough
+            let mut seen = HashSet::new();
+            let mut documents = Vec::new();
+            for (_, table_name, key) in &changes {
+                // Changes to tables which are no longer synced are skipped
+                let Some(table) = self.tables.iter().find(|t| &t.table == table_name) else {
+                    continue;
+                };
+                if !seen.insert((table_name, *key)) {
+                    continue;
+                }
+
+                documents.push((table, *key, table.document(&conn, &schema, *key)?));
+            }
+
+            (changes, documents)
+        };
+
+        let Some((last_applied, _, _)) = changes.last() else {
+            return Ok(0);
+        };
+
+        // The transaction's connection must be free before tantivy writes through it
+        for (table, key, document) in documents {
+            index_writer.delete_term(Term::from_field_i64(table.key_field, key));
+            if let Some(document) = document {
+                index_writer
+                    .add_document(document)
+                    .map_err(|e| TantivySqliteStorageError::InvalidIndex(e.to_string()))?;
+            }
+        }
+        index_writer
+            .commit()
+            .map_err(|e| TantivySqliteStorageError::InvalidIndex(e.to_string()))?;
+
+        let committed = (|| {
+            {
+                let conn = transaction.connection();
+                conn.execute(
+                    &format!(
+                        "INSERT OR REPLACE INTO {} VALUES (?, ?)",
+                        tables.storage_meta
+                    ),
+                    (LAST_CHANGE_KEY, last_applied),
+                )?;
+                conn.execute(
+                    &format!("DELETE FROM {} WHERE id <= ?", tables.changes),
+                    [last_applied],
+                )?;
+            }
+
+            transaction.commit()
+        })();
+
+        if let Err(e) = committed {
+            // Its next commit could refer to segments which were rolled back along with the transaction
+            self.index_writer = None;
+            return Err(TantivySqliteStorageError::SyncNeedsIndexWriter(Some(
+                Box::new(e),
+            )));
+        }
+
+        Ok(changes.len())
+    }
+
+    /// Syncs every `interval` on a background thread until the returned worker is stopped or dropped.
+    /// Failed syncs, for example because the database is busy, are retried at the next interval,
+    /// except that [`TantivySqliteStorageError::SyncNeedsIndexWriter`] keeps failing until the worker
+    /// is stopped and the sync given a new index writer. The error of a failed sync can be seen with
+    /// [`SyncWorker::take_error`].
+    pub fn spawn(self, interval: Duration) -> std::io::Result<SyncWorker> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let error = Arc::new(Mutex::new(None));
+
+        let mut sync = self;
+        let thread = thread::Builder::new()
+            .name("tantivy-sqlite-sync".into())
+            .spawn({
+                let error = error.clone();
+                move || {
+                    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                        *error.lock() = sync.sync().err();
+                    }
+                    sync
+                }
+            })?;
+
+        Ok(SyncWorker {
+            stop: Some(stop),
+            thread: Some(thread),
+            error,
+        })
+    }
+
+    /// Replaces the index writer, for example after [`TantivySqliteStorageError::SyncNeedsIndexWriter`].
+    /// The old one must have been dropped before the new one could be created, since only one index
+    /// writer can hold the index's lock.
+    pub fn set_index_writer(&mut self, index_writer: IndexWriter) {
+        self.index_writer = Some(index_writer);
+    }
+
+    /// Gives back the index writer, unless it was dropped because it no longer matched the index.
+    pub fn into_index_writer(self) -> Option<IndexWriter> {
+        self.index_writer
+    }
+}
+
+/// A [`TantivySqliteSync`] running on a background thread, started by [`TantivySqliteSync::spawn`].
+/// It stops when this is dropped.
+#[derive(Debug)]
+pub struct SyncWorker {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<TantivySqliteSync>>,
+    /// The error of the last sync, cleared when a sync succeeds.
+    error: Arc<Mutex<Option<TantivySqliteStorageError>>>,
+}
+
+impl SyncWorker {
+    /// Takes the error of the last sync, if it failed. Returns `None` once a later sync has
+    /// succeeded, or if the error has already been taken.
+    pub fn take_error(&self) -> Option<TantivySqliteStorageError> {
+        self.error.lock().take()
+    }
+
+    /// Stops the background thread, waiting for a sync in progress to finish, and gives back the sync.
+    pub fn stop(mut self) -> TantivySqliteSync {
+        drop(self.stop.take());
+
+        let thread = self
+            .thread
+            .take()
+            .expect("the thread is only taken when stopping");
+        match thread.join() {
+            Ok(sync) => sync,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+impl Drop for SyncWorker {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn last_change(conn: &Connection, tables: &TableNames) -> Result<i64, TantivySqliteStorageError> {
+    let last_change: Option<i64> = conn
+        .query_row(
+            &format!("SELECT value FROM {} WHERE key = ?", tables.storage_meta),
+            [LAST_CHANGE_KEY],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    Ok(last_change.unwrap_or(0))
+}
+
+fn install(
+    conn: &Connection,
+    tables: &TableNames,
+    synced: &[SyncTable],
+) -> Result<(), TantivySqliteStorageError> {
+    let transaction = Savepoint::new(conn)?;
+
+    // Autoincrement stops the ids of applied changes, which are deleted, from being reused
+    transaction.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                table_name TEXT NOT NULL,
+                row_key INTEGER NOT NULL
+            )",
+            tables.changes
+        ),
+        [],
+    )?;
+
+    for table in synced {
+        let insert_trigger = table.trigger(tables, "insert");
+        let first_install: Option<i32> = transaction
+            .query_row(
+                "SELECT 1 FROM sqlite_master WHERE type = 'trigger' AND name = ?",
+                [format!("{}_{}_insert", tables.changes, table.table)],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let name = quote_literal(&table.table);
+        let table_name = quote_identifier(&table.table);
+        let key = quote_identifier(&table.key_column);
+        let changes = &tables.changes;
+
+        transaction.execute_batch(&format!(
+            "DROP TRIGGER IF EXISTS {insert_trigger};
+             CREATE TRIGGER {insert_trigger} AFTER INSERT ON {table_name} BEGIN
+                 INSERT INTO {changes} (table_name, row_key) VALUES ({name}, NEW.{key});
+             END;
+
+             DROP TRIGGER IF EXISTS {update_trigger};
+             CREATE TRIGGER {update_trigger} AFTER UPDATE OF {watched} ON {table_name} BEGIN
+                 INSERT INTO {changes} (table_name, row_key) VALUES ({name}, OLD.{key});
+                 INSERT INTO {changes} (table_name, row_key)
+                     SELECT {name}, NEW.{key} WHERE NEW.{key} IS NOT OLD.{key};
+             END;
+
+             DROP TRIGGER IF EXISTS {delete_trigger};
+             CREATE TRIGGER {delete_trigger} AFTER DELETE ON {table_name} BEGIN
+                 INSERT INTO {changes} (table_name, row_key) VALUES ({name}, OLD.{key});
+             END;",
+            update_trigger = table.trigger(tables, "update"),
+            delete_trigger = table.trigger(tables, "delete"),
+            watched = table.watched_columns(),
+        ))?;
+
+        if first_install.is_none() {
+            transaction.execute(
+                &format!(
+                    "INSERT INTO {changes} (table_name, row_key) SELECT {name}, {key} FROM {table_name}"
+                ),
+                [],
+            )?;
+        }
+    }
+
+    transaction.commit()
+}
+
+/// Drops the triggers which record changes into the change log of `tables`, since they would fail
+/// once the change log is gone.
+pub(crate) fn drop_triggers(
+    conn: &Connection,
+    tables: &TableNames,
+) -> Result<(), TantivySqliteStorageError> {
+    let prefix = format!("{}_", tables.changes);
+
+    let mut statement = conn.prepare(
+        "SELECT name FROM sqlite_master WHERE type = 'trigger' AND substr(name, 1, ?) = ?",
+    )?;
+    let triggers: Vec<String> = statement
+        .query_map((prefix.len(), &prefix), |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+
+    for trigger in triggers {
+        conn.execute(&format!("DROP TRIGGER {}", quote_identifier(&trigger)), [])?;
+    }
+
+    Ok(())
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+fn quote_literal(literal: &str) -> String {
+    format!("'{}'", literal.replace('\'', "''"))
+}
@@ -0,0 +1,119 @@
+//! Lets tantivy's writes share a sqlite transaction with the application's own changes.
+
+use std::{fmt, ops::Deref, sync::Arc};
+
+use parking_lot::{ArcMutexGuard, Mutex, MutexGuard, RawMutex};
+use rusqlite::Connection;
+
+use crate::{ProvidedConnection, TantivySqliteStorage, TantivySqliteStorageError};
+
+/// The connection which holds an open transaction, shared between the storage and the [`StorageTransaction`].
+pub(crate) type SharedConnection = Arc<Mutex<ProvidedConnection>>;
+
+/// The connection used for a single operation. Either a fresh one from the connection provider, or
+/// the connection of the transaction in progress.
+pub(crate) enum StorageConnection {
+    Provided(ProvidedConnection),
+    Transaction(ArcMutexGuard<RawMutex, ProvidedConnection>),
+}
+
+impl Deref for StorageConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            StorageConnection::Provided(conn) => conn,
+            StorageConnection::Transaction(conn) => conn,
+        }
+    }
+}
+
+/// A sqlite transaction which every change made through a [`TantivySqliteStorage`] goes into,
+/// until it is committed or rolled back. Started with [`TantivySqliteStorage::begin_transaction`].
+///
+/// Make your own changes using [`StorageTransaction::connection`], and commit the index writer
+/// before committing the transaction. Rolling back then undoes both your changes and the index commit.
+/// Dropping the transaction without committing it rolls it back.
+///
+/// ```
+/// # use r2d2::Pool;
+/// # use r2d2_sqlite::SqliteConnectionManager;
+/// use tantivy::{doc, schema::{Schema, TEXT}, Index};
+/// use tantivy_sqlite_storage::TantivySqliteStorage;
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let connection_manager = SqliteConnectionManager::file("file:tantivy-transaction-example?mode=memory&cache=shared");
+/// # let pool = Pool::builder().max_size(4).build(connection_manager)?;
+/// # pool.get()?.execute("CREATE TABLE articles (title TEXT)", [])?;
+/// let storage = TantivySqliteStorage::new(pool)?;
+/// # let mut schema_builder = Schema::builder();
+/// # let title = schema_builder.add_text_field("title", TEXT);
+/// let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+/// let mut index_writer = index.writer(15_000_000)?;
+///
+/// let transaction = storage.begin_transaction()?;
+/// transaction
+///     .connection()
+///     .execute("INSERT INTO articles VALUES ('Of Mice and Men')", [])?;
+/// index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+/// index_writer.commit()?;
+/// transaction.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// While the transaction is open, tantivy's reads and writes from every thread are serialised through
+/// its connection. Writes made by the index writer before the transaction started, such as segments
+/// flushed by the indexing threads, are committed straight away, but aren't referenced by the index
+/// until `meta.json` is written. Tantivy's locks are kept out of the transaction, so rolling it
+/// back doesn't release them.
+///
+/// After a rollback, the [`tantivy::IndexWriter`] still believes its commit succeeded, so should be
+/// dropped and recreated.
+pub struct StorageTransaction {
+    storage: TantivySqliteStorage,
+    conn: SharedConnection,
+    finished: bool,
+}
+
+impl fmt::Debug for StorageTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StorageTransaction")
+    }
+}
+
+impl StorageTransaction {
+    pub(crate) fn new(storage: TantivySqliteStorage, conn: SharedConnection) -> Self {
+        Self {
+            storage,
+            conn,
+            finished: false,
+        }
+    }
+
+    /// The connection holding the transaction. The storage can't be used while this is borrowed,
+    /// so drop it before calling into tantivy.
+    pub fn connection(&self) -> impl Deref<Target = Connection> + '_ {
+        MutexGuard::map(self.conn.lock(), |conn| -> &mut Connection { conn })
+    }
+
+    /// Commits your changes along with everything written to the index since the transaction started.
+    pub fn commit(mut self) -> Result<(), TantivySqliteStorageError> {
+        self.finished = true;
+        self.storage.inner.write().finish_transaction(true)
+    }
+
+    /// Undoes your changes along with everything written to the index since the transaction started.
+    pub fn rollback(mut self) -> Result<(), TantivySqliteStorageError> {
+        self.finished = true;
+        self.storage.inner.write().finish_transaction(false)
+    }
+}
+
+impl Drop for StorageTransaction {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.storage.inner.write().finish_transaction(false);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Checks the stored files against their checksums and against the segments listed in `meta.json`.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use tantivy::{Index, SegmentMeta};
+
+use crate::{
+    cancel::Cancellation, TantivySqliteStorage, TantivySqliteStorageError, MANAGED_FILES, META_FILE,
+};
+
+/// Files which tantivy keeps alongside the segments, so aren't orphans even though no segment refers to them.
+pub(crate) const INDEX_FILES: [&str; 2] = [META_FILE, MANAGED_FILES];
+
+/// The problems found by [`TantivySqliteStorage::verify`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Files which don't match their checksum, are shorter than their recorded length, or fail to decrypt or decompress.
+    pub corrupt: Vec<PathBuf>,
+    /// Files which a segment in `meta.json` needs, but which aren't stored.
+    pub missing: Vec<PathBuf>,
+    /// Stored files which no segment in `meta.json` refers to. These are usually left behind by an
+    /// index writer which crashed, but also include the files of a commit which is still in progress.
+    pub orphaned: Vec<PathBuf>,
+    /// Unencrypted files written by an earlier version of this crate, which don't have a checksum to
+    /// compare against. Encrypted files are checked by decrypting them instead, and files which are
+    /// still being written aren't checked until they are finished.
+    pub unchecked: Vec<PathBuf>,
+}
+
+impl VerifyReport {
+    /// Whether the index can be opened and read without errors, which is the case if no files are
+    /// corrupt or missing. Orphaned files take up space, but are otherwise harmless.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty() && self.missing.is_empty()
+    }
+}
+
+pub(crate) fn verify(
+    storage: &TantivySqliteStorage,
+    cancellation: &Cancellation,
+) -> Result<VerifyReport, TantivySqliteStorageError> {
+    let mut report = VerifyReport::default();
+
+    let referenced = referenced_files(storage)?;
+    let stored = storage.inner.read().stored_files()?;
+
+    // Each file gets its own read lock, so writers aren't blocked for the whole scan
+    for file in &stored {
+        cancellation.check()?;
+
+        // Its checksum is only recorded once its writer finishes it
+        if file.handle.unfinished {
+            continue;
+        }
+        if file.corrupt {
+            report.corrupt.push(file.path.clone());
+            continue;
+        }
+
+        match storage.inner.read().check_stored_file(&file.path) {
+            Ok(_) if file.handle.checksum.is_none() && !file.handle.encrypted => {
+                report.unchecked.push(file.path.clone())
+            }
+            Ok(_) | Err(TantivySqliteStorageError::FileDoesNotExist(_)) => {}
+            Err(
+                TantivySqliteStorageError::CorruptFile(_)
+                | TantivySqliteStorageError::DecryptionFailed
+                | TantivySqliteStorageError::InvalidCompressedData(_),
+            ) => report.corrupt.push(file.path.clone()),
+            Err(e) => return Err(e),
+        }
+    }
+
+    if let Some(referenced) = referenced {
+        let stored_paths: HashSet<&Path> = stored.iter().map(|file| file.path.as_path()).collect();
+
+        report.missing = referenced
+            .iter()
+            .filter(|path| !stored_paths.contains(path.as_path()))
+            .cloned()
+            .collect();
+        report.orphaned = stored_paths
+            .into_iter()
+            .filter(|path| !referenced.contains(*path) && !INDEX_FILES.iter().any(|f| path == f))
+            .map(Path::to_path_buf)
+            .collect();
+    }
+
+    report.corrupt.sort();
+    report.missing.sort();
+    report.orphaned.sort();
+    report.unchecked.sort();
+
+    Ok(report)
+}
+
+/// The files needed by the segments in `meta.json`, or `None` if there is no index yet.
+pub(crate) fn referenced_files(
+    storage: &TantivySqliteStorage,
+) -> Result<Option<HashSet<PathBuf>>, TantivySqliteStorageError> {
+    if !storage.inner.read().exists(Path::new(META_FILE))? {
+        return Ok(None);
+    }
+
+    let segments = Index::open(storage.clone())
+        .and_then(|index| index.searchable_segment_metas())
+        .map_err(|e| TantivySqliteStorageError::InvalidIndex(e.to_string()))?;
+
+    Ok(Some(segment_files(&segments).collect()))
+}
+
+/// The files which make up `segments`.
+pub(crate) fn segment_files(segments: &[SegmentMeta]) -> impl Iterator<Item = PathBuf> + '_ {
+    segments.iter().flat_map(|segment| {
+        // `list_files` names a delete file even for segments which don't have any deletes
+        let has_deletes = segment.has_deletes();
+        segment
+            .list_files()
+            .into_iter()
+            .filter(move |path| has_deletes || path.extension() != Some("del".as_ref()))
+    })
+}
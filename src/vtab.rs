@@ -0,0 +1,288 @@
+//! Makes an index searchable from SQL through a table-valued function, so that search results can
+//! be joined with the application's own tables.
+
+use std::os::raw::c_int;
+
+use rusqlite::{
+    ffi,
+    vtab::{
+        eponymous_only_module, Context, IndexConstraintOp, IndexInfo, VTab, VTabConnection,
+        VTabCursor, Values,
+    },
+    Connection,
+};
+use tantivy::{
+    collector::TopDocs, query::QueryParser, schema::Field, DocAddress, Index, IndexReader,
+    ReloadPolicy,
+};
+
+use crate::TantivySqliteStorageError;
+
+/// The number of results returned when the limit argument is left out.
+pub const DEFAULT_SEARCH_LIMIT: usize = 10;
+
+const COLUMN_QUERY: c_int = 4;
+const COLUMN_LIMIT: c_int = 5;
+
+/// Bits of the plan chosen by `best_index`, saying which arguments are passed to `filter`.
+const PLAN_QUERY: c_int = 1;
+const PLAN_LIMIT: c_int = 2;
+
+/// Registers a table-valued function called `name` on `conn`, which searches `index` for a query
+/// in tantivy's query language. Terms which don't name a field are searched for in `default_fields`.
+///
+/// The function takes the query and optionally the maximum number of results, which defaults to
+/// [`DEFAULT_SEARCH_LIMIT`], and returns the best matches first with the columns:
+///
+/// * `segment_ord` and `doc_id`, which make up the document's [`DocAddress`]
+/// * `score`
+/// * `doc`, the document's stored fields as JSON, in the same format as [`tantivy::schema::Schema::to_json`]
+///
+/// ```
+/// # use r2d2::Pool;
+/// # use r2d2_sqlite::SqliteConnectionManager;
+/// use tantivy::{doc, schema::{Schema, STORED, TEXT}, Index};
+/// use tantivy_sqlite_storage::{register_search_function, TantivySqliteStorage};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let path = std::env::temp_dir().join("tantivy-vtab-example.sqlite");
+/// # let _ = std::fs::remove_file(&path);
+/// # let pool = Pool::builder().max_size(4).build(SqliteConnectionManager::file(&path))?;
+/// let storage = TantivySqliteStorage::new(pool.clone())?;
+/// let mut schema_builder = Schema::builder();
+/// let title = schema_builder.add_text_field("title", TEXT | STORED);
+/// let index = Index::open_or_create(storage, schema_builder.build())?;
+/// # let mut index_writer = index.writer(15_000_000)?;
+/// # index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+/// # index_writer.commit()?;
+///
+/// let conn = pool.get()?;
+/// register_search_function(&conn, "tantivy_search", &index, vec![title])?;
+///
+/// let found: String = conn.query_row(
+///     "SELECT json_extract(doc, '$.title[0]') FROM tantivy_search('mice', 5)",
+///     [],
+///     |row| row.get(0),
+/// )?;
+/// assert_eq!(found, "Of Mice and Men");
+/// # drop(conn);
+/// # std::fs::remove_file(&path)?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The search reads the index through its own connections, so a pool shared with `conn` needs room
+/// for at least one more connection. This deadlocks with a shared cache, including in-memory
+/// databases opened with `mode=memory&cache=shared`, since sqlite holds the cache's mutex while the
+/// function runs. New commits are picked up as they are made.
+pub fn register_search_function(
+    conn: &Connection,
+    name: &str,
+    index: &Index,
+    default_fields: Vec<Field>,
+) -> Result<(), TantivySqliteStorageError> {
+    let reader = index
+        .reader_builder()
+        .reload_policy(ReloadPolicy::OnCommit)
+        .try_into()
+        .map_err(|e: tantivy::TantivyError| {
+            TantivySqliteStorageError::InvalidIndex(e.to_string())
+        })?;
+
+    let source = SearchSource {
+        index: index.clone(),
+        reader,
+        default_fields,
+    };
+
+    conn.create_module(name, eponymous_only_module::<SearchTab>(), Some(source))?;
+    Ok(())
+}
+
+/// Everything a search needs, handed to each connection of the virtual table.
+#[derive(Clone)]
+struct SearchSource {
+    index: Index,
+    reader: IndexReader,
+    default_fields: Vec<Field>,
+}
+
+#[repr(C)]
+struct SearchTab {
+    /// Must come first, since sqlite treats a pointer to this struct as a pointer to its base.
+    base: ffi::sqlite3_vtab,
+    source: SearchSource,
+}
+
+unsafe impl<'vtab> VTab<'vtab> for SearchTab {
+    type Aux = SearchSource;
+    type Cursor = SearchCursor<'vtab>;
+
+    fn connect(
+        _db: &mut VTabConnection,
+        aux: Option<&SearchSource>,
+        _args: &[&[u8]],
+    ) -> rusqlite::Result<(String, Self)> {
+        let source = aux
+            .cloned()
+            .ok_or_else(|| rusqlite::Error::ModuleError("missing index".into()))?;
+
+        Ok((
+            "CREATE TABLE x(segment_ord, doc_id, score, doc, query HIDDEN, \"limit\" HIDDEN)"
+                .to_owned(),
+            SearchTab {
+                base: ffi::sqlite3_vtab::default(),
+                source,
+            },
+        ))
+    }
+
+    fn best_index(&self, info: &mut IndexInfo) -> rusqlite::Result<()> {
+        let mut query = None;
+        let mut limit = None;
+
+        for (i, constraint) in info.constraints().enumerate() {
+            if !constraint.is_usable()
+                || constraint.operator() != IndexConstraintOp::SQLITE_INDEX_CONSTRAINT_EQ
+            {
+                continue;
+            }
+
+            match constraint.column() {
+                COLUMN_QUERY => query = Some(i),
+                COLUMN_LIMIT => limit = Some(i),
+                _ => {}
+            }
+        }
+
+        let mut plan = 0;
+        let mut argv_index = 0;
+        for (constraint, bit) in [(query, PLAN_QUERY), (limit, PLAN_LIMIT)] {
+            if let Some(constraint) = constraint {
+                argv_index += 1;
+                plan |= bit;
+
+                let mut usage = info.constraint_usage(constraint);
+                usage.set_argv_index(argv_index);
+                usage.set_omit(true);
+            }
+        }
+
+        // Without a query there is nothing to search for, so steer the planner away from that plan
+        info.set_estimated_cost(if query.is_some() { 1.0 } else { f64::MAX });
+        info.set_idx_num(plan);
+        Ok(())
+    }
+
+    fn open(&'vtab mut self) -> rusqlite::Result<SearchCursor<'vtab>> {
+        Ok(SearchCursor {
+            base: ffi::sqlite3_vtab_cursor::default(),
+            source: &self.source,
+            results: Vec::new(),
+            position: 0,
+        })
+    }
+}
+
+struct SearchResult {
+    address: DocAddress,
+    score: f32,
+    doc: String,
+}
+
+#[repr(C)]
+struct SearchCursor<'vtab> {
+    /// Must come first, since sqlite treats a pointer to this struct as a pointer to its base.
+    base: ffi::sqlite3_vtab_cursor,
+    source: &'vtab SearchSource,
+    results: Vec<SearchResult>,
+    position: usize,
+}
+
+impl SearchCursor<'_> {
+    fn search(&self, query: &str, limit: usize) -> tantivy::Result<Vec<SearchResult>> {
+        let SearchSource {
+            index,
+            reader,
+            default_fields,
+        } = self.source;
+
+        // Tantivy's top docs collector can't collect nothing
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+
+        let query = QueryParser::for_index(index, default_fields.clone()).parse_query(query)?;
+        let searcher = reader.searcher();
+        let schema = index.schema();
+
+        searcher
+            .search(&query, &TopDocs::with_limit(limit))?
+            .into_iter()
+            .map(|(score, address)| {
+                Ok(SearchResult {
+                    address,
+                    score,
+                    doc: schema.to_json(&searcher.doc(address)?),
+                })
+            })
+            .collect()
+    }
+}
+
+unsafe impl VTabCursor for SearchCursor<'_> {
+    fn filter(
+        &mut self,
+        plan: c_int,
+        _idx_str: Option<&str>,
+        args: &Values<'_>,
+    ) -> rusqlite::Result<()> {
+        if plan & PLAN_QUERY == 0 {
+            return Err(rusqlite::Error::ModuleError(
+                "a query is needed to search the index".into(),
+            ));
+        }
+
+        let query: String = args.get(0)?;
+        let limit = if plan & PLAN_LIMIT != 0 {
+            let limit: i64 = args.get(1)?;
+            usize::try_from(limit)
+                .map_err(|_| rusqlite::Error::ModuleError(format!("invalid limit {limit}")))?
+        } else {
+            DEFAULT_SEARCH_LIMIT
+        };
+
+        self.results = self
+            .search(&query, limit)
+            .map_err(|e| rusqlite::Error::ModuleError(e.to_string()))?;
+        self.position = 0;
+
+        Ok(())
+    }
+
+    fn next(&mut self) -> rusqlite::Result<()> {
+        self.position += 1;
+        Ok(())
+    }
+
+    fn eof(&self) -> bool {
+        self.position >= self.results.len()
+    }
+
+    fn column(&self, ctx: &mut Context, i: c_int) -> rusqlite::Result<()> {
+        let result = &self.results[self.position];
+
+        match i {
+            0 => ctx.set_result(&result.address.segment_ord),
+            1 => ctx.set_result(&result.address.doc_id),
+            2 => ctx.set_result(&f64::from(result.score)),
+            3 => ctx.set_result(&result.doc),
+            // The hidden arguments aren't needed once the search has run
+            _ => ctx.set_result(&rusqlite::types::Null),
+        }
+    }
+
+    fn rowid(&self) -> rusqlite::Result<i64> {
+        Ok(self.position as i64)
+    }
+}
@@ -0,0 +1,81 @@
+//! Polls the database for commits made through other connections, such as by another process,
+//! so that `watch` callbacks fire for them as well as for commits made through this storage.
+
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Weak,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use parking_lot::RwLock;
+
+use crate::TantivySqliteStorageInner;
+
+/// The default time between checks for commits made elsewhere. The same as tantivy uses when watching `meta.json` on disk.
+pub const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Tells versions of `meta.json` apart without holding on to their content. Tantivy writes a new
+/// opstamp into every commit, so two versions with the same length and checksum are the same commit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MetaVersion {
+    pub(crate) length: usize,
+    pub(crate) checksum: u32,
+}
+
+impl MetaVersion {
+    pub(crate) fn of(meta: &[u8]) -> Self {
+        Self {
+            length: meta.len(),
+            checksum: crc32c::crc32c(meta),
+        }
+    }
+}
+
+/// The background thread doing the polling. It stops when this is dropped.
+pub(crate) struct Watcher {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watcher {
+    pub(crate) fn start(
+        inner: Weak<RwLock<TantivySqliteStorageInner>>,
+        interval: Duration,
+    ) -> std::io::Result<Self> {
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let thread = thread::Builder::new()
+            .name("tantivy-sqlite-watcher".into())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let Some(inner) = inner.upgrade() else {
+                        break;
+                    };
+
+                    // Errors such as the database being busy are retried on the next poll
+                    let _ = TantivySqliteStorageInner::poll_external_changes(&inner);
+                }
+            })?;
+
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+
+        if let Some(thread) = self.thread.take() {
+            // The storage can be dropped by the watcher thread itself, if it held the last reference while polling
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
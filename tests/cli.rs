@@ -0,0 +1,192 @@
+//! Runs the `tantivy-sqlite` command line tool against indexes written with the library.
+
+#![cfg(feature = "cli")]
+
+use std::{
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use assert_cmd::Command;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use tantivy::{doc, schema, Directory, Index};
+use tantivy_sqlite_storage::{
+    CompressionConfig, EncryptionKey, StorageLayout, TantivySqliteStorage,
+    TantivySqliteStorageBuilder,
+};
+use uuid::Uuid;
+
+/// A database and everything else the test writes, removed once the test is done with them.
+struct TestFiles {
+    database: PathBuf,
+    others: Vec<PathBuf>,
+}
+
+impl TestFiles {
+    fn new() -> Self {
+        Self {
+            database: std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4())),
+            others: vec![],
+        }
+    }
+
+    fn other(&mut self, extension: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}.{extension}", Uuid::new_v4()));
+        self.others.push(path.clone());
+        path
+    }
+
+    fn pool(&self) -> Result<Pool<SqliteConnectionManager>, r2d2::Error> {
+        Pool::builder().build(SqliteConnectionManager::file(&self.database))
+    }
+
+    fn tantivy_sqlite(&self) -> Command {
+        let mut command = Command::cargo_bin("tantivy-sqlite").expect("the cli is built");
+        command.arg(&self.database);
+        command
+    }
+}
+
+impl Drop for TestFiles {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.database);
+        for path in &self.others {
+            let _ = fs::remove_dir_all(path).or_else(|_| fs::remove_file(path));
+        }
+    }
+}
+
+fn write_index(builder: TantivySqliteStorageBuilder) -> Result<(), Box<dyn std::error::Error>> {
+    let mut schema_builder = schema::Schema::builder();
+    let title = schema_builder.add_text_field("title", schema::TEXT | schema::STORED);
+    let index = Index::open_or_create(builder.build()?, schema_builder.build())?;
+
+    let mut index_writer = index.writer_with_num_threads(1, 15_000_000)?;
+    index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+    index_writer.add_document(doc!(title => "The Old Man and the Sea"))?;
+    index_writer.commit()?;
+
+    Ok(())
+}
+
+fn stdout(command: &mut Command) -> String {
+    String::from_utf8(command.assert().success().get_output().stdout.clone())
+        .expect("the cli prints utf-8")
+}
+
+#[test]
+fn lists_verifies_and_exports_an_index() -> Result<(), Box<dyn std::error::Error>> {
+    let mut files = TestFiles::new();
+    write_index(TantivySqliteStorage::builder(files.pool()?))?;
+    let storage = TantivySqliteStorage::new(files.pool()?)?;
+
+    let listed = stdout(files.tantivy_sqlite().arg("ls"));
+    for file in storage.list_files()? {
+        assert!(listed.contains(&format!("{:>12}  {}", file.size, file.path.display())));
+    }
+
+    assert_eq!(stdout(files.tantivy_sqlite().arg("verify")), "");
+
+    // Tantivy can't collect zero documents, so the limit is rejected up front
+    files
+        .tantivy_sqlite()
+        .args(["search", "title", "mice", "--limit", "0"])
+        .assert()
+        .code(2);
+
+    let directory = files.other("export");
+    files
+        .tantivy_sqlite()
+        .arg("export")
+        .arg(&directory)
+        .assert()
+        .success();
+    for file in storage.list_files()? {
+        if file.path.as_os_str() != ".managed.json" {
+            assert_eq!(
+                fs::read(directory.join(&file.path))?,
+                storage.atomic_read(&file.path)?
+            );
+        }
+    }
+
+    // Swap the content of a segment file for zeroes of the same length
+    let corrupt = storage
+        .list_files()?
+        .into_iter()
+        .find(|file| file.path.extension().is_some_and(|e| e == "idx"))
+        .expect("the segment has an idx file")
+        .path;
+    files.pool()?.get()?.execute(
+        "UPDATE tantivy_blobs SET content = zeroblob(length(content)) WHERE filename = ?",
+        [corrupt.as_os_str().as_bytes()],
+    )?;
+
+    files
+        .tantivy_sqlite()
+        .arg("verify")
+        .assert()
+        .failure()
+        .stdout(format!("corrupt    {}\n", corrupt.display()));
+
+    Ok(())
+}
+
+#[test]
+fn reads_the_layout_from_the_database() -> Result<(), Box<dyn std::error::Error>> {
+    let mut files = TestFiles::new();
+    let key = [42; 32];
+    write_index(
+        TantivySqliteStorage::builder(files.pool()?)
+            .layout(StorageLayout::Chunked { chunk_size: 1024 })
+            .compression(CompressionConfig::default())
+            .encryption(EncryptionKey::from_bytes(key)),
+    )?;
+
+    let key_file = files.other("key");
+    fs::write(&key_file, format!("{}\n", hex(&key)))?;
+
+    let listed = stdout(
+        files
+            .tantivy_sqlite()
+            .arg("ls")
+            .arg("--key-file")
+            .arg(&key_file),
+    );
+    assert!(listed.contains("meta.json"));
+
+    files
+        .tantivy_sqlite()
+        .args(["verify", "--key-file"])
+        .arg(&key_file)
+        .assert()
+        .success()
+        .stdout("");
+
+    let directory = files.other("export");
+    files
+        .tantivy_sqlite()
+        .arg("export")
+        .arg(&directory)
+        .arg("--key-file")
+        .arg(&key_file)
+        .assert()
+        .success();
+    assert!(Path::new(&directory.join("meta.json")).exists());
+
+    // Without the key, the encrypted files can't be read
+    files
+        .tantivy_sqlite()
+        .arg("export")
+        .arg(files.other("export"))
+        .assert()
+        .failure();
+
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
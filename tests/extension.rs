@@ -0,0 +1,113 @@
+//! Builds the loadable extension in `extension/` and loads it into a connection, the same way the
+//! sqlite3 shell would, so that it runs against a real host's routines.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::Connection;
+use tantivy::{doc, schema, Index};
+use tantivy_sqlite_storage::TantivySqliteStorage;
+use uuid::Uuid;
+
+/// Builds the extension into its own target directory, since it has a workspace of its own.
+fn build_extension() -> PathBuf {
+    let target_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("extension");
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--quiet", "--manifest-path"])
+        .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join("extension/Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success(), "failed to build the extension");
+
+    target_dir.join("debug/libtantivy_sqlite_extension")
+}
+
+#[test]
+fn searches_lists_and_verifies_through_the_loading_connection(
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join(format!("{}.sqlite", Uuid::new_v4()));
+    let pool = Pool::builder().build(SqliteConnectionManager::file(&path))?;
+    let storage = TantivySqliteStorage::new(pool)?;
+
+    let mut schema_builder = schema::Schema::builder();
+    let title = schema_builder.add_text_field("title", schema::TEXT | schema::STORED);
+    let index = Index::open_or_create(storage.clone(), schema_builder.build())?;
+    let mut index_writer = index.writer(15_000_000)?;
+    index_writer.add_document(doc!(title => "Of Mice and Men"))?;
+    index_writer.add_document(doc!(title => "The Old Man and the Sea"))?;
+    index_writer.commit()?;
+
+    let conn = Connection::open(&path)?;
+    unsafe {
+        conn.load_extension_enable()?;
+        conn.load_extension(build_extension(), None)?;
+    }
+    conn.load_extension_disable()?;
+
+    let titles: Vec<String> = conn
+        .prepare(
+            "SELECT json_extract(value, '$.doc.title[0]') FROM json_each(tantivy_search('mice OR sea'))",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    assert_eq!(titles.len(), 2);
+
+    let num_results: i64 = conn.query_row(
+        "SELECT json_array_length(tantivy_search('mice OR sea', 1))",
+        [],
+        |row| row.get(0),
+    )?;
+    assert_eq!(num_results, 1);
+
+    let num_files: i64 =
+        conn.query_row("SELECT json_array_length(tantivy_files())", [], |row| {
+            row.get(0)
+        })?;
+    assert_eq!(num_files as usize, storage.list_files()?.len());
+
+    let ok: bool = conn.query_row("SELECT json_extract(tantivy_verify(), '$.ok')", [], |row| {
+        row.get(0)
+    })?;
+    assert!(ok);
+
+    // The index is read through the loading connection, so it sees the transaction in progress
+    conn.execute_batch(
+        "BEGIN; DELETE FROM tantivy_blobs WHERE filename = CAST('meta.json' AS BLOB);",
+    )?;
+    let error = conn
+        .query_row("SELECT tantivy_search('mice')", [], |row| {
+            row.get::<_, String>(0)
+        })
+        .unwrap_err();
+    assert!(error.to_string().contains("meta.json"), "{error}");
+    conn.execute_batch("ROLLBACK")?;
+
+    let error = conn
+        .query_row("SELECT tantivy_search('title:(')", [], |row| {
+            row.get::<_, String>(0)
+        })
+        .unwrap_err();
+    assert!(error.to_string().contains("Syntax"), "{error}");
+
+    let error = conn
+        .query_row("SELECT tantivy_search()", [], |row| row.get::<_, String>(0))
+        .unwrap_err();
+    assert!(error.to_string().contains("tantivy_search takes a query"));
+
+    // Only callable directly, so a schema can't run them from a view or trigger
+    conn.execute_batch("CREATE VIEW files AS SELECT tantivy_files() AS files")?;
+    let error = conn
+        .query_row("SELECT files FROM files", [], |row| row.get::<_, String>(0))
+        .unwrap_err();
+    assert!(error.to_string().contains("unsafe use"), "{error}");
+
+    drop(conn);
+    std::fs::remove_file(path)?;
+    Ok(())
+}